@@ -0,0 +1,600 @@
+use crate::{
+    LoRaPacket, LoRaPacketType, OtaDataPacket, OtaError, OtaInitPacket, OtaProducer, RadioLink,
+};
+use core::fmt::{Debug, Write};
+use gateway_host_schema::{
+    self, BootReport, ErrorCode, EventFilter, FirmwareInfo, GatewayPacket, HostPacket,
+    LinkMetadata, LinkStats, LogConfig, OtaStatus, Uplink, UplinkPayload,
+};
+use heapless::{String, Vec};
+
+#[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error<E> {
+    Ota(OtaError),
+    LoRa(E),
+    Unsupported,
+    /* the request queue was full */
+    Busy,
+}
+
+impl<E: Debug> Error<E> {
+    /* what the host gets instead of the response */
+    pub fn to_packet(&self) -> GatewayPacket {
+        match self {
+            Error::Ota(e) => {
+                let code = match e {
+                    OtaError::Deserialize => ErrorCode::OtaDeserialize,
+                    OtaError::Serialize => ErrorCode::OtaSerialize,
+                    OtaError::Transmit => ErrorCode::OtaTransmit,
+                    OtaError::Receive => ErrorCode::OtaReceive,
+                    OtaError::InvalidPacketType => ErrorCode::OtaInvalidPacketType,
+                    OtaError::AlreadyStarted => ErrorCode::OtaAlreadyStarted,
+                    OtaError::NotStarted => ErrorCode::OtaNotStarted,
+                    OtaError::MemoryWriteFailed => ErrorCode::OtaMemoryWriteFailed,
+                    OtaError::Rejected(_) => ErrorCode::OtaRejected,
+                    OtaError::TooManyNotAcked => ErrorCode::OtaTooManyNotAcked,
+                };
+                error_packet(code, e)
+            }
+            Error::LoRa(e) => error_packet(ErrorCode::Radio, e),
+            Error::Unsupported => error_packet(ErrorCode::Unsupported, self),
+            Error::Busy => error_packet(ErrorCode::Busy, self),
+        }
+    }
+}
+
+/* the detail is the Debug name of the error, cut short when it does not fit */
+pub fn error_packet(code: ErrorCode, error: &impl Debug) -> GatewayPacket {
+    let mut detail = String::new();
+    let _ = write!(detail, "{:?}", error);
+    GatewayPacket::Error { code, detail }
+}
+
+/* turns an application packet from a node into an event for the host,
+payloads that do not decode are reported as malformed */
+pub fn decode_uplink(packet: LoRaPacket) -> GatewayPacket {
+    let payload = match packet.packet_type {
+        LoRaPacketType::SoilSensor => decode_soil_sensor(&packet.payload),
+        LoRaPacketType::LinkStats => postcard::from_bytes::<LinkStats>(&packet.payload)
+            .ok()
+            .map(UplinkPayload::LinkStats),
+        LoRaPacketType::Info => postcard::from_bytes::<FirmwareInfo>(&packet.payload)
+            .ok()
+            .map(UplinkPayload::FirmwareInfo),
+        LoRaPacketType::Boot => postcard::from_bytes::<BootReport>(&packet.payload)
+            .ok()
+            .map(UplinkPayload::BootReport),
+        _ => {
+            /* the gateway has no use for these, the host may */
+            return GatewayPacket::PeerMessage {
+                source: packet.source,
+                packet_type: packet.packet_type.as_u8(),
+                payload: packet.payload,
+                rssi: packet.rssi,
+                snr: packet.snr,
+            };
+        }
+    };
+    GatewayPacket::Uplink(Uplink {
+        source_address: packet.source,
+        link: LinkMetadata {
+            rssi: packet.rssi,
+            snr: packet.snr,
+        },
+        payload: payload.unwrap_or_else(|| {
+            warn!(
+                "malformed {} payload from {}, len {}",
+                packet.packet_type,
+                packet.source,
+                packet.payload.len()
+            );
+            UplinkPayload::Malformed {
+                packet_type: packet.packet_type.as_u8(),
+                length: packet.payload.len(),
+            }
+        }),
+    })
+}
+
+/* four little endian u16 samples, one per channel */
+fn decode_soil_sensor(payload: &[u8]) -> Option<UplinkPayload> {
+    if payload.len() != 8 {
+        return None;
+    }
+    let mut data = [0u16; 4];
+    for (d, bytes) in data.iter_mut().zip(payload.chunks_exact(2)) {
+        *d = u16::from_le_bytes([bytes[0], bytes[1]]);
+    }
+    Some(UplinkPayload::SoilSensorMoisture(data))
+}
+
+/// The nodes already reported with NodeJoined, any beyond N are never reported.
+pub struct KnownNodes<const N: usize> {
+    nodes: Vec<usize, N>,
+}
+
+impl<const N: usize> KnownNodes<N> {
+    pub const fn new() -> Self {
+        KnownNodes { nodes: Vec::new() }
+    }
+
+    /* the events an application packet from a node turns into, NodeJoined first
+    when it is the first packet of the node */
+    pub fn uplink(&mut self, packet: LoRaPacket, mut publish: impl FnMut(GatewayPacket)) {
+        if !self.nodes.contains(&packet.source) && self.nodes.push(packet.source).is_ok() {
+            publish(GatewayPacket::NodeJoined {
+                address: packet.source,
+                rssi: packet.rssi,
+                snr: packet.snr,
+            });
+        }
+        publish(decode_uplink(packet));
+    }
+}
+
+impl<const N: usize> Default for KnownNodes<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The parts of the gateway besides the radio that the host configures.
+pub trait GatewayHost {
+    /* replaces the previous subscription */
+    fn subscribe(&mut self, filter: EventFilter);
+
+    /* false when the gateway does not forward its log */
+    fn configure_log(&mut self, config: &LogConfig) -> bool;
+}
+
+fn init_packet(init: &gateway_host_schema::OtaInitRequest) -> OtaInitPacket {
+    OtaInitPacket {
+        binary_size: init.binary_size,
+        block_size: init.block_size,
+        block_count: init.block_count,
+        binary_sha256: init.binary_sha256,
+        signature: init.signature.clone(),
+    }
+}
+
+/// Answers the packets of the host, the OTA producer keeps the state of the
+/// update in progress between them.
+pub struct Gateway {
+    ota: Option<OtaProducer>,
+    info: FirmwareInfo,
+}
+
+impl Gateway {
+    pub fn new(info: FirmwareInfo) -> Gateway {
+        Gateway { ota: None, info }
+    }
+
+    async fn init_download<R: RadioLink>(
+        &mut self,
+        lora: &mut R,
+        init: gateway_host_schema::OtaInitRequest,
+    ) -> Result<GatewayPacket, Error<R::Error>> {
+        let mut ota = OtaProducer::new(init_packet(&init), init.destination_address);
+        let ret = ota.init_download(lora).await.map_err(Error::Ota)?;
+        self.ota = Some(ota);
+        Ok(ret)
+    }
+
+    async fn continue_download<R: RadioLink>(
+        &mut self,
+        lora: &mut R,
+        data: gateway_host_schema::OtaData,
+    ) -> Result<(), Error<R::Error>> {
+        match self.ota.as_mut() {
+            Some(ota) => {
+                ota.continue_download(
+                    lora,
+                    OtaDataPacket {
+                        index: data.index,
+                        data: data.data,
+                    },
+                )
+                .await
+                .map_err(Error::Ota)?;
+            }
+            None => {
+                return Err(Error::Ota(OtaError::NotStarted));
+            }
+        }
+        Ok(())
+    }
+
+    /* sends a request packet of the given type, the node answers with an uplink later */
+    async fn request_uplink<R: RadioLink>(
+        lora: &mut R,
+        destination_address: usize,
+        packet_type: LoRaPacketType,
+    ) -> Result<GatewayPacket, Error<R::Error>> {
+        let mut p = LoRaPacket::new(destination_address, packet_type);
+        p.payload.push(0).unwrap();
+        lora.transmit(&mut p, None).await.map_err(Error::LoRa)?;
+        Ok(GatewayPacket::Ack)
+    }
+
+    pub async fn process_host_message<R: RadioLink>(
+        &mut self,
+        lora: &mut R,
+        host: &mut impl GatewayHost,
+        packet: HostPacket,
+    ) -> Result<GatewayPacket, Error<R::Error>> {
+        let ret = match packet {
+            HostPacket::PingRequest => GatewayPacket::PingResponse,
+            HostPacket::OtaInit(init) => {
+                info!("init download");
+                match self.ota.as_mut() {
+                    Some(ota) => {
+                        /* a host that restarted sends the same image again, the node resumes it */
+                        if ota.is_done()
+                            || ota.is_same_download(&init_packet(&init), init.destination_address)
+                        {
+                            self.init_download(lora, init).await?
+                        } else {
+                            return Err(Error::Ota(OtaError::AlreadyStarted));
+                        }
+                    }
+                    None => self.init_download(lora, init).await?,
+                }
+            }
+            HostPacket::OtaData(data) => {
+                self.continue_download(lora, data).await?;
+                GatewayPacket::Ack
+            }
+            HostPacket::OtaDoneRequest => match self.ota.as_mut() {
+                Some(ota) => ota.done_download(lora).await.map_err(Error::Ota)?,
+                None => return Err(Error::Ota(OtaError::NotStarted)),
+            },
+            HostPacket::OtaAbortRequest => match self.ota.as_mut() {
+                Some(ota) => ota.abort_download(lora).await.map_err(Error::Ota)?,
+                None => return Err(Error::Ota(OtaError::NotStarted)),
+            },
+            HostPacket::OtaGetStatus => GatewayPacket::OtaStatus(match self.ota.as_ref() {
+                Some(ota) => ota.get_status(),
+                None => OtaStatus {
+                    in_progress: false,
+                    not_acked: Vec::new(),
+                    last_acked: 0,
+                },
+            }),
+            /* the data of these arrives later as an uplink event */
+            HostPacket::SoilSensor(req) => {
+                Self::request_uplink(lora, req.destination_address, LoRaPacketType::SoilSensor)
+                    .await?
+            }
+            HostPacket::NodeLinkStats(req) => {
+                Self::request_uplink(lora, req.destination_address, LoRaPacketType::LinkStats)
+                    .await?
+            }
+            HostPacket::NodeInfo(req) => {
+                Self::request_uplink(lora, req.destination_address, LoRaPacketType::Info).await?
+            }
+            HostPacket::LinkStatsRequest => GatewayPacket::LinkStats(lora.stats()),
+            HostPacket::LinkStatsReset => {
+                lora.reset_stats();
+                GatewayPacket::LinkStatsResetAck
+            }
+            HostPacket::GetInfo => GatewayPacket::Info(self.info.clone()),
+            HostPacket::SnifferMode(enabled) => {
+                lora.set_sniffer(enabled);
+                GatewayPacket::SnifferModeAck
+            }
+            HostPacket::RawTransmit(req) => {
+                let mut p = LoRaPacket::new_with_payload(
+                    req.destination_address,
+                    LoRaPacketType::from_u8(req.packet_type),
+                    req.payload,
+                );
+                lora.transmit(&mut p, req.modulation)
+                    .await
+                    .map_err(Error::LoRa)?;
+                GatewayPacket::RawTransmitAck
+            }
+            /* the host loop switches to KISS itself, this is reached only where it cannot */
+            HostPacket::KissMode => return Err(Error::Unsupported),
+            HostPacket::Subscribe(filter) => {
+                host.subscribe(filter);
+                GatewayPacket::SubscribeAck
+            }
+            HostPacket::LogConfig(config) => match host.configure_log(&config) {
+                true => GatewayPacket::Ack,
+                false => return Err(Error::Unsupported),
+            },
+        };
+        Ok(ret)
+    }
+
+    /* an OTA packet that was not the response to a request, the node reporting its status */
+    pub async fn process_peer_message<R: RadioLink>(
+        &mut self,
+        lora: &mut R,
+        packet: LoRaPacket,
+    ) -> Result<Option<GatewayPacket>, Error<R::Error>> {
+        match self.ota.as_mut() {
+            Some(ota) => Ok(Some(
+                ota.process_response_raw(lora, packet)
+                    .await
+                    .map_err(Error::Ota)?,
+            )),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gateway_host_schema::{ModulationOverride, OtaData, SoilSensorRequest, OTA_MAX_NOT_ACKED};
+
+    /* records what is sent, answers with what was queued in responses */
+    #[derive(Default)]
+    struct FakeRadio {
+        sent: std::vec::Vec<LoRaPacket>,
+        responses: std::vec::Vec<LoRaPacket>,
+        stats: LinkStats,
+        sniffer: bool,
+    }
+
+    impl RadioLink for FakeRadio {
+        type Error = ();
+
+        async fn transmit(
+            &mut self,
+            packet: &mut LoRaPacket,
+            _modulation: Option<ModulationOverride>,
+        ) -> Result<(), ()> {
+            self.stats.tx += 1;
+            self.sent.push(packet.clone());
+            Ok(())
+        }
+
+        async fn receive_single(&mut self) -> Result<LoRaPacket, ()> {
+            if !self.responses.is_empty() {
+                return Ok(self.responses.remove(0));
+            }
+            self.stats.timeouts += 1;
+            Err(())
+        }
+
+        fn clear(&mut self) {}
+
+        async fn hold_off(&mut self) {}
+
+        fn stats(&self) -> LinkStats {
+            self.stats.clone()
+        }
+
+        fn reset_stats(&mut self) {
+            self.stats = LinkStats::default();
+        }
+
+        fn record_retry(&mut self) {
+            self.stats.retries += 1;
+        }
+
+        fn set_sniffer(&mut self, enabled: bool) {
+            self.sniffer = enabled;
+        }
+    }
+
+    #[derive(Default)]
+    struct FakeHost {
+        filter: Option<EventFilter>,
+    }
+
+    impl GatewayHost for FakeHost {
+        fn subscribe(&mut self, filter: EventFilter) {
+            self.filter = Some(filter);
+        }
+
+        fn configure_log(&mut self, _config: &LogConfig) -> bool {
+            false
+        }
+    }
+
+    fn info() -> FirmwareInfo {
+        FirmwareInfo {
+            name: String::from("module-gateway"),
+            version: String::new(),
+            git_hash: String::new(),
+            build_time: 0,
+            module_version: String::new(),
+            features: String::new(),
+        }
+    }
+
+    fn process(
+        gateway: &mut Gateway,
+        radio: &mut FakeRadio,
+        packet: HostPacket,
+    ) -> Result<GatewayPacket, Error<()>> {
+        pollster::block_on(gateway.process_host_message(radio, &mut FakeHost::default(), packet))
+    }
+
+    #[test]
+    fn requests_go_out_on_the_radio() {
+        let mut gateway = Gateway::new(info());
+        let mut radio = FakeRadio::default();
+        let request = SoilSensorRequest {
+            destination_address: 3,
+        };
+        assert_eq!(
+            process(&mut gateway, &mut radio, HostPacket::SoilSensor(request)),
+            Ok(GatewayPacket::Ack)
+        );
+        assert_eq!(radio.sent.len(), 1);
+        assert_eq!(radio.sent[0].destination, 3);
+        assert!(matches!(
+            radio.sent[0].packet_type,
+            LoRaPacketType::SoilSensor
+        ));
+
+        match process(&mut gateway, &mut radio, HostPacket::LinkStatsRequest) {
+            Ok(GatewayPacket::LinkStats(stats)) => assert_eq!(stats.tx, 1),
+            r => panic!("unexpected {:?}", r),
+        }
+        process(&mut gateway, &mut radio, HostPacket::SnifferMode(true)).unwrap();
+        assert!(radio.sniffer);
+    }
+
+    #[test]
+    fn ota_needs_a_session() {
+        let mut gateway = Gateway::new(info());
+        let mut radio = FakeRadio::default();
+        let data = OtaData {
+            index: 0,
+            data: Vec::new(),
+        };
+        let error = process(&mut gateway, &mut radio, HostPacket::OtaData(data)).unwrap_err();
+        assert_eq!(error, Error::Ota(OtaError::NotStarted));
+        assert!(matches!(
+            error.to_packet(),
+            GatewayPacket::Error {
+                code: ErrorCode::OtaNotStarted,
+                ..
+            }
+        ));
+        assert!(radio.sent.is_empty());
+
+        /* the node never answers the init */
+        let init = gateway_host_schema::OtaInitRequest {
+            destination_address: 3,
+            binary_size: 100,
+            binary_sha256: [0; 32],
+            block_size: 50,
+            block_count: 2,
+            signature: None,
+        };
+        let error = process(&mut gateway, &mut radio, HostPacket::OtaInit(init)).unwrap_err();
+        assert_eq!(error, Error::Ota(OtaError::Receive));
+        assert!(radio.stats.retries > 0);
+    }
+
+    #[test]
+    fn too_many_blocks_not_acked() {
+        let mut gateway = Gateway::new(info());
+        let mut radio = FakeRadio::default();
+        let mut init_ack = LoRaPacket::new(1, LoRaPacketType::OTA);
+        init_ack.payload = postcard::to_vec(&crate::OtaPacket::InitAck).unwrap();
+        radio.responses.push(init_ack);
+        let init = gateway_host_schema::OtaInitRequest {
+            destination_address: 3,
+            binary_size: 129 * 50,
+            binary_sha256: [0; 32],
+            block_size: 50,
+            block_count: 129,
+            signature: None,
+        };
+        assert_eq!(
+            process(&mut gateway, &mut radio, HostPacket::OtaInit(init)),
+            Ok(GatewayPacket::OtaInitAck)
+        );
+
+        /* the node acknowledges none of them */
+        for index in 0..129u16 {
+            let data = OtaData {
+                index,
+                data: Vec::from_slice(&[0; 50]).unwrap(),
+            };
+            let result = process(&mut gateway, &mut radio, HostPacket::OtaData(data));
+            if (index as usize) < OTA_MAX_NOT_ACKED {
+                assert_eq!(result, Ok(GatewayPacket::Ack), "block {}", index);
+            } else {
+                let error = result.unwrap_err();
+                assert_eq!(error, Error::Ota(OtaError::TooManyNotAcked));
+                assert!(matches!(
+                    error.to_packet(),
+                    GatewayPacket::Error {
+                        code: ErrorCode::OtaTooManyNotAcked,
+                        ..
+                    }
+                ));
+            }
+        }
+        /* the refused blocks were not sent */
+        assert_eq!(radio.sent.len(), 1 + OTA_MAX_NOT_ACKED);
+        /* one of those waiting may be sent again */
+        let data = OtaData {
+            index: 5,
+            data: Vec::from_slice(&[0; 50]).unwrap(),
+        };
+        assert_eq!(
+            process(&mut gateway, &mut radio, HostPacket::OtaData(data)),
+            Ok(GatewayPacket::Ack)
+        );
+        match process(&mut gateway, &mut radio, HostPacket::OtaGetStatus) {
+            Ok(GatewayPacket::OtaStatus(status)) => {
+                assert_eq!(status.not_acked.len(), OTA_MAX_NOT_ACKED);
+                assert!(status.in_progress);
+            }
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+
+    #[test]
+    fn known_nodes_join_once() {
+        let mut known_nodes = KnownNodes::<1>::new();
+        let mut events = std::vec::Vec::new();
+        for source in [3, 3, 4] {
+            let mut packet = LoRaPacket::new_with_payload(
+                1,
+                LoRaPacketType::SoilSensor,
+                Vec::from_slice(&[1, 0, 2, 0, 3, 0, 4, 0]).unwrap(),
+            );
+            packet.source = source;
+            known_nodes.uplink(packet, |e| events.push(e));
+        }
+        /* node 4 does not fit, its data is still forwarded */
+        assert_eq!(events.len(), 4);
+        assert!(matches!(
+            events[0],
+            GatewayPacket::NodeJoined { address: 3, .. }
+        ));
+        for event in &events[1..] {
+            assert!(matches!(
+                event,
+                GatewayPacket::Uplink(Uplink {
+                    payload: UplinkPayload::SoilSensorMoisture([1, 2, 3, 4]),
+                    ..
+                })
+            ));
+        }
+    }
+
+    #[test]
+    fn malformed_uplinks() {
+        let mut packet = LoRaPacket::new_with_payload(
+            1,
+            LoRaPacketType::SoilSensor,
+            Vec::from_slice(&[1, 2, 3]).unwrap(),
+        );
+        packet.source = 3;
+        match decode_uplink(packet) {
+            GatewayPacket::Uplink(uplink) => assert_eq!(
+                uplink.payload,
+                UplinkPayload::Malformed {
+                    packet_type: LoRaPacketType::SoilSensor.as_u8(),
+                    length: 3,
+                }
+            ),
+            p => panic!("unexpected {:?}", p),
+        }
+        /* unknown types are left to the host */
+        let packet = LoRaPacket::new_with_payload(
+            1,
+            LoRaPacketType::Other(42),
+            Vec::from_slice(&[7]).unwrap(),
+        );
+        assert!(matches!(
+            decode_uplink(packet),
+            GatewayPacket::PeerMessage {
+                packet_type: 42,
+                ..
+            }
+        ));
+    }
+}
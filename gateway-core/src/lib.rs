@@ -0,0 +1,26 @@
+//! The gateway logic that does not depend on the hardware: the LoRa packets
+//! of the modules, the OTA protocol and the handling of the host packets.
+//!
+//! Everything here reaches the radio through [`RadioLink`], module-runtime
+//! implements it on its `RadioClient`, the gateway emulator on simulated nodes,
+//! so both answer the host with the same code.
+
+#![cfg_attr(not(test), no_std)]
+#![allow(async_fn_in_trait)]
+
+#[macro_use]
+mod fmt;
+
+mod gateway;
+mod ota;
+mod packet;
+mod producer;
+mod radio;
+
+pub use gateway::*;
+pub use ota::*;
+pub use packet::*;
+pub use producer::*;
+pub use radio::*;
+
+pub use gateway_host_schema;
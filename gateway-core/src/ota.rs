@@ -0,0 +1,211 @@
+use crate::{LoRaPacket, LoRaPacketType, RadioLink};
+use heapless::Vec;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum OtaError {
+    Deserialize,
+    Serialize,
+    Transmit,
+    Receive,
+    InvalidPacketType,
+    AlreadyStarted,
+    NotStarted,
+    MemoryWriteFailed,
+    /* the node refused to activate the image */
+    Rejected(OtaFailure),
+    /* the host sent more blocks than OTA_MAX_NOT_ACKED ahead of the acknowledgements */
+    TooManyNotAcked,
+}
+
+pub(crate) mod err {
+    pub fn deserialize(_: postcard::Error) -> super::OtaError {
+        super::OtaError::Deserialize
+    }
+
+    pub fn serialize(_: postcard::Error) -> super::OtaError {
+        super::OtaError::Serialize
+    }
+
+    pub fn transmit<E>(_: E) -> super::OtaError {
+        super::OtaError::Transmit
+    }
+
+    pub fn receive<E>(_: E) -> super::OtaError {
+        super::OtaError::Receive
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone)]
+/* sent by the gateway to node */
+pub struct OtaInitPacket {
+    pub binary_size: u32,
+    pub binary_sha256: [u8; 32],
+    pub block_size: u16,
+    pub block_count: u16,
+    /* Ed25519 signature of gateway_host_schema::ota_manifest, checked with signed_ota */
+    pub signature: Option<Vec<u8, 64>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+/* sent by the gateway to node */
+pub struct OtaDataPacket {
+    pub index: u16, // index of this block
+    pub data: Vec<u8, 96>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+/* sent by the node to gateway */
+pub struct OtaStatusPacket {
+    /* array of the most recently received indexes
+    node purposefully includes index numbers that it already sent previously
+    because these ACKs may get lost, by doing this we try to minimize the number
+    of redundantly retransmitted data packets */
+    pub received_indexes: Vec<u16, 32>,
+    pub valid_up_to_index: u16,
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+/* sent by the node to gateway, answers Init instead of InitAck when it already holds part
+of the same image, the gateway skips these blocks */
+pub struct OtaResumePacket {
+    /* every block below it is in the memory */
+    pub first_missing_index: u16,
+    /* blocks after it in the memory too, the first 32 of them */
+    pub received_indexes: Vec<u16, 32>,
+}
+
+/* the most blocks of an image the node keeps track of */
+pub const OTA_MAX_BLOCKS: usize = 2048;
+const OTA_BITMAP_LENGTH: usize = OTA_MAX_BLOCKS / 8;
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone)]
+/* a download as the memory keeps it over a reset, to resume it */
+pub struct OtaSessionRecord {
+    pub params: OtaInitPacket,
+    /* bit i % 8 of byte i / 8 is set when block i is in the memory */
+    pub received: Vec<u8, OTA_BITMAP_LENGTH>,
+}
+
+impl OtaSessionRecord {
+    pub fn new(params: OtaInitPacket) -> Self {
+        let mut received = Vec::new();
+        let _ = received.resize((params.block_count as usize).div_ceil(8), 0);
+        OtaSessionRecord { params, received }
+    }
+
+    /* the blocks are laid out the same, only the signature may differ */
+    pub fn is_same_image(&self, params: &OtaInitPacket) -> bool {
+        self.params.binary_sha256 == params.binary_sha256
+            && self.params.binary_size == params.binary_size
+            && self.params.block_size == params.block_size
+            && self.params.block_count == params.block_count
+    }
+
+    pub fn is_received(&self, index: u16) -> bool {
+        let index = index as usize;
+        match self.received.get(index / 8) {
+            Some(b) => b & (1 << (index % 8)) != 0,
+            None => false,
+        }
+    }
+
+    pub fn set_received(&mut self, index: u16, received: bool) {
+        let index = index as usize;
+        if let Some(b) = self.received.get_mut(index / 8) {
+            match received {
+                true => *b |= 1 << (index % 8),
+                false => *b &= !(1 << (index % 8)),
+            }
+        }
+    }
+
+    /* block_count once every block is in the memory */
+    pub fn first_missing(&self) -> u16 {
+        (0..self.params.block_count)
+            .find(|i| !self.is_received(*i))
+            .unwrap_or(self.params.block_count)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+/* why the node answered Done with DoneFailed */
+pub enum OtaFailure {
+    /* the SHA-256 of the received image differs from the one in OtaInitPacket */
+    HashMismatch,
+    /* the memory could not read the image back to hash it */
+    ReadFailed,
+    /* the node requires a signature, but the image has none or the node no key */
+    Unsigned,
+    /* the signature was not made with the key of the node */
+    SignatureInvalid,
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub enum OtaPacket {
+    Init(OtaInitPacket),
+    InitAck,
+    Data(OtaDataPacket),
+    Status(OtaStatusPacket),
+    Done,
+    DoneAck,
+    Abort,
+    AbortAck,
+    /* answers Done instead of DoneAck, the image is not activated, the gateway has to start over */
+    DoneFailed(OtaFailure),
+    InitResumed(OtaResumePacket),
+}
+
+pub async fn lora_transmit<R: RadioLink>(
+    lora: &mut R,
+    destination: usize,
+    packet: &OtaPacket,
+) -> Result<(), OtaError> {
+    let mut p = LoRaPacket::new(destination, LoRaPacketType::OTA);
+    p.payload = postcard::to_vec(packet).map_err(err::serialize)?;
+    lora.transmit(&mut p, None).await.map_err(err::transmit)
+}
+
+pub async fn lora_transmit_until_response<R: RadioLink>(
+    lora: &mut R,
+    destination: usize,
+    packet: &OtaPacket,
+    retries: usize,
+) -> Result<OtaPacket, OtaError> {
+    /* serialize the packet */
+    let mut p = LoRaPacket::new(destination, LoRaPacketType::OTA);
+    p.payload = postcard::to_vec(packet).map_err(err::serialize)?;
+    /* whatever is queued now cannot be a response to this packet */
+    lora.clear();
+    /* loop until we reach retries or get an error */
+    let mut last_error: Option<OtaError> = None;
+    for attempt in 0..retries {
+        if attempt > 0 {
+            lora.record_retry();
+        }
+        /* transmit the packet */
+        lora.transmit(&mut p, None).await.map_err(err::transmit)?;
+        /* listen for response (with timeout) */
+        match lora.receive_single().await {
+            Ok(packet) => {
+                /* parse response */
+                match postcard::from_bytes::<OtaPacket>(&packet.payload).map_err(err::deserialize) {
+                    Ok(ret) => return Ok(ret),
+                    Err(e) => {
+                        warn!("{}", e);
+                        last_error = Some(e);
+                    }
+                }
+            }
+            Err(e) => {
+                warn!("{}", e);
+                last_error = Some(err::receive(e));
+            }
+        }
+        /* a bit of a hold-off to not spam the air */
+        lora.hold_off().await;
+    }
+    Err(last_error.unwrap())
+}
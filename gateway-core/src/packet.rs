@@ -0,0 +1,115 @@
+use gateway_host_schema::{FrameHeader, HEADER_LENGTH, PACKET_LENGTH, PAYLOAD_LENGTH};
+use heapless::Vec;
+
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum LoRaPacketType {
+    Ping,
+    OTA,
+    SoilSensor,
+    LinkStats,
+    /* FirmwareInfo of the node, the request carries a single ignored byte like the others */
+    Info,
+    /* BootReport of a node after an update, sent unrequested */
+    Boot,
+    /* types the runtime does not know about, used by applications prototyped from the host */
+    Other(u8),
+}
+
+impl LoRaPacketType {
+    pub fn from_u8(value: u8) -> Self {
+        match value {
+            0 => LoRaPacketType::Ping,
+            1 => LoRaPacketType::OTA,
+            2 => LoRaPacketType::SoilSensor,
+            3 => LoRaPacketType::LinkStats,
+            4 => LoRaPacketType::Info,
+            5 => LoRaPacketType::Boot,
+            t => LoRaPacketType::Other(t),
+        }
+    }
+
+    pub fn as_u8(&self) -> u8 {
+        match self {
+            LoRaPacketType::Ping => 0,
+            LoRaPacketType::OTA => 1,
+            LoRaPacketType::SoilSensor => 2,
+            LoRaPacketType::LinkStats => 3,
+            LoRaPacketType::Info => 4,
+            LoRaPacketType::Boot => 5,
+            LoRaPacketType::Other(t) => *t,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LoRaPacket {
+    pub source: usize,
+    pub destination: usize,
+    pub packet_type: LoRaPacketType,
+    pub payload: Vec<u8, PAYLOAD_LENGTH>,
+    /* link quality of a received packet, zero for packets created locally */
+    pub rssi: i16,
+    pub snr: i16,
+}
+
+impl LoRaPacket {
+    pub fn new(destination: usize, packet_type: LoRaPacketType) -> Self {
+        LoRaPacket {
+            destination,
+            source: 0,
+            packet_type,
+            payload: Vec::new(),
+            rssi: 0,
+            snr: 0,
+        }
+    }
+
+    pub fn new_with_payload(
+        destination: usize,
+        packet_type: LoRaPacketType,
+        payload: Vec<u8, PAYLOAD_LENGTH>,
+    ) -> Self {
+        let mut ret = Self::new(destination, packet_type);
+        ret.payload = payload;
+        ret
+    }
+
+    pub fn parse(buff: &[u8]) -> Option<Self> {
+        if buff.len() <= HEADER_LENGTH || buff.len() > PACKET_LENGTH {
+            return None;
+        }
+        Some(LoRaPacket {
+            destination: u16::from_le_bytes(buff[0..2].try_into().ok()?) as usize,
+            source: u16::from_le_bytes(buff[2..4].try_into().ok()?) as usize,
+            packet_type: LoRaPacketType::from_u8(buff[4]),
+            payload: Vec::from_slice(&buff[HEADER_LENGTH..]).ok()?,
+            rssi: 0,
+            snr: 0,
+        })
+    }
+
+    /* the header alone, also for frames that failed the CRC or carry an unknown type */
+    pub fn parse_header(buff: &[u8]) -> Option<FrameHeader> {
+        if buff.len() < HEADER_LENGTH {
+            return None;
+        }
+        Some(FrameHeader {
+            destination: u16::from_le_bytes(buff[0..2].try_into().ok()?) as usize,
+            source: u16::from_le_bytes(buff[2..4].try_into().ok()?) as usize,
+            packet_type: buff[4],
+        })
+    }
+
+    pub fn serialize(&self, buff: &mut [u8]) -> Option<usize> {
+        let len = HEADER_LENGTH + self.payload.len();
+        if len > buff.len() {
+            return None;
+        }
+        buff[0..2].copy_from_slice(&(self.destination as u16).to_le_bytes());
+        buff[2..4].copy_from_slice(&(self.source as u16).to_le_bytes());
+        buff[4] = self.packet_type.as_u8();
+        buff[HEADER_LENGTH..HEADER_LENGTH + self.payload.len()].copy_from_slice(&self.payload);
+        Some(len)
+    }
+}
@@ -1,10 +1,10 @@
-use crate::lora::*;
-use crate::ota::common::*;
-use defmt::*;
-use gateway_host_schema::{self, GatewayPacket, OtaStatus};
+use crate::ota::*;
+use crate::{LoRaPacket, RadioLink};
+use gateway_host_schema::{GatewayPacket, OtaResume, OtaStatus, OTA_MAX_NOT_ACKED};
 use heapless::Vec;
 
-#[derive(Debug, defmt::Format, PartialEq)]
+#[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum OtaProducerState {
     Init,
     Download,
@@ -15,8 +15,7 @@ pub struct OtaProducer {
     params: OtaInitPacket,
     destination_address: usize,
     state: OtaProducerState,
-    data_cache: Vec<OtaDataPacket, 8>,
-    not_acked_indexes: Vec<u16, 128>,
+    not_acked_indexes: Vec<u16, OTA_MAX_NOT_ACKED>,
     highest_sent_index: u16,
     last_acked_index: u16,
 }
@@ -27,7 +26,6 @@ impl OtaProducer {
             params,
             destination_address,
             state: OtaProducerState::Init,
-            data_cache: Vec::new(),
             not_acked_indexes: Vec::new(),
             highest_sent_index: 0,
             last_acked_index: 0,
@@ -38,6 +36,15 @@ impl OtaProducer {
         self.state == OtaProducerState::Done
     }
 
+    /* an Init of the same image to the same node, the node resumes it */
+    pub fn is_same_download(&self, params: &OtaInitPacket, destination_address: usize) -> bool {
+        self.destination_address == destination_address
+            && self.params.binary_sha256 == params.binary_sha256
+            && self.params.binary_size == params.binary_size
+            && self.params.block_size == params.block_size
+            && self.params.block_count == params.block_count
+    }
+
     pub fn get_status(&self) -> OtaStatus {
         OtaStatus {
             not_acked: self.not_acked_indexes.iter().cloned().collect(),
@@ -46,9 +53,9 @@ impl OtaProducer {
         }
     }
 
-    async fn process_status(
+    async fn process_status<R: RadioLink>(
         &mut self,
-        _lora: &mut ModuleLoRa,
+        _lora: &mut R,
         status: OtaStatusPacket,
     ) -> Result<GatewayPacket, OtaError> {
         // remove all acknowledged indexes from the internal registry
@@ -78,16 +85,16 @@ impl OtaProducer {
         }
     }
 
-    pub async fn process_response(
+    pub async fn process_response<R: RadioLink>(
         &mut self,
-        lora: &mut ModuleLoRa,
+        lora: &mut R,
         packet: OtaPacket,
     ) -> Result<GatewayPacket, OtaError> {
         match packet {
-            OtaPacket::Init(_) => return Err(OtaError::InvalidPacketType),
-            OtaPacket::Data(_) => return Err(OtaError::InvalidPacketType),
-            OtaPacket::Done => return Err(OtaError::InvalidPacketType),
-            OtaPacket::Abort => return Err(OtaError::InvalidPacketType),
+            OtaPacket::Init(_) => Err(OtaError::InvalidPacketType),
+            OtaPacket::Data(_) => Err(OtaError::InvalidPacketType),
+            OtaPacket::Done => Err(OtaError::InvalidPacketType),
+            OtaPacket::Abort => Err(OtaError::InvalidPacketType),
             OtaPacket::InitAck => {
                 if self.state == OtaProducerState::Init {
                     self.state = OtaProducerState::Download;
@@ -111,12 +118,36 @@ impl OtaProducer {
                 self.state = OtaProducerState::Done;
                 Ok(GatewayPacket::OtaAbortAck)
             }
+            OtaPacket::InitResumed(resume) => {
+                if self.state == OtaProducerState::Init {
+                    info!("node resumes at block {}", resume.first_missing_index);
+                    self.state = OtaProducerState::Download;
+                    self.last_acked_index = resume.first_missing_index.saturating_sub(1);
+                    /* the host does not send the blocks the node has, the last one may be among them */
+                    self.highest_sent_index = resume
+                        .received_indexes
+                        .iter()
+                        .cloned()
+                        .fold(self.last_acked_index, u16::max);
+                    Ok(GatewayPacket::OtaResumed(OtaResume {
+                        first_missing_index: resume.first_missing_index,
+                        received_indexes: resume.received_indexes,
+                    }))
+                } else {
+                    Err(OtaError::InvalidPacketType)
+                }
+            }
+            OtaPacket::DoneFailed(failure) => {
+                warn!("node rejected the image: {}", failure);
+                self.state = OtaProducerState::Done;
+                Err(OtaError::Rejected(failure))
+            }
         }
     }
 
-    pub async fn process_response_raw(
+    pub async fn process_response_raw<R: RadioLink>(
         &mut self,
-        lora: &mut ModuleLoRa,
+        lora: &mut R,
         packet: LoRaPacket,
     ) -> Result<GatewayPacket, OtaError> {
         self.process_response(
@@ -126,9 +157,9 @@ impl OtaProducer {
         .await
     }
 
-    pub async fn init_download(
+    pub async fn init_download<R: RadioLink>(
         &mut self,
-        lora: &mut ModuleLoRa,
+        lora: &mut R,
     ) -> Result<GatewayPacket, OtaError> {
         let packet = OtaPacket::Init(self.params.clone());
         let resp =
@@ -136,17 +167,25 @@ impl OtaProducer {
         self.process_response(lora, resp).await
     }
 
-    pub async fn continue_download(
+    pub async fn continue_download<R: RadioLink>(
         &mut self,
-        lora: &mut ModuleLoRa,
+        lora: &mut R,
         data: OtaDataPacket,
     ) -> Result<(), OtaError> {
         let current_index = data.index;
         info!("data: index {}", data.index);
+        /* the host has to wait for the node, a block sent again is fine */
+        let known = self.not_acked_indexes.contains(&current_index);
+        if !known && self.not_acked_indexes.is_full() {
+            warn!(
+                "{} blocks not acked, block {} refused",
+                OTA_MAX_NOT_ACKED, current_index
+            );
+            return Err(OtaError::TooManyNotAcked);
+        }
         lora_transmit(lora, self.destination_address, &OtaPacket::Data(data)).await?;
 
-        if !self.not_acked_indexes.contains(&current_index) {
-            //TODO handle the case where this would overflow - we have too many unACKED, need to throttle transmit
+        if !known {
             self.not_acked_indexes.push(current_index).unwrap();
         }
         if current_index > self.highest_sent_index {
@@ -155,9 +194,9 @@ impl OtaProducer {
         Ok(())
     }
 
-    pub async fn done_download(
+    pub async fn done_download<R: RadioLink>(
         &mut self,
-        lora: &mut ModuleLoRa,
+        lora: &mut R,
     ) -> Result<GatewayPacket, OtaError> {
         let resp =
             lora_transmit_until_response(lora, self.destination_address, &OtaPacket::Done, 10)
@@ -165,9 +204,9 @@ impl OtaProducer {
         self.process_response(lora, resp).await
     }
 
-    pub async fn abort_download(
+    pub async fn abort_download<R: RadioLink>(
         &mut self,
-        lora: &mut ModuleLoRa,
+        lora: &mut R,
     ) -> Result<GatewayPacket, OtaError> {
         let resp =
             lora_transmit_until_response(lora, self.destination_address, &OtaPacket::Abort, 10)
@@ -0,0 +1,33 @@
+use crate::LoRaPacket;
+use core::fmt::Debug;
+use gateway_host_schema::{LinkStats, ModulationOverride};
+
+/// The radio as the OTA and the gateway logic use it, a single client of it
+/// that receives only the packets meant for it.
+pub trait RadioLink {
+    #[cfg(feature = "defmt")]
+    type Error: Debug + defmt::Format;
+    #[cfg(not(feature = "defmt"))]
+    type Error: Debug;
+
+    /* sets the source address, None keeps the modulation of the radio */
+    async fn transmit(
+        &mut self,
+        packet: &mut LoRaPacket,
+        modulation: Option<ModulationOverride>,
+    ) -> Result<(), Self::Error>;
+
+    /* the next packet, an error once none arrived within the receive timeout */
+    async fn receive_single(&mut self) -> Result<LoRaPacket, Self::Error>;
+
+    /* drop packets that were received but not yet consumed */
+    fn clear(&mut self);
+
+    /* waits between two attempts, to not spam the air */
+    async fn hold_off(&mut self);
+
+    fn stats(&self) -> LinkStats;
+    fn reset_stats(&mut self);
+    fn record_retry(&mut self);
+    fn set_sniffer(&mut self, enabled: bool);
+}
@@ -0,0 +1,302 @@
+use crate::node::SimNode;
+use gateway_core::{GatewayHost, KnownNodes, LoRaPacket, LoRaPacketType, RadioLink};
+use gateway_host_client::gateway_host_schema::{
+    EventFilter, FirmwareInfo, FrameHeader, GatewayPacket, HostPacket, LinkStats, LogConfig,
+    ModulationOverride, SniffedFrame, PACKET_LENGTH,
+};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/* how long a node takes to answer, the time on air of a short packet and back */
+const RESPONSE_DELAY: Duration = Duration::from_millis(150);
+/* MAX_KNOWN_NODES of module-gateway */
+const MAX_KNOWN_NODES: usize = 32;
+
+/// Small deterministic generator for the packet loss and the sensor noise.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng(seed | 1)
+    }
+
+    pub fn next(&mut self) -> u32 {
+        /* xorshift64* */
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        (self.0.wrapping_mul(0x2545f4914f6cdd1d) >> 32) as u32
+    }
+}
+
+fn truncated<const N: usize>(value: &str) -> heapless::String<N> {
+    let mut s = heapless::String::new();
+    for c in value.chars() {
+        if s.push(c).is_err() {
+            break;
+        }
+    }
+    s
+}
+
+/// What the emulator reports in place of the metadata the firmware build embeds.
+pub fn emulated_firmware_info(name: &str) -> FirmwareInfo {
+    FirmwareInfo {
+        name: truncated(name),
+        version: truncated(env!("CARGO_PKG_VERSION")),
+        git_hash: truncated("emulated"),
+        build_time: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs()),
+        module_version: truncated("emulated"),
+        features: heapless::String::new(),
+    }
+}
+
+#[derive(Debug)]
+pub enum SimError {
+    ReceiveTimeout,
+}
+
+/// The radio of the gateway and the simulated nodes around it. Nodes answer
+/// right away, so nothing here ever waits.
+pub struct SimRadio {
+    address: usize,
+    nodes: Vec<SimNode>,
+    /* chance of losing each frame on the air, 0 to 1 */
+    loss: f64,
+    rng: Rng,
+    stats: LinkStats,
+    sniffer: bool,
+    booted: Instant,
+    /* OTA packets from the nodes, the responses or the status they report on their own */
+    ota: VecDeque<LoRaPacket>,
+    /* every other packet from the nodes, for the uplink events */
+    uplinks: Vec<LoRaPacket>,
+    sniffed: Vec<SniffedFrame>,
+}
+
+impl SimRadio {
+    fn lost(&mut self) -> bool {
+        (self.rng.next() as f64 / u32::MAX as f64) < self.loss
+    }
+
+    /* a frame from the node to the gateway */
+    fn receive(&mut self, index: usize, mut packet: LoRaPacket) {
+        self.nodes[index].stats.tx += 1;
+        if self.lost() {
+            return;
+        }
+        let node = &self.nodes[index];
+        packet.source = node.address;
+        packet.rssi = node.rssi;
+        packet.snr = node.snr;
+        self.stats.rx += 1;
+        self.stats.last_rssi = node.rssi;
+        self.stats.last_snr = node.snr;
+        if self.sniffer {
+            let mut data = heapless::Vec::new();
+            let mut frame = [0u8; PACKET_LENGTH];
+            let len = packet.serialize(&mut frame).unwrap();
+            let _ = data.extend_from_slice(&frame[..len]);
+            let _ = data.extend_from_slice(&crc32(&data).to_le_bytes());
+            self.sniffed.push(SniffedFrame {
+                data,
+                crc_ok: true,
+                header: Some(FrameHeader {
+                    destination: packet.destination,
+                    source: packet.source,
+                    packet_type: packet.packet_type.as_u8(),
+                }),
+                rssi: packet.rssi,
+                snr: packet.snr,
+                timestamp_ms: self.booted.elapsed().as_millis() as u64,
+            });
+        }
+        match packet.packet_type {
+            LoRaPacketType::OTA => self.ota.push_back(packet),
+            _ => self.uplinks.push(packet),
+        }
+    }
+}
+
+impl RadioLink for SimRadio {
+    type Error = SimError;
+
+    /* the modulation makes no difference to the simulated nodes */
+    async fn transmit(
+        &mut self,
+        packet: &mut LoRaPacket,
+        _modulation: Option<ModulationOverride>,
+    ) -> Result<(), SimError> {
+        packet.source = self.address;
+        self.stats.tx += 1;
+        let Some(index) = self
+            .nodes
+            .iter()
+            .position(|n| n.address == packet.destination)
+        else {
+            return Ok(());
+        };
+        if self.lost() {
+            return Ok(());
+        }
+        let node = &mut self.nodes[index];
+        node.stats.rx += 1;
+        node.stats.last_rssi = node.rssi;
+        node.stats.last_snr = node.snr;
+        let noise = self.rng.next();
+        for response in self.nodes[index].receive(packet, noise) {
+            self.receive(index, response);
+        }
+        Ok(())
+    }
+
+    async fn receive_single(&mut self) -> Result<LoRaPacket, SimError> {
+        match self.ota.pop_front() {
+            Some(packet) => Ok(packet),
+            None => {
+                self.stats.timeouts += 1;
+                Err(SimError::ReceiveTimeout)
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.ota.clear();
+    }
+
+    async fn hold_off(&mut self) {}
+
+    fn stats(&self) -> LinkStats {
+        self.stats.clone()
+    }
+
+    fn reset_stats(&mut self) {
+        self.stats = LinkStats::default();
+    }
+
+    fn record_retry(&mut self) {
+        self.stats.retries += 1;
+    }
+
+    fn set_sniffer(&mut self, enabled: bool) {
+        self.sniffer = enabled;
+    }
+}
+
+/* the subscription of the host, the emulator forwards no log of its own */
+struct SimHost {
+    filter: EventFilter,
+}
+
+impl GatewayHost for SimHost {
+    fn subscribe(&mut self, filter: EventFilter) {
+        self.filter = filter;
+    }
+
+    fn configure_log(&mut self, _config: &LogConfig) -> bool {
+        false
+    }
+}
+
+/// The gateway firmware as seen from the host, with the radio replaced by
+/// simulated nodes. The host packets are handled by the same gateway-core as
+/// on the hardware, whatever the nodes send arrives as an event after a short delay.
+pub struct Gateway {
+    core: gateway_core::Gateway,
+    radio: SimRadio,
+    host: SimHost,
+    known_nodes: KnownNodes<MAX_KNOWN_NODES>,
+    scheduled: Vec<(Instant, GatewayPacket)>,
+}
+
+impl Gateway {
+    pub fn new(address: usize, nodes: Vec<SimNode>, loss: f64, seed: u64) -> Self {
+        Gateway {
+            core: gateway_core::Gateway::new(emulated_firmware_info("module-gateway")),
+            radio: SimRadio {
+                address,
+                nodes,
+                loss,
+                rng: Rng::new(seed),
+                stats: LinkStats::default(),
+                sniffer: false,
+                booted: Instant::now(),
+                ota: VecDeque::new(),
+                uplinks: Vec::new(),
+                sniffed: Vec::new(),
+            },
+            host: SimHost {
+                filter: EventFilter::all(),
+            },
+            known_nodes: KnownNodes::new(),
+            scheduled: Vec::new(),
+        }
+    }
+
+    /// When the next event is due, if any.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.scheduled.iter().map(|(t, _)| *t).min()
+    }
+
+    pub fn take_due_events(&mut self, now: Instant) -> Vec<GatewayPacket> {
+        let (due, pending) = self.scheduled.drain(..).partition(|(t, _)| *t <= now);
+        self.scheduled = pending;
+        /* filtered when sent, like the gateway does when they are queued */
+        due.into_iter()
+            .map(|(_, p)| p)
+            .filter(|p| self.host.filter.accepts(p))
+            .collect()
+    }
+
+    /// Handles a command from the host, the response is immediate.
+    pub fn process_host_message(&mut self, packet: HostPacket) -> GatewayPacket {
+        let response = pollster::block_on(self.core.process_host_message(
+            &mut self.radio,
+            &mut self.host,
+            packet,
+        ));
+        self.forward_received();
+        response.unwrap_or_else(|e| e.to_packet())
+    }
+
+    /* what gateway_task, uplink_task and sniffer_task of module-gateway make of
+    the packets left over once the request was handled */
+    fn forward_received(&mut self) {
+        let due = Instant::now() + RESPONSE_DELAY;
+        while let Some(packet) = self.radio.ota.pop_front() {
+            let event = pollster::block_on(self.core.process_peer_message(&mut self.radio, packet));
+            match event {
+                Ok(Some(e)) => self.scheduled.push((due, e)),
+                Ok(None) => {}
+                Err(e) => self.scheduled.push((due, e.to_packet())),
+            }
+        }
+        for packet in self.radio.uplinks.drain(..) {
+            let scheduled = &mut self.scheduled;
+            self.known_nodes
+                .uplink(packet, |e| scheduled.push((due, e)));
+        }
+        for frame in self.radio.sniffed.drain(..) {
+            self.scheduled
+                .push((due, GatewayPacket::SniffedFrame(frame)));
+        }
+    }
+}
+
+/* the checksum trailer of a LoRa frame, only the sniffer ever shows it */
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffffffffu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb88320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
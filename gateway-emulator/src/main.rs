@@ -0,0 +1,103 @@
+//! Emulates the gateway on a pseudo-terminal, so the host tools can be
+//! developed without the hardware. The host packets are handled by the
+//! `Gateway` of gateway-core like on the hardware, only the radio is replaced
+//! by simulated nodes answering with the OTA protocol of gateway-core too.
+
+mod gateway;
+mod node;
+
+use anyhow::{Context, Result};
+use clap::Parser;
+use gateway::Gateway;
+use gateway_host_client::{encode_frame, GatewayMessage, HostMessage, MessageDecoder};
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+use nix::pty::openpty;
+use nix::sys::termios::{cfmakeraw, tcgetattr, tcsetattr, SetArg};
+use nix::unistd::ttyname;
+use node::SimNode;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::fd::AsFd;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+#[derive(Parser)]
+#[command(about = "Gateway emulator exposing the host protocol on a pseudo-terminal")]
+struct Args {
+    /// Also make the terminal available under this path
+    #[arg(short, long)]
+    link: Option<PathBuf>,
+    /// Addresses of the simulated nodes
+    #[arg(short, long, value_delimiter = ',', default_value = "3")]
+    nodes: Vec<usize>,
+    #[arg(long, default_value_t = 1)]
+    address: usize,
+    /// Percentage of frames lost on the air
+    #[arg(long, default_value_t = 0.0)]
+    loss: f64,
+    #[arg(long, default_value_t = 1)]
+    seed: u64,
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let nodes = args
+        .nodes
+        .iter()
+        .enumerate()
+        .map(|(i, a)| SimNode::new(*a, -60 - 10 * i as i16, 9 - i as i16))
+        .collect();
+    let mut gateway = Gateway::new(args.address, nodes, args.loss / 100.0, args.seed);
+
+    let pty = openpty(None, None).context("opening a pseudo-terminal")?;
+    /* the host protocol is binary, nothing may be translated on the way */
+    let mut termios = tcgetattr(&pty.slave)?;
+    cfmakeraw(&mut termios);
+    tcsetattr(&pty.slave, SetArg::TCSANOW, &termios)?;
+    let path = ttyname(pty.slave.as_fd())?;
+    if let Some(link) = &args.link {
+        let _ = std::fs::remove_file(link);
+        std::os::unix::fs::symlink(&path, link)
+            .with_context(|| format!("linking {}", link.display()))?;
+    }
+    println!("gateway {} on {}", args.address, path.display());
+    println!("nodes {:?}, {}% loss", args.nodes, args.loss);
+
+    /* the slave stays open, reading the master would fail whenever no host has it open */
+    let _slave = pty.slave;
+    let mut master = File::from(pty.master);
+    let mut decoder = MessageDecoder::new();
+    let mut buffer = [0u8; 256];
+    loop {
+        let timeout = match gateway.next_deadline() {
+            Some(t) => t.saturating_duration_since(Instant::now()),
+            None => Duration::from_secs(1),
+        };
+        let readable = {
+            let mut fds = [PollFd::new(master.as_fd(), PollFlags::POLLIN)];
+            let timeout = PollTimeout::try_from(timeout.as_millis().min(1000) as i32 + 1).unwrap();
+            poll(&mut fds, timeout)? > 0
+        };
+
+        let mut outgoing = Vec::new();
+        if readable {
+            let len = master.read(&mut buffer)?;
+            decoder.push::<HostMessage>(&buffer[..len], |m| match m {
+                Ok(m) => {
+                    let packet = gateway.process_host_message(m.packet);
+                    outgoing.push(GatewayMessage::Response {
+                        request_id: m.request_id,
+                        packet,
+                    });
+                }
+                Err(e) => eprintln!("host frame dropped: {}", e),
+            });
+        }
+        for event in gateway.take_due_events(Instant::now()) {
+            outgoing.push(GatewayMessage::Event(event));
+        }
+        for message in outgoing {
+            master.write_all(&encode_frame(&message)?)?;
+        }
+    }
+}
@@ -0,0 +1,219 @@
+use crate::gateway::emulated_firmware_info;
+use gateway_core::{
+    LoRaPacket, LoRaPacketType, OtaFailure, OtaInitPacket, OtaPacket, OtaResumePacket,
+    OtaStatusPacket,
+};
+use gateway_host_client::gateway_host_schema::{BootReport, LinkStats};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::VecDeque;
+
+/* the node remembers only the most recent blocks, like the OtaConsumer */
+const RECENT_INDEXES: usize = 32;
+
+struct OtaSession {
+    binary_size: usize,
+    binary_sha256: [u8; 32],
+    block_size: usize,
+    block_count: u16,
+    image: Vec<u8>,
+    recent_indexes: VecDeque<u16>,
+    valid_up_to_index: u16,
+    /* the bitmap the firmware keeps in flash, it stands for the session over a reset */
+    received: Vec<bool>,
+    rejected: bool,
+    /* the image was verified, the node resets into it, repeated Dones get DoneAck again */
+    activated: bool,
+}
+
+/// A node running module-node, as far as the gateway can tell over the air.
+pub struct SimNode {
+    pub address: usize,
+    pub rssi: i16,
+    pub snr: i16,
+    pub stats: LinkStats,
+    moisture: [u16; 4],
+    ota: Option<OtaSession>,
+}
+
+fn packet(destination: usize, packet_type: LoRaPacketType, payload: &impl Serialize) -> LoRaPacket {
+    let payload = postcard::to_allocvec(payload).unwrap();
+    LoRaPacket::new_with_payload(
+        destination,
+        packet_type,
+        heapless::Vec::from_slice(&payload).unwrap(),
+    )
+}
+
+impl SimNode {
+    pub fn new(address: usize, rssi: i16, snr: i16) -> Self {
+        SimNode {
+            address,
+            rssi,
+            snr,
+            stats: LinkStats::default(),
+            moisture: [1800, 2100, 2400, 2700],
+            ota: None,
+        }
+    }
+
+    /// What the node sends back to a packet it heard, the way module-node answers it.
+    pub fn receive(&mut self, request: &LoRaPacket, noise: u32) -> Vec<LoRaPacket> {
+        let source = request.source;
+        match request.packet_type {
+            LoRaPacketType::OTA => {
+                let Ok(ota) = postcard::from_bytes::<OtaPacket>(&request.payload) else {
+                    return Vec::new();
+                };
+                let activated = self.ota.as_ref().is_some_and(|s| s.activated);
+                let response = match ota {
+                    OtaPacket::Init(init) => Some(self.ota_init(&init)),
+                    OtaPacket::Data(data) => self.ota_data(data.index, &data.data),
+                    OtaPacket::Done => self.ota_done(),
+                    OtaPacket::Abort => Some(self.ota_abort()),
+                    _ => None,
+                };
+                let mut sent: Vec<LoRaPacket> = response
+                    .iter()
+                    .map(|r| packet(source, LoRaPacketType::OTA, r))
+                    .collect();
+                /* the node resets into the image, which passes its health checks */
+                if !activated && self.ota.as_ref().is_some_and(|s| s.activated) {
+                    sent.push(packet(source, LoRaPacketType::Boot, &BootReport::Confirmed));
+                }
+                sent
+            }
+            LoRaPacketType::SoilSensor => {
+                let moisture = self.measure_soil(noise);
+                let payload: Vec<u8> = moisture.iter().flat_map(|m| m.to_le_bytes()).collect();
+                vec![LoRaPacket::new_with_payload(
+                    source,
+                    LoRaPacketType::SoilSensor,
+                    heapless::Vec::from_slice(&payload).unwrap(),
+                )]
+            }
+            LoRaPacketType::LinkStats => {
+                vec![packet(source, LoRaPacketType::LinkStats, &self.stats)]
+            }
+            LoRaPacketType::Info => {
+                let info = emulated_firmware_info("module-node");
+                vec![packet(source, LoRaPacketType::Info, &info)]
+            }
+            /* the simulated nodes do not react to anything else */
+            _ => Vec::new(),
+        }
+    }
+
+    /* drifts a little with every measurement */
+    fn measure_soil(&mut self, noise: u32) -> [u16; 4] {
+        for (i, m) in self.moisture.iter_mut().enumerate() {
+            let step = ((noise >> (i * 8)) & 0x1f) as i32 - 16;
+            *m = (*m as i32 + step).clamp(0, 4095) as u16;
+        }
+        self.moisture
+    }
+
+    /* emulated nodes are built without signed_ota, the signature is ignored,
+    the same image again resumes the session */
+    fn ota_init(&mut self, init: &OtaInitPacket) -> OtaPacket {
+        if let Some(session) = self.ota.as_mut().filter(|s| s.is_same_image(init)) {
+            let first_missing = session.first_missing();
+            println!("node {}: resuming at block {}", self.address, first_missing);
+            session.recent_indexes.clear();
+            session.valid_up_to_index = first_missing.saturating_sub(1);
+            return OtaPacket::InitResumed(OtaResumePacket {
+                first_missing_index: first_missing,
+                received_indexes: (first_missing..session.block_count)
+                    .filter(|i| session.received[*i as usize])
+                    .take(RECENT_INDEXES)
+                    .collect(),
+            });
+        }
+        self.ota = Some(OtaSession {
+            binary_size: init.binary_size as usize,
+            binary_sha256: init.binary_sha256,
+            block_size: init.block_size as usize,
+            block_count: init.block_count,
+            image: vec![0xff; init.binary_size as usize],
+            recent_indexes: VecDeque::new(),
+            valid_up_to_index: 0,
+            received: vec![false; init.block_count as usize],
+            rejected: false,
+            activated: false,
+        });
+        OtaPacket::InitAck
+    }
+
+    /* a node without a session drops the data without answering */
+    fn ota_data(&mut self, index: u16, data: &[u8]) -> Option<OtaPacket> {
+        let session = self.ota.as_mut()?;
+        let begin = session.block_size * index as usize;
+        if index < session.block_count && begin + data.len() <= session.binary_size {
+            session.image[begin..begin + data.len()].copy_from_slice(data);
+            session.received[index as usize] = true;
+            session.rejected = false;
+            if !session.recent_indexes.contains(&index) {
+                if session.recent_indexes.len() == RECENT_INDEXES {
+                    session.recent_indexes.pop_front();
+                }
+                session.recent_indexes.push_back(index);
+            }
+            session.valid_up_to_index = session.first_missing().saturating_sub(1);
+        }
+        Some(OtaPacket::Status(session.status()))
+    }
+
+    fn ota_done(&mut self) -> Option<OtaPacket> {
+        let session = self.ota.as_mut()?;
+        if session.activated {
+            return Some(OtaPacket::DoneAck);
+        }
+        if session.first_missing() != session.block_count {
+            return Some(OtaPacket::Status(session.status()));
+        }
+        let sha256: [u8; 32] = Sha256::digest(&session.image).into();
+        if sha256 == session.binary_sha256 {
+            println!("node {}: image complete, sha256 matches", self.address);
+            session.activated = true;
+            Some(OtaPacket::DoneAck)
+        } else {
+            println!(
+                "node {}: image complete, but its sha256 does not match",
+                self.address
+            );
+            session.rejected = true;
+            Some(OtaPacket::DoneFailed(OtaFailure::HashMismatch))
+        }
+    }
+
+    fn ota_abort(&mut self) -> OtaPacket {
+        self.ota = None;
+        OtaPacket::AbortAck
+    }
+}
+
+impl OtaSession {
+    /* a rejected image starts over, so does an activated one, its session was cleared */
+    fn is_same_image(&self, init: &OtaInitPacket) -> bool {
+        !self.rejected
+            && !self.activated
+            && self.binary_sha256 == init.binary_sha256
+            && self.binary_size == init.binary_size as usize
+            && self.block_size == init.block_size as usize
+            && self.block_count == init.block_count
+    }
+
+    fn first_missing(&self) -> u16 {
+        self.received
+            .iter()
+            .position(|r| !r)
+            .unwrap_or(self.received.len()) as u16
+    }
+
+    fn status(&self) -> OtaStatusPacket {
+        OtaStatusPacket {
+            received_indexes: self.recent_indexes.iter().cloned().collect(),
+            valid_up_to_index: self.valid_up_to_index,
+        }
+    }
+}
@@ -0,0 +1,161 @@
+//! Drives the emulator binary over its pseudo-terminal with gateway-host-client,
+//! the way the host tools use a real gateway.
+
+use gateway_host_client::gateway_host_schema::{
+    BootReport, ErrorCode, EventFilter, LogConfig, LogLevel, OtaData, OtaInitRequest, Uplink,
+    UplinkPayload,
+};
+use gateway_host_client::{Client, Error, GatewayPacket, SerialTransport, DEFAULT_BAUD_RATE};
+use sha2::{Digest, Sha256};
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+const NODE: usize = 3;
+
+struct Emulator {
+    child: Child,
+    link: PathBuf,
+}
+
+impl Emulator {
+    fn start(name: &str) -> Emulator {
+        let link = std::env::temp_dir().join(format!("gateway-{}-{}", name, std::process::id()));
+        let mut child = Command::new(env!("CARGO_BIN_EXE_gateway-emulator"))
+            .arg("--link")
+            .arg(&link)
+            .arg("--nodes")
+            .arg(NODE.to_string())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        /* the first line is printed once the terminal is linked */
+        let mut stdout = BufReader::new(child.stdout.take().unwrap());
+        let mut line = String::new();
+        stdout.read_line(&mut line).unwrap();
+        assert!(line.starts_with("gateway"), "{}", line);
+        /* the rest is read only so the emulator can keep printing */
+        std::thread::spawn(move || stdout.lines().count());
+        Emulator { child, link }
+    }
+
+    fn client(&self) -> Client<SerialTransport> {
+        Client::open_serial(self.link.to_str().unwrap(), DEFAULT_BAUD_RATE).unwrap()
+    }
+}
+
+impl Drop for Emulator {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_file(&self.link);
+    }
+}
+
+/* the events until `until` matches one, panics when it does not come */
+fn wait_for(
+    client: &mut Client<SerialTransport>,
+    until: impl Fn(&GatewayPacket) -> bool,
+) -> Vec<GatewayPacket> {
+    let mut events = Vec::new();
+    while let Some(event) = client.next_event(Duration::from_secs(2)).unwrap() {
+        let done = until(&event);
+        events.push(event);
+        if done {
+            return events;
+        }
+    }
+    panic!("the event did not arrive, got {:?}", events);
+}
+
+#[test]
+fn requests_and_uplinks() {
+    let emulator = Emulator::start("uplinks");
+    let mut client = emulator.client();
+    client.ping().unwrap();
+    assert_eq!(client.firmware_info().unwrap().name, "module-gateway");
+
+    client.subscribe(EventFilter::all()).unwrap();
+    client.request_soil_sensor(NODE).unwrap();
+    let events = wait_for(&mut client, |e| matches!(e, GatewayPacket::Uplink(_)));
+    assert!(matches!(
+        events[0],
+        GatewayPacket::NodeJoined { address: NODE, .. }
+    ));
+    assert!(matches!(
+        events.last(),
+        Some(GatewayPacket::Uplink(Uplink {
+            source_address: NODE,
+            payload: UplinkPayload::SoilSensorMoisture(_),
+            ..
+        }))
+    ));
+
+    let stats = client.link_stats().unwrap();
+    assert_eq!((stats.tx, stats.rx), (1, 1));
+}
+
+#[test]
+fn gateway_errors() {
+    let emulator = Emulator::start("errors");
+    let mut client = emulator.client();
+    let data = OtaData {
+        index: 0,
+        data: heapless::Vec::new(),
+    };
+    match client.ota_data(data) {
+        Err(Error::Gateway { code, .. }) => assert_eq!(code, ErrorCode::OtaNotStarted),
+        r => panic!("unexpected {:?}", r),
+    }
+    let config = LogConfig {
+        level: LogLevel::Info,
+        max_per_second: 0,
+    };
+    match client.configure_log(config) {
+        Err(Error::Gateway { code, .. }) => assert_eq!(code, ErrorCode::Unsupported),
+        r => panic!("unexpected {:?}", r),
+    }
+    /* the link is still fine afterwards */
+    client.ping().unwrap();
+}
+
+#[test]
+fn ota_update() {
+    let emulator = Emulator::start("ota");
+    let mut client = emulator.client();
+    let image: Vec<u8> = (0..1000u32).map(|i| (i * 7) as u8).collect();
+    let block_size = 96;
+    let blocks: Vec<&[u8]> = image.chunks(block_size).collect();
+    let init = OtaInitRequest {
+        destination_address: NODE,
+        binary_size: image.len() as u32,
+        binary_sha256: Sha256::digest(&image).into(),
+        block_size: block_size as u16,
+        block_count: blocks.len() as u16,
+        signature: None,
+    };
+    assert_eq!(client.ota_init(init).unwrap(), None);
+    for (index, block) in blocks.iter().enumerate() {
+        client
+            .ota_data(OtaData {
+                index: index as u16,
+                data: heapless::Vec::from_slice(block).unwrap(),
+            })
+            .unwrap();
+    }
+    /* the status the node reports after the last block */
+    wait_for(&mut client, |e| matches!(e, GatewayPacket::OtaDoneAck));
+    assert!(!client.ota_status().unwrap().in_progress);
+
+    assert_eq!(client.ota_done().unwrap(), GatewayPacket::OtaDoneAck);
+    wait_for(&mut client, |e| {
+        matches!(
+            e,
+            GatewayPacket::Uplink(Uplink {
+                payload: UplinkPayload::BootReport(BootReport::Confirmed),
+                ..
+            })
+        )
+    });
+}
@@ -0,0 +1,46 @@
+use anyhow::{bail, Context, Result};
+use object::elf::PT_LOAD;
+use object::read::elf::{ElfFile32, FileHeader, ProgramHeader};
+use object::LittleEndian;
+use std::path::Path;
+
+/* erased flash reads as 0xff, so gaps between segments are padded with it */
+const FLASH_ERASED: u8 = 0xff;
+
+/// Loads the firmware image to be written to the DFU partition, an ELF is
+/// flattened the way `objcopy -O binary` would, anything else is taken as is.
+pub fn load(path: &Path) -> Result<Vec<u8>> {
+    let file = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+    if !file.starts_with(b"\x7fELF") {
+        return Ok(file);
+    }
+    let elf = ElfFile32::<LittleEndian>::parse(file.as_slice()).context("parsing the ELF")?;
+    let endian = elf.endian();
+    let mut segments = Vec::new();
+    for header in elf.elf_header().program_headers(endian, file.as_slice())? {
+        if header.p_type(endian) != PT_LOAD || header.p_filesz(endian) == 0 {
+            continue;
+        }
+        /* the load address, .data is stored in flash but runs from RAM */
+        let address = header.p_paddr(endian) as u64;
+        let data = header
+            .data(endian, file.as_slice())
+            .map_err(|_| anyhow::anyhow!("segment at {:#x} is out of the file", address))?;
+        segments.push((address, data));
+    }
+    let start = match segments.iter().map(|(a, _)| *a).min() {
+        Some(a) => a,
+        None => bail!("the ELF has no loadable segments"),
+    };
+    let end = segments
+        .iter()
+        .map(|(a, d)| *a + d.len() as u64)
+        .max()
+        .unwrap();
+    let mut image = vec![FLASH_ERASED; (end - start) as usize];
+    for (address, data) in segments {
+        let offset = (address - start) as usize;
+        image[offset..offset + data.len()].copy_from_slice(data);
+    }
+    Ok(image)
+}
@@ -0,0 +1,526 @@
+use anyhow::{anyhow, bail, Context, Result};
+use defmt_parser::{DisplayHint, Fragment, ParserMode, TimePrecision, Type};
+use object::{Object, ObjectSection, ObjectSymbol};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+/*
+Decodes the defmt frames of GatewayPacket::Log. A frame starts with the index of
+its format string, followed by the timestamp and the arguments. The format strings
+are the names of the symbols in the .defmt section of the firmware ELF, e.g.
+    {"package":"module-gateway","tag":"defmt_info","data":"hello {=u8}","disambiguator":"..","crate_name":".."}
+their address is the index. Only the frames are decoded, no location info.
+*/
+
+/* what the host needs of a symbol */
+struct Entry {
+    tag: String,
+    format: String,
+}
+
+/// Format strings of one firmware build, frames of other builds decode to garbage.
+pub struct Table {
+    entries: HashMap<u16, Entry>,
+    timestamp: Option<String>,
+}
+
+pub struct LogMessage {
+    /* None for println! and the like */
+    pub level: Option<&'static str>,
+    pub timestamp: Option<String>,
+    pub text: String,
+}
+
+enum Value {
+    Unsigned(u128),
+    Signed(i128),
+    F32(f32),
+    F64(f64),
+    Bool(bool),
+    Char(char),
+    Bytes(Vec<u8>),
+    /* strings and anything already formatted */
+    Text(String),
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.data.len() < len {
+            bail!("the frame is cut short");
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    /* little endian, of any width up to 16 bytes */
+    fn unsigned(&mut self, len: usize) -> Result<u128> {
+        let mut buf = [0u8; 16];
+        buf[..len].copy_from_slice(self.bytes(len)?);
+        Ok(u128::from_le_bytes(buf))
+    }
+
+    fn signed(&mut self, len: usize) -> Result<i128> {
+        let shift = 128 - 8 * len as u32;
+        Ok(((self.unsigned(len)? << shift) as i128) >> shift)
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(self.unsigned(2)? as u16)
+    }
+
+    /* usize is 32 bits on the gateway */
+    fn length(&mut self) -> Result<usize> {
+        Ok(self.unsigned(4)? as usize)
+    }
+
+    /* Debug and Display output, terminated by 0xff which UTF-8 never contains */
+    fn until_terminator(&mut self) -> Result<&'a [u8]> {
+        let end = self
+            .data
+            .iter()
+            .position(|b| *b == 0xff)
+            .ok_or_else(|| anyhow!("unterminated string"))?;
+        let text = self.bytes(end)?;
+        self.bytes(1)?;
+        Ok(text)
+    }
+}
+
+impl Table {
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+        let elf = object::File::parse(file.as_slice()).context("parsing the ELF")?;
+        let section = elf
+            .section_by_name(".defmt")
+            .ok_or_else(|| anyhow!("{} has no .defmt section", path.display()))?;
+        let mut table = Table {
+            entries: HashMap::new(),
+            timestamp: None,
+        };
+        for symbol in elf.symbols() {
+            if symbol.section_index() != Some(section.index()) {
+                continue;
+            }
+            /* the __DEFMT_MARKER_* symbols are not JSON */
+            let mut fields = match symbol.name().ok().and_then(parse_symbol) {
+                Some(f) => f,
+                None => continue,
+            };
+            let (tag, format) = match (fields.remove("tag"), fields.remove("data")) {
+                (Some(t), Some(d)) => (t, d),
+                _ => continue,
+            };
+            if tag == "defmt_timestamp" {
+                table.timestamp = Some(format);
+            } else {
+                table
+                    .entries
+                    .insert(symbol.address() as u16, Entry { tag, format });
+            }
+        }
+        if table.entries.is_empty() {
+            bail!("{} has no defmt format strings", path.display());
+        }
+        Ok(table)
+    }
+
+    pub fn decode(&self, frame: &[u8]) -> Result<LogMessage> {
+        let mut reader = Reader { data: frame };
+        let entry = self.entry(reader.u16()?)?;
+        let level = match entry.tag.as_str() {
+            "defmt_trace" => Some("TRACE"),
+            "defmt_debug" => Some("DEBUG"),
+            "defmt_info" => Some("INFO"),
+            "defmt_warn" => Some("WARN"),
+            "defmt_error" => Some("ERROR"),
+            _ => None,
+        };
+        let timestamp = match &self.timestamp {
+            Some(format) => Some(self.format(format, &mut reader)?),
+            None => None,
+        };
+        let text = self.format(&entry.format, &mut reader)?;
+        Ok(LogMessage {
+            level,
+            timestamp,
+            text,
+        })
+    }
+
+    fn entry(&self, index: u16) -> Result<&Entry> {
+        self.entries.get(&index).ok_or_else(|| {
+            anyhow!(
+                "no format string {}, is it the ELF of the running firmware?",
+                index
+            )
+        })
+    }
+
+    fn format(&self, format: &str, reader: &mut Reader) -> Result<String> {
+        let fragments = defmt_parser::parse(format, ParserMode::ForwardsCompatible)
+            .with_context(|| format!("parsing {:?}", format))?;
+        /* every argument is encoded once, in the order of the indexes, however often it is printed */
+        let mut arguments = BTreeMap::new();
+        for fragment in &fragments {
+            if let Fragment::Parameter(p) = fragment {
+                let ty = arguments.entry(p.index).or_insert_with(|| p.ty.clone());
+                /* bitfields of one argument share it, it is as wide as the highest bit needs */
+                if let (Type::BitField(a), Type::BitField(b)) = (ty, &p.ty) {
+                    a.end = a.end.max(b.end);
+                }
+            }
+        }
+        let mut values = HashMap::new();
+        for (index, ty) in arguments {
+            values.insert(index, self.read_value(&ty, reader)?);
+        }
+        let mut text = String::new();
+        for fragment in &fragments {
+            match fragment {
+                Fragment::Literal(l) => text.push_str(l),
+                Fragment::Parameter(p) => {
+                    let value = &values[&p.index];
+                    match (&p.ty, value) {
+                        (Type::BitField(range), Value::Unsigned(v)) => {
+                            let width = (range.end - range.start) as u32;
+                            let mask = 1u128.checked_shl(width).map_or(u128::MAX, |m| m - 1);
+                            let bits = (v >> range.start) & mask;
+                            text.push_str(&render(&Value::Unsigned(bits), &p.ty, p.hint.as_ref()))
+                        }
+                        _ => text.push_str(&render(value, &p.ty, p.hint.as_ref())),
+                    }
+                }
+            }
+        }
+        Ok(text)
+    }
+
+    /* the data of a Format implementation, its tag was read already */
+    fn format_data(&self, tag: u16, reader: &mut Reader) -> Result<String> {
+        let entry = self.entry(tag)?;
+        if entry.tag != "defmt_derived" || !entry.format.contains('|') {
+            return self.format(&entry.format, reader);
+        }
+        /* a derived enum, the variants are separated by | and the discriminant picks one */
+        let variants: Vec<&str> = entry.format.split('|').collect();
+        let discriminant = match variants.len() {
+            0..=0xff => reader.unsigned(1)?,
+            0x100..=0xffff => reader.unsigned(2)?,
+            _ => reader.unsigned(4)?,
+        } as usize;
+        match variants.get(discriminant) {
+            Some(v) => self.format(v, reader),
+            None => bail!(
+                "invalid discriminant {} of {:?}",
+                discriminant,
+                entry.format
+            ),
+        }
+    }
+
+    fn read_value(&self, ty: &Type, reader: &mut Reader) -> Result<Value> {
+        Ok(match ty {
+            Type::U8 => Value::Unsigned(reader.unsigned(1)?),
+            Type::U16 => Value::Unsigned(reader.unsigned(2)?),
+            Type::U32 | Type::Usize => Value::Unsigned(reader.unsigned(4)?),
+            Type::U64 => Value::Unsigned(reader.unsigned(8)?),
+            Type::U128 => Value::Unsigned(reader.unsigned(16)?),
+            Type::I8 => Value::Signed(reader.signed(1)?),
+            Type::I16 => Value::Signed(reader.signed(2)?),
+            Type::I32 | Type::Isize => Value::Signed(reader.signed(4)?),
+            Type::I64 => Value::Signed(reader.signed(8)?),
+            Type::I128 => Value::Signed(reader.signed(16)?),
+            Type::BitField(range) => Value::Unsigned(match range.end {
+                0..=8 => reader.unsigned(1)?,
+                9..=16 => reader.unsigned(2)?,
+                17..=32 => reader.unsigned(4)?,
+                33..=64 => reader.unsigned(8)?,
+                _ => reader.unsigned(16)?,
+            }),
+            Type::F32 => Value::F32(f32::from_bits(reader.unsigned(4)? as u32)),
+            Type::F64 => Value::F64(f64::from_bits(reader.unsigned(8)? as u64)),
+            Type::Bool => Value::Bool(reader.unsigned(1)? != 0),
+            Type::Char => Value::Char(char::from_u32(reader.unsigned(4)? as u32).unwrap_or('?')),
+            Type::Str => {
+                let len = reader.length()?;
+                Value::Text(String::from_utf8_lossy(reader.bytes(len)?).into_owned())
+            }
+            Type::IStr => Value::Text(self.entry(reader.u16()?)?.format.clone()),
+            Type::U8Slice => {
+                let len = reader.length()?;
+                Value::Bytes(reader.bytes(len)?.to_vec())
+            }
+            Type::U8Array(len) => Value::Bytes(reader.bytes(*len)?.to_vec()),
+            Type::Debug | Type::Display => {
+                Value::Text(String::from_utf8_lossy(reader.until_terminator()?).into_owned())
+            }
+            Type::Format => {
+                let tag = reader.u16()?;
+                Value::Text(self.format_data(tag, reader)?)
+            }
+            Type::FormatSlice | Type::FormatArray(_) => {
+                let len = match ty {
+                    Type::FormatArray(len) => *len,
+                    _ => reader.length()?,
+                };
+                /* one tag for all the elements */
+                let tag = reader.u16()?;
+                let mut elements = Vec::new();
+                for _ in 0..len {
+                    elements.push(self.format_data(tag, reader)?);
+                }
+                Value::Text(format!("[{}]", elements.join(", ")))
+            }
+            /* several write!s into one Formatter, tag and data each, ended by tag 0 */
+            Type::FormatSequence => {
+                let mut text = String::new();
+                loop {
+                    match reader.u16()? {
+                        0 => break,
+                        tag => text.push_str(&self.format_data(tag, reader)?),
+                    }
+                }
+                Value::Text(text)
+            }
+        })
+    }
+}
+
+fn render(value: &Value, ty: &Type, hint: Option<&DisplayHint>) -> String {
+    match (value, hint) {
+        (
+            Value::Unsigned(v),
+            Some(DisplayHint::Hexadecimal {
+                alternate,
+                uppercase,
+                zero_pad,
+            }),
+        ) => {
+            let digits = if *uppercase {
+                format!("{:X}", v)
+            } else {
+                format!("{:x}", v)
+            };
+            format!(
+                "{}{:0>2$}",
+                if *alternate { "0x" } else { "" },
+                digits,
+                zero_pad
+            )
+        }
+        (
+            Value::Unsigned(v),
+            Some(DisplayHint::Binary {
+                alternate,
+                zero_pad,
+            }),
+        ) => {
+            format!(
+                "{}{:0>2$}",
+                if *alternate { "0b" } else { "" },
+                format!("{:b}", v),
+                zero_pad
+            )
+        }
+        (
+            Value::Unsigned(v),
+            Some(DisplayHint::Octal {
+                alternate,
+                zero_pad,
+            }),
+        ) => {
+            format!(
+                "{}{:0>2$}",
+                if *alternate { "0o" } else { "" },
+                format!("{:o}", v),
+                zero_pad
+            )
+        }
+        (Value::Unsigned(v), Some(DisplayHint::Seconds(precision))) => match precision {
+            TimePrecision::Micros => format!("{}.{:06}", v / 1_000_000, v % 1_000_000),
+            TimePrecision::Millis => format!("{}.{:03}", v / 1_000, v % 1_000),
+            TimePrecision::Seconds => v.to_string(),
+        },
+        (Value::Unsigned(v), Some(DisplayHint::Time(precision))) => {
+            let (seconds, fraction) = match precision {
+                TimePrecision::Micros => (v / 1_000_000, format!(".{:06}", v % 1_000_000)),
+                TimePrecision::Millis => (v / 1_000, format!(".{:03}", v % 1_000)),
+                TimePrecision::Seconds => (*v, String::new()),
+            };
+            let (h, m, s) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+            format!("{:02}:{:02}:{:02}{}", h, m, s, fraction)
+        }
+        (Value::Unsigned(v), Some(DisplayHint::NoHint { zero_pad })) => {
+            format!("{:01$}", v, zero_pad)
+        }
+        (Value::Unsigned(v), _) => v.to_string(),
+        (Value::Signed(v), Some(DisplayHint::NoHint { zero_pad })) => {
+            format!("{:01$}", v, zero_pad)
+        }
+        (Value::Signed(v), Some(DisplayHint::Hexadecimal { .. })) if *v < 0 => {
+            format!("-{}", render(&Value::Unsigned(v.unsigned_abs()), ty, hint))
+        }
+        (Value::Signed(v), Some(DisplayHint::Hexadecimal { .. } | DisplayHint::Binary { .. }))
+        | (Value::Signed(v), Some(DisplayHint::Octal { .. })) => {
+            render(&Value::Unsigned(*v as u128), ty, hint)
+        }
+        (Value::Signed(v), _) => v.to_string(),
+        (Value::F32(v), _) => v.to_string(),
+        (Value::F64(v), _) => v.to_string(),
+        (Value::Bool(v), _) => v.to_string(),
+        (Value::Char(c), Some(DisplayHint::Debug)) => format!("{:?}", c),
+        (Value::Char(c), _) => c.to_string(),
+        (Value::Bytes(b), Some(DisplayHint::Ascii)) => {
+            format!("b\"{}\"", b.escape_ascii())
+        }
+        (Value::Bytes(b), Some(DisplayHint::Hexadecimal { .. })) => {
+            let bytes: Vec<String> = b
+                .iter()
+                .map(|byte| render(&Value::Unsigned(*byte as u128), &Type::U8, hint))
+                .collect();
+            format!("[{}]", bytes.join(", "))
+        }
+        (Value::Bytes(b), _) => format!("{:?}", b),
+        /* only strings are quoted, Format output is printed as it is */
+        (Value::Text(s), Some(DisplayHint::Debug)) if matches!(ty, Type::Str | Type::IStr) => {
+            format!("{:?}", s)
+        }
+        (Value::Text(s), _) => s.clone(),
+    }
+}
+
+/* the symbol names are flat JSON objects, values that are not strings are kept as they are */
+fn parse_symbol(name: &str) -> Option<HashMap<String, String>> {
+    let mut chars = name
+        .strip_prefix('{')?
+        .strip_suffix('}')?
+        .chars()
+        .peekable();
+    let mut fields = HashMap::new();
+    loop {
+        let key = json_string(&mut chars)?;
+        if chars.next()? != ':' {
+            return None;
+        }
+        let value = match chars.peek()? {
+            '"' => json_string(&mut chars)?,
+            _ => {
+                let mut value = String::new();
+                while let Some(c) = chars.next_if(|c| *c != ',') {
+                    value.push(c);
+                }
+                value
+            }
+        };
+        fields.insert(key, value);
+        match chars.next() {
+            Some(',') => continue,
+            None => return Some(fields),
+            Some(_) => return None,
+        }
+    }
+}
+
+fn json_string(chars: &mut impl Iterator<Item = char>) -> Option<String> {
+    if chars.next()? != '"' {
+        return None;
+    }
+    let mut s = String::new();
+    loop {
+        match chars.next()? {
+            '"' => return Some(s),
+            '\\' => s.push(match chars.next()? {
+                'n' => '\n',
+                't' => '\t',
+                'r' => '\r',
+                'b' => '\u{8}',
+                'f' => '\u{c}',
+                'u' => {
+                    let hex: String = chars.take(4).collect();
+                    /* surrogate pairs do not appear in format strings, they become U+FFFD */
+                    char::from_u32(u32::from_str_radix(&hex, 16).ok()?).unwrap_or('\u{fffd}')
+                }
+                c => c,
+            }),
+            c => s.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::process::Command;
+
+    /* builds and runs tests/defmt-fixture, its ELF and the frames it logged */
+    fn fixture() -> (Table, Vec<Vec<u8>>) {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let target = dir.join("target").join("defmt-fixture");
+        let status = Command::new(env!("CARGO"))
+            .arg("build")
+            .arg("--quiet")
+            .arg("--manifest-path")
+            .arg(dir.join("tests/defmt-fixture/Cargo.toml"))
+            .arg("--target-dir")
+            .arg(&target)
+            .env("DEFMT_LOG", "trace")
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let elf: PathBuf = target.join("debug").join("defmt-fixture");
+        let output = Command::new(&elf).output().unwrap();
+        assert!(output.status.success());
+        let frames = String::from_utf8(output.stdout)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                (0..line.len())
+                    .step_by(2)
+                    .map(|i| u8::from_str_radix(&line[i..i + 2], 16).unwrap())
+                    .collect()
+            })
+            .collect();
+        (Table::load(&elf).unwrap(), frames)
+    }
+
+    #[test]
+    fn frames_of_a_real_elf() {
+        let (table, frames) = fixture();
+        let expected = [
+            (Some("INFO"), "hello from node 3, module-node 0.1.0"),
+            (Some("DEBUG"), "read [1, ab, ff]"),
+            (Some("WARN"), "type Ping then Other(42)"),
+            (
+                Some("ERROR"),
+                "stats Stats { tx: 7, last_rssi: -93 } ok false",
+            ),
+            (Some("TRACE"), "-5 1.5 x"),
+            (Some("INFO"), "bits 5 10"),
+            (Some("INFO"), "interned gateway"),
+            (Some("INFO"), "debug Some(2)"),
+            (Some("INFO"), "types [Ping, Other(1)]"),
+            (None, "no level 0x10"),
+        ];
+        assert_eq!(frames.len(), expected.len());
+        for (frame, (level, text)) in frames.iter().zip(expected) {
+            let message = table.decode(frame).unwrap();
+            assert_eq!((message.level, message.text.as_str()), (level, text));
+            assert_eq!(message.timestamp.as_deref(), Some("1.234567"));
+        }
+    }
+
+    #[test]
+    fn broken_frames() {
+        let (table, frames) = fixture();
+        let frame = &frames[0];
+        assert!(table.decode(&frame[..frame.len() - 1]).is_err());
+        assert!(table.decode(&[0xfe, 0xff]).is_err());
+        assert!(table.decode(&frame[..1]).is_err());
+    }
+}
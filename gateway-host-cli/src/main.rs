@@ -0,0 +1,373 @@
+mod image;
+mod logs;
+mod ota;
+
+use anyhow::{bail, Result};
+use clap::{Parser, Subcommand, ValueEnum};
+use gateway_host_client::gateway_host_schema::{
+    EventClasses, EventFilter, FirmwareInfo, LinkStats, LogConfig, LogLevel, Uplink, UplinkPayload,
+};
+use gateway_host_client::{Client, GatewayPacket, SerialTransport, DEFAULT_BAUD_RATE};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+#[derive(Parser)]
+#[command(about = "Talks to the LoRa gateway over its host serial link")]
+struct Cli {
+    /// Serial port of the gateway, e.g. /dev/ttyACM0
+    #[arg(short, long, env = "GATEWAY_PORT")]
+    port: Option<String>,
+    #[arg(short, long, default_value_t = DEFAULT_BAUD_RATE)]
+    baud: u32,
+    /// Seconds to wait for each response
+    #[arg(short, long, default_value_t = 5)]
+    timeout: u64,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Checks that the gateway responds
+    Ping,
+    /// Describes the gateway and its firmware, or the firmware of a node
+    Info {
+        /// Ask this node over the air instead
+        #[arg(long)]
+        node: Option<usize>,
+    },
+    /// Link statistics of the gateway, or of a node
+    Stats {
+        /// Ask this node over the air instead
+        #[arg(long)]
+        node: Option<usize>,
+        /// Clear the gateway counters afterwards
+        #[arg(long, conflicts_with = "node")]
+        reset: bool,
+    },
+    /// Polls the soil sensor of a node
+    Soil {
+        node: usize,
+        /// Seconds between measurements
+        #[arg(short, long, default_value_t = 10)]
+        interval: u64,
+        /// Stop after this many measurements, polls forever by default
+        #[arg(short, long)]
+        count: Option<u32>,
+    },
+    /// Prints the events of the gateway as they arrive
+    Events {
+        /// Event classes to subscribe to, all by default
+        #[arg(short, long, value_delimiter = ',')]
+        class: Vec<EventClass>,
+        /// Only events of these nodes
+        #[arg(short, long, value_delimiter = ',')]
+        node: Vec<usize>,
+        /// ELF of the gateway firmware, to decode its log messages
+        #[arg(long)]
+        elf: Option<PathBuf>,
+    },
+    /// Configures the log forwarding of the gateway and prints its log messages,
+    /// needs a firmware built with the host_log feature
+    Log {
+        /// ELF of the gateway firmware, log messages are printed undecoded without it
+        #[arg(long)]
+        elf: Option<PathBuf>,
+        /// Messages below this level are not forwarded
+        #[arg(short, long, value_enum, default_value_t = Level::Info)]
+        level: Level,
+        /// At most this many messages per second, 0 for no limit
+        #[arg(short, long, default_value_t = 20)]
+        rate: u16,
+    },
+    /// Firmware updates of the nodes
+    #[command(subcommand)]
+    Ota(OtaCommand),
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum EventClass {
+    Uplinks,
+    Ota,
+    LinkStats,
+    NodeJoins,
+    Logs,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum Level {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+    Off,
+}
+
+impl From<Level> for LogLevel {
+    fn from(level: Level) -> Self {
+        match level {
+            Level::Trace => LogLevel::Trace,
+            Level::Debug => LogLevel::Debug,
+            Level::Info => LogLevel::Info,
+            Level::Warn => LogLevel::Warn,
+            Level::Error => LogLevel::Error,
+            Level::Off => LogLevel::Off,
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum OtaCommand {
+    /// Uploads firmware to a node, the ELF is flattened like objcopy does
+    Push {
+        node: usize,
+        /// firmware.bin or firmware.elf
+        firmware: PathBuf,
+        #[arg(long, default_value_t = ota::MAX_BLOCK_SIZE)]
+        block_size: usize,
+        /// Signs the image with the key made by keygen, for nodes built with signed_ota
+        #[arg(long)]
+        key: Option<PathBuf>,
+    },
+    /// Progress of the upload the gateway is running
+    Status,
+    /// Cancels the upload on the node
+    Abort,
+    /// Creates a signing key and prints the public key to build the nodes with
+    Keygen { key: PathBuf },
+}
+
+type GatewayClient = Client<SerialTransport>;
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    /* the only command that does not talk to the gateway */
+    if let Command::Ota(OtaCommand::Keygen { key }) = &cli.command {
+        let public_key = ota::hex(&ota::keygen(key)?);
+        println!("public key {}", public_key);
+        println!(
+            "build the nodes with OTA_PUBLIC_KEY={} cargo build --release --features signed_ota",
+            public_key
+        );
+        return Ok(());
+    }
+    let Some(port) = &cli.port else {
+        bail!("--port or GATEWAY_PORT is required");
+    };
+    let mut client = Client::open_serial(port, cli.baud)?;
+    client.set_timeout(Duration::from_secs(cli.timeout));
+
+    match cli.command {
+        Command::Ping => {
+            let start = Instant::now();
+            client.ping()?;
+            println!("pong in {} ms", start.elapsed().as_millis());
+        }
+        Command::Info { node: None } => info(&mut client)?,
+        Command::Info { node: Some(node) } => {
+            client.subscribe(EventFilter::all())?;
+            client.request_node_info(node)?;
+            match wait_for_uplink(&mut client, node, Duration::from_secs(cli.timeout))? {
+                UplinkPayload::FirmwareInfo(i) => print_firmware(&i),
+                p => bail!("unexpected uplink {:?}", p),
+            }
+        }
+        Command::Stats { node: None, reset } => {
+            print_stats(&client.link_stats()?);
+            if reset {
+                client.reset_link_stats()?;
+                println!("counters cleared");
+            }
+        }
+        Command::Stats {
+            node: Some(node), ..
+        } => {
+            client.subscribe(EventFilter::all())?;
+            client.request_node_link_stats(node)?;
+            match wait_for_uplink(&mut client, node, Duration::from_secs(cli.timeout))? {
+                UplinkPayload::LinkStats(s) => print_stats(&s),
+                p => bail!("unexpected uplink {:?}", p),
+            }
+        }
+        Command::Soil {
+            node,
+            interval,
+            count,
+        } => soil(&mut client, node, Duration::from_secs(interval), count)?,
+        Command::Events { class, node, elf } => {
+            let table = elf.as_deref().map(logs::Table::load).transpose()?;
+            events(&mut client, &class, &node, table.as_ref())?
+        }
+        Command::Log { elf, level, rate } => {
+            let table = elf.as_deref().map(logs::Table::load).transpose()?;
+            client.configure_log(LogConfig {
+                level: level.into(),
+                max_per_second: rate,
+            })?;
+            events(&mut client, &[EventClass::Logs], &[], table.as_ref())?
+        }
+        Command::Ota(OtaCommand::Push {
+            node,
+            firmware,
+            block_size,
+            key,
+        }) => {
+            let image = image::load(&firmware)?;
+            let key = key.as_deref().map(ota::load_key).transpose()?;
+            ota::push(&mut client, node, &image, block_size, key.as_ref())?;
+        }
+        Command::Ota(OtaCommand::Status) => {
+            let s = client.ota_status()?;
+            println!(
+                "in progress: {}, valid up to block {}, not acknowledged: {:?}",
+                s.in_progress, s.last_acked, s.not_acked
+            );
+        }
+        Command::Ota(OtaCommand::Abort) => {
+            client.ota_abort()?;
+            println!("aborted");
+        }
+        Command::Ota(OtaCommand::Keygen { .. }) => unreachable!(),
+    }
+    Ok(())
+}
+
+fn info(client: &mut GatewayClient) -> Result<()> {
+    let start = Instant::now();
+    client.ping()?;
+    let rtt = start.elapsed();
+    let ota = client.ota_status()?;
+    let firmware = client.firmware_info()?;
+    println!("round trip: {} ms", rtt.as_millis());
+    println!("OTA in progress: {}", ota.in_progress);
+    print_firmware(&firmware);
+    Ok(())
+}
+
+fn print_firmware(i: &FirmwareInfo) {
+    println!("firmware           {} {}", i.name, i.version);
+    println!("git                {}", i.git_hash);
+    println!("built              {} (unix time)", i.build_time);
+    println!("module             {}", i.module_version);
+    println!("features           {}", i.features);
+}
+
+fn print_stats(s: &LinkStats) {
+    println!("tx                 {}", s.tx);
+    println!("rx                 {}", s.rx);
+    println!("crc failures       {}", s.crc_failures);
+    println!("parse failures     {}", s.parse_failures);
+    println!("address mismatches {}", s.address_mismatches);
+    println!("timeouts           {}", s.timeouts);
+    println!("retries            {}", s.retries);
+    println!("recoveries         {}", s.recoveries);
+    println!("last rssi          {} dBm", s.last_rssi);
+    println!("last snr           {} dB", s.last_snr);
+}
+
+fn events(
+    client: &mut GatewayClient,
+    classes: &[EventClass],
+    nodes: &[usize],
+    table: Option<&logs::Table>,
+) -> Result<()> {
+    let subscribed = |c| classes.is_empty() || classes.contains(&c);
+    let filter = EventFilter {
+        classes: EventClasses {
+            uplinks: subscribed(EventClass::Uplinks),
+            ota: subscribed(EventClass::Ota),
+            link_stats: subscribed(EventClass::LinkStats),
+            node_joins: subscribed(EventClass::NodeJoins),
+            logs: subscribed(EventClass::Logs),
+        },
+        sources: match nodes.try_into() {
+            Ok(sources) => sources,
+            Err(_) => bail!("the gateway filters at most 8 nodes"),
+        },
+    };
+    client.subscribe(filter)?;
+    loop {
+        match client.next_event(Duration::from_secs(1))? {
+            Some(GatewayPacket::EventsDropped { count }) => {
+                eprintln!("{} events dropped by the gateway", count)
+            }
+            Some(GatewayPacket::Log { frame, suppressed }) => {
+                if suppressed > 0 {
+                    eprintln!("{} log messages suppressed by the gateway", suppressed);
+                }
+                print_log(table, &frame);
+            }
+            Some(e) => println!("{:?}", e),
+            None => {}
+        }
+    }
+}
+
+fn print_log(table: Option<&logs::Table>, frame: &[u8]) {
+    let table = match table {
+        Some(t) => t,
+        None => {
+            println!("log {:02x?}", frame);
+            return;
+        }
+    };
+    match table.decode(frame) {
+        Ok(m) => println!(
+            "{} {:<5} {}",
+            m.timestamp.as_deref().unwrap_or("-"),
+            m.level.unwrap_or(""),
+            m.text
+        ),
+        Err(e) => eprintln!("undecodable log {:02x?}: {}", frame, e),
+    }
+}
+
+/* other events are skipped, there is nothing else this tool waits for,
+the callers subscribe to every event first, `events` may have narrowed the subscription */
+fn wait_for_uplink(
+    client: &mut GatewayClient,
+    node: usize,
+    timeout: Duration,
+) -> Result<UplinkPayload> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match client.next_event(remaining)? {
+            Some(GatewayPacket::Uplink(Uplink {
+                source_address,
+                payload,
+                ..
+            })) if source_address == node => return Ok(payload),
+            Some(GatewayPacket::Error { code, detail }) => {
+                eprintln!("gateway reported {:?}: {}", code, detail)
+            }
+            Some(_) => {}
+            None => bail!("no response from node {}", node),
+        }
+    }
+}
+
+fn soil(
+    client: &mut GatewayClient,
+    node: usize,
+    interval: Duration,
+    count: Option<u32>,
+) -> Result<()> {
+    client.subscribe(EventFilter::all())?;
+    let mut done = 0;
+    while count.is_none_or(|c| done < c) {
+        let start = Instant::now();
+        client.request_soil_sensor(node)?;
+        match wait_for_uplink(client, node, interval.max(Duration::from_secs(5))) {
+            Ok(UplinkPayload::SoilSensorMoisture(m)) => {
+                println!("{} {} {} {}", m[0], m[1], m[2], m[3])
+            }
+            Ok(p) => eprintln!("unexpected uplink {:?}", p),
+            Err(e) => eprintln!("{}", e),
+        }
+        done += 1;
+        std::thread::sleep(interval.saturating_sub(start.elapsed()));
+    }
+    Ok(())
+}
@@ -0,0 +1,205 @@
+use anyhow::{bail, Context, Result};
+use ed25519_dalek::{Signer, SigningKey};
+use gateway_host_client::gateway_host_schema::{
+    ota_manifest, OtaData, OtaInitRequest, OtaStatus, OTA_MAX_NOT_ACKED,
+};
+use gateway_host_client::{Client, Error, ErrorCode, GatewayPacket, Transport};
+use indicatif::{ProgressBar, ProgressStyle};
+use sha2::{Digest, Sha256};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::thread::sleep;
+use std::time::Duration;
+
+/* the largest block OtaData can carry */
+pub const MAX_BLOCK_SIZE: usize = 96;
+/* OTA_MAX_BLOCKS of the node */
+const MAX_BLOCKS: usize = 2048;
+/* half of what the gateway keeps track of, it refuses blocks beyond OTA_MAX_NOT_ACKED */
+const MAX_IN_FLIGHT: usize = OTA_MAX_NOT_ACKED / 2;
+const STATUS_POLL_INTERVAL: Duration = Duration::from_millis(500);
+/* status polls without progress before the pending blocks are sent again */
+const POLLS_BEFORE_RETRANSMIT: u32 = 6;
+const DONE_ATTEMPTS: u32 = 5;
+/* the gateway retries the node up to ten times for init and done, 5 s each */
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub fn push<T: Transport>(
+    client: &mut Client<T>,
+    node: usize,
+    image: &[u8],
+    block_size: usize,
+    key: Option<&SigningKey>,
+) -> Result<()> {
+    if block_size == 0 || block_size > MAX_BLOCK_SIZE {
+        bail!("block size must be between 1 and {}", MAX_BLOCK_SIZE);
+    }
+    let blocks: Vec<&[u8]> = image.chunks(block_size).collect();
+    if blocks.len() > MAX_BLOCKS {
+        bail!(
+            "{} blocks are more than the node keeps track of, use larger ones",
+            blocks.len()
+        );
+    }
+    let block_count = blocks.len() as u16;
+    let sha256: [u8; 32] = Sha256::digest(image).into();
+    println!(
+        "pushing {} bytes in {} blocks of {} to node {}, sha256 {}",
+        image.len(),
+        block_count,
+        block_size,
+        node,
+        hex(&sha256)
+    );
+    let signature = key.map(|k| {
+        let signature = k.sign(&ota_manifest(image.len() as u32, &sha256));
+        println!("signed with {}", hex(k.verifying_key().as_bytes()));
+        signature.to_bytes().as_slice().try_into().unwrap()
+    });
+
+    /* init and done wait for the node, data only for the transmission */
+    let data_timeout = client.timeout();
+    client.set_timeout(HANDSHAKE_TIMEOUT);
+    let resume = client
+        .ota_init(OtaInitRequest {
+            destination_address: node,
+            binary_size: image.len() as u32,
+            binary_sha256: sha256,
+            block_size: block_size as u16,
+            block_count,
+            signature,
+        })
+        .context("init")?;
+    client.set_timeout(data_timeout);
+
+    let progress = ProgressBar::new(block_count as u64);
+    progress.set_style(
+        ProgressStyle::with_template("{bar:40} {pos}/{len} blocks {msg}")
+            .unwrap()
+            .progress_chars("=> "),
+    );
+    let send = |client: &mut Client<T>, index: u16| -> Result<()> {
+        client
+            .ota_data(OtaData {
+                index,
+                data: blocks[index as usize].try_into().unwrap(),
+            })
+            .with_context(|| format!("block {}", index))
+    };
+
+    /* the blocks the node has from an earlier attempt are skipped */
+    let (mut next, skipped) = match resume {
+        Some(r) => {
+            println!(
+                "node resumes at block {}, {} more after it already received",
+                r.first_missing_index,
+                r.received_indexes.len()
+            );
+            (r.first_missing_index, r.received_indexes)
+        }
+        None => (0, Default::default()),
+    };
+    let mut status = client.ota_status()?;
+    let mut stalled = 0;
+    loop {
+        while next < block_count && status.not_acked.len() < MAX_IN_FLIGHT {
+            if !skipped.contains(&next) {
+                send(client, next)?;
+                status = client.ota_status()?;
+            }
+            next += 1;
+        }
+        update(&progress, next, &status);
+        if next == block_count && status.not_acked.is_empty() {
+            break;
+        }
+        sleep(STATUS_POLL_INTERVAL);
+        let previous = status.not_acked.len();
+        status = client.ota_status()?;
+        if status.not_acked.len() < previous {
+            stalled = 0;
+            continue;
+        }
+        stalled += 1;
+        if stalled >= POLLS_BEFORE_RETRANSMIT {
+            stalled = 0;
+            progress.set_message(format!("resending {}", status.not_acked.len()));
+            for index in status.not_acked.clone() {
+                send(client, index)?;
+            }
+        }
+    }
+    progress.finish_with_message("sent");
+
+    client.set_timeout(HANDSHAKE_TIMEOUT);
+    for _ in 0..DONE_ATTEMPTS {
+        let response = match client.ota_done() {
+            Err(Error::Gateway {
+                code: ErrorCode::OtaRejected,
+                detail,
+            }) => bail!("node {} rejected the image: {}", node, detail),
+            r => r.context("done")?,
+        };
+        match response {
+            GatewayPacket::OtaDoneAck => {
+                client.set_timeout(data_timeout);
+                println!("node {} received the complete image", node);
+                println!("it reports the outcome of its health checks after the reset, see events --class ota");
+                return Ok(());
+            }
+            /* the node is still missing some blocks */
+            GatewayPacket::OtaStatus(s) => {
+                client.set_timeout(data_timeout);
+                println!("node is missing {} blocks, resending", s.not_acked.len());
+                for index in s.not_acked {
+                    send(client, index)?;
+                }
+                sleep(STATUS_POLL_INTERVAL);
+                client.set_timeout(HANDSHAKE_TIMEOUT);
+            }
+            p => bail!("unexpected response to done: {:?}", p),
+        }
+    }
+    client.set_timeout(data_timeout);
+    bail!(
+        "the node did not confirm the image after {} attempts",
+        DONE_ATTEMPTS
+    )
+}
+
+fn update(progress: &ProgressBar, sent: u16, status: &OtaStatus) {
+    progress.set_position((sent as usize - status.not_acked.len().min(sent as usize)) as u64);
+}
+
+/* the key file holds the 32 byte Ed25519 seed in hex */
+pub fn load_key(path: &Path) -> Result<SigningKey> {
+    let text = std::fs::read_to_string(path).with_context(|| format!("{}", path.display()))?;
+    let text = text.trim();
+    let key: Option<Vec<u8>> = (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect();
+    match key.map(|k| k.try_into()) {
+        Some(Ok(k)) => Ok(SigningKey::from_bytes(&k)),
+        _ => bail!("{} is not a key made by ota keygen", path.display()),
+    }
+}
+
+/* writes a new signing key only the user can read, returns its public key */
+pub fn keygen(path: &Path) -> Result<[u8; 32]> {
+    let key = SigningKey::generate(&mut rand_core::OsRng);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .with_context(|| format!("{}", path.display()))?;
+    writeln!(file, "{}", hex(key.as_bytes()))?;
+    Ok(key.verifying_key().to_bytes())
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,10 @@
+use std::env;
+
+/* the firmware links defmt.x as its only linker script, the host keeps its default one */
+fn main() {
+    let dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    println!("cargo:rustc-link-arg=-Wl,-T,{}/defmt_host.x", dir);
+    /* the indexes are the addresses of the .defmt symbols, they must not be relocated */
+    println!("cargo:rustc-link-arg=-no-pie");
+    println!("cargo:rerun-if-changed=defmt_host.x");
+}
@@ -0,0 +1,22 @@
+/* the parts of defmt.x a host binary needs, inserted into the default linker script */
+EXTERN(_defmt_acquire);
+EXTERN(_defmt_release);
+PROVIDE(_defmt_panic = __defmt_default_panic);
+
+SECTIONS
+{
+  /* the addresses are the indexes in the frames, 0 ends a format sequence */
+  .defmt 1 (INFO) :
+  {
+    . = 1;
+    *(.defmt.prim.*);
+    *(.defmt.trace.*);
+    *(.defmt.debug.*);
+    *(.defmt.info.*);
+    *(.defmt.warn.*);
+    *(.defmt.error.*);
+    *(.defmt.*);
+    KEEP(*(.defmt.end .defmt.end.*));
+  }
+}
+INSERT AFTER .comment;
@@ -0,0 +1,65 @@
+/*
+Logs the messages the tests of gateway-host-cli/src/logs.rs decode, one frame per
+line in hex, as the host_log logger of module-runtime forwards them: the bytes
+before their rzcobs encoding.
+*/
+
+use std::sync::Mutex;
+
+static FRAME: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+
+#[defmt::global_logger]
+struct StdoutLogger;
+
+unsafe impl defmt::Logger for StdoutLogger {
+    fn acquire() {
+        FRAME.lock().unwrap().clear();
+    }
+
+    unsafe fn flush() {}
+
+    unsafe fn release() {
+        let frame = FRAME.lock().unwrap();
+        let hex: Vec<String> = frame.iter().map(|b| format!("{:02x}", b)).collect();
+        println!("{}", hex.concat());
+    }
+
+    unsafe fn write(bytes: &[u8]) {
+        FRAME.lock().unwrap().extend_from_slice(bytes);
+    }
+}
+
+/* embassy-time with defmt-timestamp-uptime, a fixed value so the output is known */
+defmt::timestamp!("{=u64:us}", 1_234_567);
+
+#[derive(defmt::Format)]
+enum PacketType {
+    Ping,
+    Other(u8),
+}
+
+#[derive(defmt::Format)]
+struct Stats {
+    tx: u32,
+    last_rssi: i16,
+}
+
+fn main() {
+    defmt::info!("hello from node {}, {} {}", 3usize, "module-node", "0.1.0");
+    defmt::debug!("read {=[u8]:x}", [0x01u8, 0xab, 0xff][..]);
+    defmt::warn!("type {} then {}", PacketType::Ping, PacketType::Other(42));
+    defmt::error!(
+        "stats {:?} ok {}",
+        Stats {
+            tx: 7,
+            last_rssi: -93
+        },
+        false
+    );
+    defmt::trace!("{=i16} {=f32} {=char}", -5i16, 1.5f32, 'x');
+    defmt::info!("bits {0=0..4} {0=4..8}", 0xa5u8);
+    defmt::info!("interned {=istr}", defmt::intern!("gateway"));
+    defmt::info!("debug {}", defmt::Debug2Format(&Some(2)));
+    defmt::info!("types {}", [PacketType::Ping, PacketType::Other(1)]);
+    defmt::println!("no level {=u8:#x}", 16u8);
+}
@@ -0,0 +1,374 @@
+use crate::{check_response, encode_frame, Error, MessageDecoder, DEFAULT_TIMEOUT};
+use gateway_host_schema::{
+    EventFilter, FirmwareInfo, GatewayMessage, GatewayPacket, HostMessage, HostPacket, LinkStats,
+    LogConfig, NodeInfoRequest, NodeLinkStatsRequest, OtaData, OtaInitRequest, OtaResume,
+    OtaStatus, RawTransmitRequest, SoilSensorRequest,
+};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU16, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, WriteHalf};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+const EVENT_QUEUE_LENGTH: usize = 1024;
+
+type Pending = Arc<Mutex<HashMap<u16, oneshot::Sender<GatewayPacket>>>>;
+
+/* removes the entry of a request however it ends, also when its future is dropped */
+struct PendingGuard<'a> {
+    pending: &'a Pending,
+    request_id: u16,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        self.pending.lock().unwrap().remove(&self.request_id);
+    }
+}
+
+/// Asynchronous client, any number of requests may be in flight at once. Requests the
+/// gateway has no room for are answered with `ErrorCode::Busy` and can be sent again.
+/// A background task reads the link and routes responses to their request
+/// and events to [`AsyncClient::next_event`].
+pub struct AsyncClient<S> {
+    writer: tokio::sync::Mutex<WriteHalf<S>>,
+    pending: Pending,
+    events: tokio::sync::Mutex<mpsc::Receiver<GatewayPacket>>,
+    dropped_events: Arc<AtomicU64>,
+    next_request_id: AtomicU16,
+    timeout: Duration,
+    reader: JoinHandle<()>,
+}
+
+#[cfg(feature = "tokio-serial")]
+impl AsyncClient<tokio_serial::SerialStream> {
+    pub fn open_serial(path: &str, baud_rate: u32) -> Result<Self, Error> {
+        use tokio_serial::SerialPortBuilderExt;
+        Ok(AsyncClient::new(
+            tokio_serial::new(path, baud_rate).open_native_async()?,
+        ))
+    }
+}
+
+async fn read_task<R: AsyncRead + Unpin>(
+    mut reader: R,
+    pending: Pending,
+    events: mpsc::Sender<GatewayPacket>,
+    dropped_events: Arc<AtomicU64>,
+) {
+    let mut decoder = MessageDecoder::new();
+    let mut buffer = [0u8; 256];
+    loop {
+        let len = match reader.read(&mut buffer).await {
+            Ok(0) | Err(_) => break,
+            Ok(len) => len,
+        };
+        decoder.push(&buffer[..len], |m| match m {
+            Ok(GatewayMessage::Response { request_id, packet }) => {
+                /* nobody waits for a response that came after its timeout */
+                if let Some(tx) = pending.lock().unwrap().remove(&request_id) {
+                    let _ = tx.send(packet);
+                }
+            }
+            Ok(GatewayMessage::Event(packet)) => {
+                if events.try_send(packet).is_err() {
+                    dropped_events.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            Err(_) => {}
+        });
+    }
+    /* wakes up the waiting requests with Error::Closed */
+    pending.lock().unwrap().clear();
+}
+
+impl<S: AsyncRead + AsyncWrite + Send + 'static> AsyncClient<S> {
+    /// Must be called from within a tokio runtime, it spawns the reader task.
+    pub fn new(io: S) -> Self {
+        let (reader, writer) = tokio::io::split(io);
+        let pending = Pending::default();
+        let dropped_events = Arc::new(AtomicU64::new(0));
+        let (events_tx, events_rx) = mpsc::channel(EVENT_QUEUE_LENGTH);
+        let reader = tokio::spawn(read_task(
+            reader,
+            pending.clone(),
+            events_tx,
+            dropped_events.clone(),
+        ));
+        AsyncClient {
+            writer: tokio::sync::Mutex::new(writer),
+            pending,
+            events: tokio::sync::Mutex::new(events_rx),
+            dropped_events,
+            next_request_id: AtomicU16::new(0),
+            timeout: DEFAULT_TIMEOUT,
+            reader,
+        }
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Sends the packet and waits for its response, an error response from
+    /// the gateway is returned as [`Error::Gateway`].
+    pub async fn request(&self, packet: HostPacket) -> Result<GatewayPacket, Error> {
+        let request_id = self.next_request_id.fetch_add(1, Ordering::Relaxed);
+        let frame = encode_frame(&HostMessage { request_id, packet })?;
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(request_id, tx);
+        let _guard = PendingGuard {
+            pending: &self.pending,
+            request_id,
+        };
+
+        let mut writer = self.writer.lock().await;
+        writer.write_all(&frame).await?;
+        writer.flush().await?;
+        drop(writer);
+        match tokio::time::timeout(self.timeout, rx).await {
+            Ok(Ok(packet)) => check_response(packet),
+            Ok(Err(_)) => Err(Error::Closed),
+            Err(_) => Err(Error::Timeout(self.timeout)),
+        }
+    }
+
+    /// Requests still waiting for their response.
+    pub fn pending_requests(&self) -> usize {
+        self.pending.lock().unwrap().len()
+    }
+
+    /// Waits for the next event, returns None once the link is closed.
+    pub async fn next_event(&self) -> Option<GatewayPacket> {
+        self.events.lock().await.recv().await
+    }
+
+    /// Events lost because they were not taken out of the queue in time.
+    pub fn dropped_events(&self) -> u64 {
+        self.dropped_events.load(Ordering::Relaxed)
+    }
+
+    pub async fn ping(&self) -> Result<(), Error> {
+        expect_response!(self.request(HostPacket::PingRequest).await?, GatewayPacket::PingResponse => ())
+    }
+
+    pub async fn link_stats(&self) -> Result<LinkStats, Error> {
+        expect_response!(self.request(HostPacket::LinkStatsRequest).await?, GatewayPacket::LinkStats(s) => s)
+    }
+
+    pub async fn reset_link_stats(&self) -> Result<(), Error> {
+        expect_response!(self.request(HostPacket::LinkStatsReset).await?, GatewayPacket::LinkStatsResetAck => ())
+    }
+
+    /* the stats arrive later as an uplink event */
+    pub async fn request_node_link_stats(&self, destination_address: usize) -> Result<(), Error> {
+        self.expect_ack(HostPacket::NodeLinkStats(NodeLinkStatsRequest {
+            destination_address,
+        }))
+        .await
+    }
+
+    /* the measurement arrives later as an uplink event */
+    pub async fn request_soil_sensor(&self, destination_address: usize) -> Result<(), Error> {
+        self.expect_ack(HostPacket::SoilSensor(SoilSensorRequest {
+            destination_address,
+        }))
+        .await
+    }
+
+    pub async fn firmware_info(&self) -> Result<FirmwareInfo, Error> {
+        expect_response!(self.request(HostPacket::GetInfo).await?, GatewayPacket::Info(i) => i)
+    }
+
+    /* the info arrives later as an uplink event */
+    pub async fn request_node_info(&self, destination_address: usize) -> Result<(), Error> {
+        self.expect_ack(HostPacket::NodeInfo(NodeInfoRequest {
+            destination_address,
+        }))
+        .await
+    }
+
+    pub async fn set_sniffer(&self, enabled: bool) -> Result<(), Error> {
+        expect_response!(self.request(HostPacket::SnifferMode(enabled)).await?, GatewayPacket::SnifferModeAck => ())
+    }
+
+    pub async fn raw_transmit(&self, request: RawTransmitRequest) -> Result<(), Error> {
+        expect_response!(self.request(HostPacket::RawTransmit(request)).await?, GatewayPacket::RawTransmitAck => ())
+    }
+
+    /// Chooses the events the gateway sends, the previous subscription is replaced.
+    pub async fn subscribe(&self, filter: EventFilter) -> Result<(), Error> {
+        expect_response!(self.request(HostPacket::Subscribe(filter)).await?, GatewayPacket::SubscribeAck => ())
+    }
+
+    /// See [`crate::Client::configure_log`].
+    pub async fn configure_log(&self, config: LogConfig) -> Result<(), Error> {
+        self.expect_ack(HostPacket::LogConfig(config)).await
+    }
+
+    /// Starts an update, gives back the blocks the node already has when it resumes
+    /// an earlier download of the same image.
+    pub async fn ota_init(&self, init: OtaInitRequest) -> Result<Option<OtaResume>, Error> {
+        match self.request(HostPacket::OtaInit(init)).await? {
+            GatewayPacket::OtaInitAck => Ok(None),
+            GatewayPacket::OtaResumed(r) => Ok(Some(r)),
+            p => Err(Error::UnexpectedResponse(Box::new(p))),
+        }
+    }
+
+    pub async fn ota_data(&self, data: OtaData) -> Result<(), Error> {
+        self.expect_ack(HostPacket::OtaData(data)).await
+    }
+
+    pub async fn ota_status(&self) -> Result<OtaStatus, Error> {
+        expect_response!(self.request(HostPacket::OtaGetStatus).await?, GatewayPacket::OtaStatus(s) => s)
+    }
+
+    /* the node may answer with its status instead, while blocks are still missing */
+    pub async fn ota_done(&self) -> Result<GatewayPacket, Error> {
+        self.request(HostPacket::OtaDoneRequest).await
+    }
+
+    pub async fn ota_abort(&self) -> Result<(), Error> {
+        expect_response!(self.request(HostPacket::OtaAbortRequest).await?, GatewayPacket::OtaAbortAck => ())
+    }
+
+    async fn expect_ack(&self, packet: HostPacket) -> Result<(), Error> {
+        expect_response!(self.request(packet).await?, GatewayPacket::Ack => ())
+    }
+}
+
+impl<S> Drop for AsyncClient<S> {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ErrorCode;
+    use tokio::io::DuplexStream;
+
+    /* answers every request the way `answer` says, from a task at the other end of the pipe */
+    fn fake_gateway(
+        mut answer: impl FnMut(HostMessage) -> Vec<GatewayMessage> + Send + 'static,
+    ) -> AsyncClient<DuplexStream> {
+        let (host, mut gateway) = tokio::io::duplex(4096);
+        tokio::spawn(async move {
+            let mut decoder = MessageDecoder::new();
+            let mut buffer = [0u8; 256];
+            while let Ok(len @ 1..) = gateway.read(&mut buffer).await {
+                let mut replies = Vec::new();
+                decoder.push(&buffer[..len], |m| replies.extend(answer(m.unwrap())));
+                for reply in replies {
+                    if gateway
+                        .write_all(&encode_frame(&reply).unwrap())
+                        .await
+                        .is_err()
+                    {
+                        return;
+                    }
+                }
+            }
+        });
+        let mut client = AsyncClient::new(host);
+        client.set_timeout(Duration::from_millis(200));
+        client
+    }
+
+    fn response(request_id: u16, packet: GatewayPacket) -> GatewayMessage {
+        GatewayMessage::Response { request_id, packet }
+    }
+
+    fn joined(address: usize) -> GatewayPacket {
+        GatewayPacket::NodeJoined {
+            address,
+            rssi: -80,
+            snr: 7,
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrent_requests_get_their_own_response() {
+        /* the answers are held back and sent in reverse order */
+        let mut held = Vec::new();
+        let client = fake_gateway(move |m| {
+            held.push(m);
+            if held.len() < 2 {
+                return vec![];
+            }
+            held.drain(..)
+                .rev()
+                .map(|m| match m.packet {
+                    HostPacket::PingRequest => response(m.request_id, GatewayPacket::PingResponse),
+                    _ => response(m.request_id, GatewayPacket::SnifferModeAck),
+                })
+                .collect()
+        });
+        let (ping, sniffer) = tokio::join!(client.ping(), client.set_sniffer(true));
+        ping.unwrap();
+        sniffer.unwrap();
+        assert_eq!(client.pending_requests(), 0);
+    }
+
+    #[tokio::test]
+    async fn events_are_delivered() {
+        let client = fake_gateway(|m| {
+            vec![
+                GatewayMessage::Event(joined(3)),
+                response(m.request_id, GatewayPacket::PingResponse),
+                GatewayMessage::Event(joined(4)),
+            ]
+        });
+        client.ping().await.unwrap();
+        assert_eq!(client.next_event().await, Some(joined(3)));
+        assert_eq!(client.next_event().await, Some(joined(4)));
+        assert_eq!(client.dropped_events(), 0);
+    }
+
+    #[tokio::test]
+    async fn unanswered_request_times_out() {
+        let client = fake_gateway(|_| vec![]);
+        assert!(matches!(client.ping().await, Err(Error::Timeout(_))));
+        assert_eq!(client.pending_requests(), 0);
+    }
+
+    #[tokio::test]
+    async fn dropped_request_leaves_nothing_pending() {
+        let client = fake_gateway(|_| vec![]);
+        let cancelled = tokio::time::timeout(Duration::from_millis(20), client.ping()).await;
+        assert!(cancelled.is_err());
+        assert_eq!(client.pending_requests(), 0);
+    }
+
+    #[tokio::test]
+    async fn gateway_errors_are_returned() {
+        let client = fake_gateway(|m| {
+            vec![response(
+                m.request_id,
+                GatewayPacket::Error {
+                    code: ErrorCode::Busy,
+                    detail: "full".into(),
+                },
+            )]
+        });
+        match client.ota_status().await {
+            Err(Error::Gateway { code, detail }) => {
+                assert_eq!(code, ErrorCode::Busy);
+                assert_eq!(detail, "full");
+            }
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+
+    #[tokio::test]
+    async fn closed_link_fails_the_request() {
+        let (host, gateway) = tokio::io::duplex(4096);
+        drop(gateway);
+        let client = AsyncClient::new(host);
+        assert!(client.ping().await.is_err());
+    }
+}
@@ -0,0 +1,321 @@
+use crate::{check_response, encode_frame, Error, MessageDecoder, Transport, DEFAULT_TIMEOUT};
+use gateway_host_schema::{
+    EventFilter, FirmwareInfo, GatewayMessage, GatewayPacket, HostMessage, HostPacket, LinkStats,
+    LogConfig, NodeInfoRequest, NodeLinkStatsRequest, OtaData, OtaInitRequest, OtaResume,
+    OtaStatus, RawTransmitRequest, SoilSensorRequest,
+};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/* events are dropped oldest first once nobody reads them */
+const MAX_QUEUED_EVENTS: usize = 1024;
+
+/// Blocking client, requests are sent one at a time and events received in
+/// the meantime are queued for [`Client::next_event`].
+pub struct Client<T: Transport> {
+    transport: T,
+    decoder: MessageDecoder,
+    next_request_id: u16,
+    events: VecDeque<GatewayPacket>,
+    timeout: Duration,
+}
+
+#[cfg(feature = "serial")]
+impl Client<crate::SerialTransport> {
+    pub fn open_serial(path: &str, baud_rate: u32) -> Result<Self, Error> {
+        Ok(Client::new(crate::SerialTransport::open(path, baud_rate)?))
+    }
+}
+
+impl<T: Transport> Client<T> {
+    pub fn new(transport: T) -> Self {
+        Client {
+            transport,
+            decoder: MessageDecoder::new(),
+            next_request_id: 0,
+            events: VecDeque::new(),
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// How long [`Client::request`] waits for the response.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn transport(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    /// Sends the packet and waits for its response, an error response from
+    /// the gateway is returned as [`Error::Gateway`].
+    pub fn request(&mut self, packet: HostPacket) -> Result<GatewayPacket, Error> {
+        let request_id = self.next_request_id;
+        self.next_request_id = self.next_request_id.wrapping_add(1);
+        let frame = encode_frame(&HostMessage { request_id, packet })?;
+        self.transport.write(&frame)?;
+
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            let mut response = None;
+            self.poll(deadline, |id, packet| {
+                /* responses to requests that timed out earlier are stale, drop them */
+                if id == request_id {
+                    response = Some(packet);
+                }
+            })?;
+            if let Some(packet) = response {
+                return check_response(packet);
+            }
+            if Instant::now() >= deadline {
+                return Err(Error::Timeout(self.timeout));
+            }
+        }
+    }
+
+    /// Returns the oldest event, waits at most `timeout` for one to arrive.
+    pub fn next_event(&mut self, timeout: Duration) -> Result<Option<GatewayPacket>, Error> {
+        let deadline = Instant::now() + timeout;
+        while self.events.is_empty() {
+            self.poll(deadline, |_, _| {})?;
+            if Instant::now() >= deadline {
+                break;
+            }
+        }
+        Ok(self.events.pop_front())
+    }
+
+    /* reads whatever arrives before the deadline, stops early after a response */
+    fn poll(
+        &mut self,
+        deadline: Instant,
+        mut on_response: impl FnMut(u16, GatewayPacket),
+    ) -> Result<(), Error> {
+        let mut buffer = [0u8; 256];
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        let len = self.transport.read(&mut buffer, timeout)?;
+        let events = &mut self.events;
+        self.decoder.push(&buffer[..len], |m| match m {
+            Ok(GatewayMessage::Response { request_id, packet }) => on_response(request_id, packet),
+            Ok(GatewayMessage::Event(packet)) => {
+                if events.len() >= MAX_QUEUED_EVENTS {
+                    events.pop_front();
+                }
+                events.push_back(packet);
+            }
+            /* a damaged frame, the request it answered times out */
+            Err(_) => {}
+        });
+        Ok(())
+    }
+
+    pub fn ping(&mut self) -> Result<(), Error> {
+        expect_response!(self.request(HostPacket::PingRequest)?, GatewayPacket::PingResponse => ())
+    }
+
+    pub fn link_stats(&mut self) -> Result<LinkStats, Error> {
+        expect_response!(self.request(HostPacket::LinkStatsRequest)?, GatewayPacket::LinkStats(s) => s)
+    }
+
+    pub fn reset_link_stats(&mut self) -> Result<(), Error> {
+        expect_response!(self.request(HostPacket::LinkStatsReset)?, GatewayPacket::LinkStatsResetAck => ())
+    }
+
+    /* the stats arrive later as an uplink event */
+    pub fn request_node_link_stats(&mut self, destination_address: usize) -> Result<(), Error> {
+        self.expect_ack(HostPacket::NodeLinkStats(NodeLinkStatsRequest {
+            destination_address,
+        }))
+    }
+
+    /* the measurement arrives later as an uplink event */
+    pub fn request_soil_sensor(&mut self, destination_address: usize) -> Result<(), Error> {
+        self.expect_ack(HostPacket::SoilSensor(SoilSensorRequest {
+            destination_address,
+        }))
+    }
+
+    pub fn firmware_info(&mut self) -> Result<FirmwareInfo, Error> {
+        expect_response!(self.request(HostPacket::GetInfo)?, GatewayPacket::Info(i) => i)
+    }
+
+    /* the info arrives later as an uplink event */
+    pub fn request_node_info(&mut self, destination_address: usize) -> Result<(), Error> {
+        self.expect_ack(HostPacket::NodeInfo(NodeInfoRequest {
+            destination_address,
+        }))
+    }
+
+    pub fn set_sniffer(&mut self, enabled: bool) -> Result<(), Error> {
+        expect_response!(self.request(HostPacket::SnifferMode(enabled))?, GatewayPacket::SnifferModeAck => ())
+    }
+
+    pub fn raw_transmit(&mut self, request: RawTransmitRequest) -> Result<(), Error> {
+        expect_response!(self.request(HostPacket::RawTransmit(request))?, GatewayPacket::RawTransmitAck => ())
+    }
+
+    /// Chooses the events the gateway sends, the previous subscription is replaced.
+    pub fn subscribe(&mut self, filter: EventFilter) -> Result<(), Error> {
+        expect_response!(self.request(HostPacket::Subscribe(filter))?, GatewayPacket::SubscribeAck => ())
+    }
+
+    /// Level and rate limit of the log forwarding, `GatewayPacket::Log` events
+    /// hold defmt frames, decoded with the ELF of the gateway firmware.
+    pub fn configure_log(&mut self, config: LogConfig) -> Result<(), Error> {
+        self.expect_ack(HostPacket::LogConfig(config))
+    }
+
+    /// Switches the gateway to KISS and gives back the transport, which then
+    /// carries KISS frames (see `gateway_host_schema::kiss`) until a Return command.
+    pub fn into_kiss(mut self) -> Result<T, Error> {
+        expect_response!(self.request(HostPacket::KissMode)?, GatewayPacket::KissModeAck => ())?;
+        Ok(self.transport)
+    }
+
+    /// Starts an update, gives back the blocks the node already has when it resumes
+    /// an earlier download of the same image.
+    pub fn ota_init(&mut self, init: OtaInitRequest) -> Result<Option<OtaResume>, Error> {
+        match self.request(HostPacket::OtaInit(init))? {
+            GatewayPacket::OtaInitAck => Ok(None),
+            GatewayPacket::OtaResumed(r) => Ok(Some(r)),
+            p => Err(Error::UnexpectedResponse(Box::new(p))),
+        }
+    }
+
+    pub fn ota_data(&mut self, data: OtaData) -> Result<(), Error> {
+        self.expect_ack(HostPacket::OtaData(data))
+    }
+
+    pub fn ota_status(&mut self) -> Result<OtaStatus, Error> {
+        expect_response!(self.request(HostPacket::OtaGetStatus)?, GatewayPacket::OtaStatus(s) => s)
+    }
+
+    /* the node may answer with its status instead, while blocks are still missing */
+    pub fn ota_done(&mut self) -> Result<GatewayPacket, Error> {
+        self.request(HostPacket::OtaDoneRequest)
+    }
+
+    pub fn ota_abort(&mut self) -> Result<(), Error> {
+        expect_response!(self.request(HostPacket::OtaAbortRequest)?, GatewayPacket::OtaAbortAck => ())
+    }
+
+    fn expect_ack(&mut self, packet: HostPacket) -> Result<(), Error> {
+        expect_response!(self.request(packet)?, GatewayPacket::Ack => ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ErrorCode, Loopback};
+    use std::thread;
+
+    /* answers every request the way `answer` says, from a thread at the other end of the loopback */
+    fn fake_gateway(
+        mut answer: impl FnMut(HostMessage) -> Vec<GatewayMessage> + Send + 'static,
+    ) -> Client<Loopback> {
+        let (host, mut gateway) = Loopback::pair();
+        thread::spawn(move || {
+            let mut decoder = MessageDecoder::new();
+            let mut buffer = [0u8; 256];
+            while let Ok(len) = gateway.read(&mut buffer, Duration::from_secs(10)) {
+                let mut replies = Vec::new();
+                decoder.push(&buffer[..len], |m| replies.extend(answer(m.unwrap())));
+                for reply in replies {
+                    if gateway.write(&encode_frame(&reply).unwrap()).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        let mut client = Client::new(host);
+        client.set_timeout(Duration::from_millis(200));
+        client
+    }
+
+    fn response(request_id: u16, packet: GatewayPacket) -> GatewayMessage {
+        GatewayMessage::Response { request_id, packet }
+    }
+
+    fn joined(address: usize) -> GatewayPacket {
+        GatewayPacket::NodeJoined {
+            address,
+            rssi: -80,
+            snr: 7,
+        }
+    }
+
+    #[test]
+    fn responses_are_matched_by_request_id() {
+        let mut client = fake_gateway(|m| match m.packet {
+            HostPacket::PingRequest => vec![response(m.request_id, GatewayPacket::PingResponse)],
+            HostPacket::SnifferMode(_) => vec![
+                /* a late answer to an earlier request comes first */
+                response(m.request_id.wrapping_sub(1), GatewayPacket::PingResponse),
+                response(m.request_id, GatewayPacket::SnifferModeAck),
+            ],
+            _ => vec![],
+        });
+        client.ping().unwrap();
+        client.set_sniffer(true).unwrap();
+        client.ping().unwrap();
+    }
+
+    #[test]
+    fn events_are_queued_while_waiting() {
+        let mut client = fake_gateway(|m| {
+            vec![
+                GatewayMessage::Event(joined(3)),
+                GatewayMessage::Event(joined(4)),
+                response(m.request_id, GatewayPacket::PingResponse),
+            ]
+        });
+        client.ping().unwrap();
+        let timeout = Duration::from_millis(50);
+        assert_eq!(client.next_event(timeout).unwrap(), Some(joined(3)));
+        assert_eq!(client.next_event(timeout).unwrap(), Some(joined(4)));
+        assert_eq!(client.next_event(timeout).unwrap(), None);
+    }
+
+    #[test]
+    fn unanswered_request_times_out() {
+        let mut client = fake_gateway(|m| match m.packet {
+            HostPacket::PingRequest => vec![response(m.request_id, GatewayPacket::PingResponse)],
+            _ => vec![],
+        });
+        assert!(matches!(client.link_stats(), Err(Error::Timeout(_))));
+        /* the link is still usable afterwards */
+        client.ping().unwrap();
+    }
+
+    #[test]
+    fn gateway_errors_are_returned() {
+        let mut client = fake_gateway(|m| {
+            vec![response(
+                m.request_id,
+                GatewayPacket::Error {
+                    code: ErrorCode::Busy,
+                    detail: "full".into(),
+                },
+            )]
+        });
+        match client.ping() {
+            Err(Error::Gateway { code, detail }) => {
+                assert_eq!(code, ErrorCode::Busy);
+                assert_eq!(detail, "full");
+            }
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+
+    #[test]
+    fn unexpected_response_is_reported() {
+        let mut client = fake_gateway(|m| vec![response(m.request_id, GatewayPacket::Ack)]);
+        assert!(matches!(client.ping(), Err(Error::UnexpectedResponse(_))));
+        client.request_soil_sensor(3).unwrap();
+    }
+}
@@ -0,0 +1,55 @@
+use crate::Error;
+use gateway_host_schema::framing::{self, FrameDecoder};
+use serde::{de::DeserializeOwned, Serialize};
+
+/* large enough for any packet of the schema, the gateway itself uses 256 */
+pub const MAX_FRAME_DATA: usize = 512;
+
+/// Serializes `message` into a complete frame, ready to be written to the link.
+pub fn encode_frame<T: Serialize>(message: &T) -> Result<Vec<u8>, Error> {
+    let data = postcard::to_allocvec(message)?;
+    let mut frame = vec![0u8; framing::max_encoded_len(data.len())];
+    let len = framing::encode(&data, &mut frame).map_err(Error::Framing)?;
+    frame.truncate(len);
+    Ok(frame)
+}
+
+/// Turns the byte stream of the link back into messages. Generic over the
+/// message type, so it decodes either direction of the protocol.
+pub struct MessageDecoder {
+    decoder: Box<FrameDecoder<MAX_FRAME_DATA>>,
+}
+
+impl MessageDecoder {
+    pub fn new() -> Self {
+        MessageDecoder {
+            decoder: Box::new(FrameDecoder::new()),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.decoder.reset();
+    }
+
+    /// Feeds received bytes, calls `on_message` for every complete frame.
+    /// Frames that fail to decode are passed as errors, the stream continues.
+    pub fn push<T: DeserializeOwned>(
+        &mut self,
+        bytes: &[u8],
+        mut on_message: impl FnMut(Result<T, Error>),
+    ) {
+        for byte in bytes {
+            match self.decoder.push(*byte) {
+                Some(Ok(data)) => on_message(postcard::from_bytes(data).map_err(Error::from)),
+                Some(Err(e)) => on_message(Err(Error::Framing(e))),
+                None => {}
+            }
+        }
+    }
+}
+
+impl Default for MessageDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,72 @@
+//! Host side of the gateway protocol.
+//!
+//! Talks to `module-gateway` over its host link: packets are framed with
+//! [`gateway_host_schema::framing`], wrapped in [`HostMessage`] envelopes and
+//! matched to their [`GatewayMessage::Response`] by request ID. Everything the
+//! gateway sends on its own arrives as an event.
+//!
+//! [`Client`] is blocking and works over any [`Transport`], [`Loopback`] lets
+//! it run against an in-memory peer instead of a serial port. With the
+//! `tokio` feature, [`AsyncClient`] offers the same over tokio IO.
+
+/* unwraps the one response packet a request expects */
+macro_rules! expect_response {
+    ($response:expr, $pattern:pat => $value:expr) => {
+        match $response {
+            $pattern => Ok($value),
+            p => Err($crate::Error::UnexpectedResponse(Box::new(p))),
+        }
+    };
+}
+
+#[cfg(feature = "tokio")]
+mod async_client;
+mod client;
+mod codec;
+mod transport;
+
+#[cfg(feature = "tokio")]
+pub use async_client::*;
+pub use client::*;
+pub use codec::*;
+pub use transport::*;
+
+pub use gateway_host_schema;
+pub use gateway_host_schema::{ErrorCode, GatewayMessage, GatewayPacket, HostMessage, HostPacket};
+
+use std::time::Duration;
+
+pub const DEFAULT_BAUD_RATE: u32 = 115200;
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("transport: {0}")]
+    Io(#[from] std::io::Error),
+    #[cfg(feature = "serial")]
+    #[error("serial port: {0}")]
+    Serial(#[from] serialport::Error),
+    #[error("serialization: {0}")]
+    Postcard(#[from] postcard::Error),
+    #[error("framing: {0:?}")]
+    Framing(gateway_host_schema::framing::FramingError),
+    #[error("no response within {0:?}")]
+    Timeout(Duration),
+    #[error("gateway reported {code:?}: {detail}")]
+    Gateway { code: ErrorCode, detail: String },
+    #[error("unexpected response {0:?}")]
+    UnexpectedResponse(Box<GatewayPacket>),
+    #[error("the client was closed")]
+    Closed,
+}
+
+/* turns the gateway's error responses into Err, so callers only see successful packets */
+pub(crate) fn check_response(packet: GatewayPacket) -> Result<GatewayPacket, Error> {
+    match packet {
+        GatewayPacket::Error { code, detail } => Err(Error::Gateway {
+            code,
+            detail: detail.as_str().into(),
+        }),
+        p => Ok(p),
+    }
+}
@@ -0,0 +1,100 @@
+use std::io;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::time::Duration;
+
+/// Byte stream to the gateway.
+pub trait Transport: Send {
+    fn write(&mut self, data: &[u8]) -> io::Result<()>;
+
+    /// Waits at most `timeout` for data, returns 0 when none arrived.
+    fn read(&mut self, buffer: &mut [u8], timeout: Duration) -> io::Result<usize>;
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        (**self).write(data)
+    }
+
+    fn read(&mut self, buffer: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        (**self).read(buffer, timeout)
+    }
+}
+
+#[cfg(feature = "serial")]
+pub struct SerialTransport {
+    port: Box<dyn serialport::SerialPort>,
+}
+
+#[cfg(feature = "serial")]
+impl SerialTransport {
+    pub fn open(path: &str, baud_rate: u32) -> Result<Self, serialport::Error> {
+        let port = serialport::new(path, baud_rate)
+            .timeout(Duration::from_millis(100))
+            .open()?;
+        Ok(SerialTransport { port })
+    }
+}
+
+#[cfg(feature = "serial")]
+impl Transport for SerialTransport {
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        io::Write::write_all(&mut self.port, data)?;
+        io::Write::flush(&mut self.port)
+    }
+
+    fn read(&mut self, buffer: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        self.port.set_timeout(timeout)?;
+        match io::Read::read(&mut self.port, buffer) {
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => Ok(0),
+            r => r,
+        }
+    }
+}
+
+/// One end of an in-memory link, whatever is written to one end is read from the other.
+pub struct Loopback {
+    tx: Sender<Vec<u8>>,
+    rx: Receiver<Vec<u8>>,
+    pending: Vec<u8>,
+}
+
+impl Loopback {
+    pub fn pair() -> (Loopback, Loopback) {
+        let (a_tx, b_rx) = channel();
+        let (b_tx, a_rx) = channel();
+        (
+            Loopback {
+                tx: a_tx,
+                rx: a_rx,
+                pending: Vec::new(),
+            },
+            Loopback {
+                tx: b_tx,
+                rx: b_rx,
+                pending: Vec::new(),
+            },
+        )
+    }
+}
+
+impl Transport for Loopback {
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.tx
+            .send(data.to_vec())
+            .map_err(|_| io::ErrorKind::BrokenPipe.into())
+    }
+
+    fn read(&mut self, buffer: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        if self.pending.is_empty() {
+            match self.rx.recv_timeout(timeout) {
+                Ok(data) => self.pending = data,
+                Err(RecvTimeoutError::Timeout) => return Ok(0),
+                Err(RecvTimeoutError::Disconnected) => return Err(io::ErrorKind::BrokenPipe.into()),
+            }
+        }
+        let len = buffer.len().min(self.pending.len());
+        buffer[..len].copy_from_slice(&self.pending[..len]);
+        self.pending.drain(..len);
+        Ok(len)
+    }
+}
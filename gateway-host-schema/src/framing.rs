@@ -0,0 +1,350 @@
+//! Framing of the packets exchanged between the gateway and the host.
+//!
+//! Each frame is the serialized packet followed by its CRC-16 (little endian),
+//! COBS encoded and terminated by a single `0x00` delimiter. Because the
+//! delimiter never appears inside an encoded frame, a receiver that joins the
+//! stream midway or hits garbage resynchronises at the next delimiter.
+//! Does not depend on `std`, so the firmware and the host tooling share it.
+
+pub const FRAME_DELIMITER: u8 = 0x00;
+pub const CRC_LENGTH: usize = 2;
+
+/// Size of the largest encoded frame, including the delimiter, for `len` bytes of data.
+pub const fn max_encoded_len(len: usize) -> usize {
+    let n = len + CRC_LENGTH;
+    n + n / 254 + 2
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FramingError {
+    NoData,
+    BufferTooSmall,
+    /// The frame is not valid COBS.
+    Corrupted,
+    /// The frame decoded, but its CRC does not match.
+    Checksum,
+    /// The frame did not fit into the decoder buffer and was dropped.
+    Overflow,
+}
+
+/// CRC-16/CCITT-FALSE
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xffffu16;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+struct CobsEncoder<'a> {
+    out: &'a mut [u8],
+    code_index: usize,
+    write: usize,
+    code: u8,
+}
+
+impl<'a> CobsEncoder<'a> {
+    fn new(out: &'a mut [u8]) -> Self {
+        CobsEncoder {
+            out,
+            code_index: 0,
+            write: 1,
+            code: 1,
+        }
+    }
+
+    fn end_block(&mut self) -> Result<(), FramingError> {
+        self.out[self.code_index] = self.code;
+        self.code_index = self.write;
+        self.write += 1;
+        self.code = 1;
+        if self.code_index >= self.out.len() {
+            return Err(FramingError::BufferTooSmall);
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, byte: u8) -> Result<(), FramingError> {
+        if byte == 0 {
+            return self.end_block();
+        }
+        *self
+            .out
+            .get_mut(self.write)
+            .ok_or(FramingError::BufferTooSmall)? = byte;
+        self.write += 1;
+        self.code += 1;
+        if self.code == 0xff {
+            self.end_block()?;
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> usize {
+        self.out[self.code_index] = self.code;
+        self.write
+    }
+}
+
+/// Encodes `data` into a complete frame including the delimiter, returns its length.
+pub fn encode(data: &[u8], out: &mut [u8]) -> Result<usize, FramingError> {
+    if data.is_empty() {
+        return Err(FramingError::NoData);
+    }
+    if out.is_empty() {
+        return Err(FramingError::BufferTooSmall);
+    }
+    let crc = crc16(data).to_le_bytes();
+    let mut encoder = CobsEncoder::new(out);
+    for byte in data.iter().chain(crc.iter()) {
+        encoder.push(*byte)?;
+    }
+    let len = encoder.finish();
+    *out.get_mut(len).ok_or(FramingError::BufferTooSmall)? = FRAME_DELIMITER;
+    Ok(len + 1)
+}
+
+/* the decoded data is never longer than the encoded, so it can be written over it */
+fn cobs_decode_in_place(buffer: &mut [u8]) -> Result<usize, FramingError> {
+    let mut read = 0;
+    let mut write = 0;
+    while read < buffer.len() {
+        let code = buffer[read] as usize;
+        if code == 0 {
+            return Err(FramingError::Corrupted);
+        }
+        read += 1;
+        let n = code - 1;
+        if read + n > buffer.len() {
+            return Err(FramingError::Corrupted);
+        }
+        buffer.copy_within(read..read + n, write);
+        write += n;
+        read += n;
+        if code != 0xff && read < buffer.len() {
+            buffer[write] = 0;
+            write += 1;
+        }
+    }
+    Ok(write)
+}
+
+/// Decodes frames from a byte stream one byte at a time, the frame boundaries
+/// do not need to line up with however the bytes were received.
+pub struct FrameDecoder<const N: usize> {
+    buffer: [u8; N],
+    len: usize,
+    overflow: bool,
+}
+
+impl<const N: usize> FrameDecoder<N> {
+    pub const fn new() -> Self {
+        FrameDecoder {
+            buffer: [0u8; N],
+            len: 0,
+            overflow: false,
+        }
+    }
+
+    /// Drops the partially received frame.
+    pub fn reset(&mut self) {
+        self.len = 0;
+        self.overflow = false;
+    }
+
+    /// Feeds one byte, returns the data of a frame once its delimiter arrives.
+    /// Errors only discard the current frame, the next one decodes normally.
+    pub fn push(&mut self, byte: u8) -> Option<Result<&[u8], FramingError>> {
+        if byte != FRAME_DELIMITER {
+            if self.len < N {
+                self.buffer[self.len] = byte;
+                self.len += 1;
+            } else {
+                self.overflow = true;
+            }
+            return None;
+        }
+        let len = self.len;
+        let overflow = self.overflow;
+        self.reset();
+        if overflow {
+            return Some(Err(FramingError::Overflow));
+        }
+        if len == 0 {
+            /* consecutive delimiters, used by the host to flush the line */
+            return None;
+        }
+        Some(Self::check(&mut self.buffer[..len]))
+    }
+
+    fn check(encoded: &mut [u8]) -> Result<&[u8], FramingError> {
+        let len = cobs_decode_in_place(encoded)?;
+        if len <= CRC_LENGTH {
+            return Err(FramingError::NoData);
+        }
+        let (data, crc) = encoded[..len].split_at(len - CRC_LENGTH);
+        if crc16(data).to_le_bytes() != crc {
+            return Err(FramingError::Checksum);
+        }
+        Ok(data)
+    }
+}
+
+impl<const N: usize> Default for FrameDecoder<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const N: usize = 1024;
+
+    fn encode_vec(data: &[u8]) -> std::vec::Vec<u8> {
+        let mut out = std::vec![0u8; max_encoded_len(data.len())];
+        let len = encode(data, &mut out).unwrap();
+        out.truncate(len);
+        out
+    }
+
+    /* every frame the bytes complete, in order */
+    fn decode_all<const M: usize>(
+        decoder: &mut FrameDecoder<M>,
+        bytes: &[u8],
+    ) -> std::vec::Vec<Result<std::vec::Vec<u8>, FramingError>> {
+        bytes
+            .iter()
+            .filter_map(|b| decoder.push(*b).map(|r| r.map(|d| d.to_vec())))
+            .collect()
+    }
+
+    #[test]
+    fn crc16_check_value() {
+        assert_eq!(crc16(b"123456789"), 0x29b1);
+    }
+
+    #[test]
+    fn round_trip() {
+        let cases: [&[u8]; 6] = [
+            &[1],
+            &[0],
+            &[0, 0, 0],
+            &[1, 2, 0, 3, 0, 0, 4],
+            b"hello gateway",
+            &[0xff; 40],
+        ];
+        let mut decoder = FrameDecoder::<N>::new();
+        for data in cases {
+            let frame = encode_vec(data);
+            assert_eq!(frame.last(), Some(&FRAME_DELIMITER));
+            assert!(!frame[..frame.len() - 1].contains(&FRAME_DELIMITER));
+            assert!(frame.len() <= max_encoded_len(data.len()));
+            assert_eq!(decode_all(&mut decoder, &frame), [Ok(data.to_vec())]);
+        }
+    }
+
+    #[test]
+    fn zero_free_runs_around_254() {
+        let mut decoder = FrameDecoder::<N>::new();
+        for len in [252, 253, 254, 255, 256, 508, 600] {
+            /* the CRC must not add a zero either, or the run is cut short */
+            let mut data: std::vec::Vec<u8> = (0..len).map(|i| (i % 255 + 1) as u8).collect();
+            while crc16(&data).to_le_bytes().contains(&0) {
+                data[0] = data[0] % 254 + 1;
+            }
+            let frame = encode_vec(&data);
+            assert!(frame.len() <= max_encoded_len(len), "len {}", len);
+            assert_eq!(decode_all(&mut decoder, &frame), [Ok(data)], "len {}", len);
+        }
+    }
+
+    #[test]
+    fn consecutive_delimiters_are_ignored() {
+        let mut decoder = FrameDecoder::<N>::new();
+        let mut bytes = std::vec![0, 0, 0];
+        bytes.extend(encode_vec(&[7, 8, 9]));
+        bytes.extend([0, 0]);
+        assert_eq!(decode_all(&mut decoder, &bytes), [Ok(std::vec![7, 8, 9])]);
+    }
+
+    #[test]
+    fn garbage_then_resync() {
+        let mut decoder = FrameDecoder::<N>::new();
+        let frame = encode_vec(b"first");
+        /* joined midway through a frame, then line noise */
+        let mut bytes = frame[3..].to_vec();
+        bytes.extend([0x13, 0x37, 0xaa, 0x55, 0x00]);
+        bytes.extend(encode_vec(b"second"));
+        let results = decode_all(&mut decoder, &bytes);
+        assert_eq!(results.len(), 3);
+        assert!(results[0].is_err());
+        assert!(results[1].is_err());
+        assert_eq!(results[2], Ok(b"second".to_vec()));
+    }
+
+    #[test]
+    fn corrupted_crc_is_rejected() {
+        let mut decoder = FrameDecoder::<N>::new();
+        let data = b"payload with a checksum";
+        let mut frame = encode_vec(data);
+        /* a data byte, it stays non-zero so the COBS structure holds */
+        frame[5] ^= 0x01;
+        assert_eq!(
+            decode_all(&mut decoder, &frame),
+            [Err(FramingError::Checksum)]
+        );
+        assert_eq!(
+            decode_all(&mut decoder, &encode_vec(data)),
+            [Ok(data.to_vec())]
+        );
+    }
+
+    #[test]
+    fn broken_cobs_is_rejected() {
+        let mut decoder = FrameDecoder::<N>::new();
+        /* the code byte points past the end of the frame */
+        assert_eq!(
+            decode_all(&mut decoder, &[0x09, 1, 2, 0]),
+            [Err(FramingError::Corrupted)]
+        );
+        /* too short to carry a CRC */
+        assert_eq!(
+            decode_all(&mut decoder, &[0x02, 1, 0]),
+            [Err(FramingError::NoData)]
+        );
+    }
+
+    #[test]
+    fn oversized_frame_is_dropped() {
+        let mut decoder = FrameDecoder::<16>::new();
+        let mut bytes = encode_vec(&[0x42; 32]);
+        bytes.extend(encode_vec(&[1, 2, 3]));
+        assert_eq!(
+            decode_all(&mut decoder, &bytes),
+            [Err(FramingError::Overflow), Ok(std::vec![1, 2, 3])]
+        );
+    }
+
+    #[test]
+    fn encode_errors() {
+        let mut out = [0u8; 8];
+        assert_eq!(encode(&[], &mut out), Err(FramingError::NoData));
+        assert_eq!(
+            encode(&[1; 16], &mut out),
+            Err(FramingError::BufferTooSmall)
+        );
+        assert_eq!(encode(&[1], &mut []), Err(FramingError::BufferTooSmall));
+        let mut exact = [0u8; max_encoded_len(6)];
+        assert!(encode(&[1, 2, 3, 4, 5, 6], &mut exact).is_ok());
+    }
+}
@@ -0,0 +1,245 @@
+//! KISS framing, the protocol packet-radio software uses to talk to a TNC.
+//!
+//! Each frame starts and ends with `FEND`, `FEND` and `FESC` inside it are
+//! escaped. The first byte of a frame holds the port in its upper nibble and
+//! the command in the lower one, the rest is the data. Unlike `framing`,
+//! there is no checksum, the link to the TNC is assumed reliable.
+
+pub const FEND: u8 = 0xc0;
+pub const FESC: u8 = 0xdb;
+pub const TFEND: u8 = 0xdc;
+pub const TFESC: u8 = 0xdd;
+
+/// Size of the largest encoded frame for `len` bytes of data.
+pub const fn max_encoded_len(len: usize) -> usize {
+    /* both delimiters, the type byte and every byte escaped */
+    2 + 2 * (len + 1)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum KissError {
+    BufferTooSmall,
+    /// `FESC` followed by something else than `TFEND` or `TFESC`.
+    InvalidEscape,
+    /// The frame did not fit into the decoder buffer and was dropped.
+    Overflow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum KissCommand {
+    DataFrame,
+    TxDelay,
+    Persistence,
+    SlotTime,
+    TxTail,
+    FullDuplex,
+    SetHardware,
+    /// Leaves KISS mode, the port nibble is meaningless.
+    Return,
+    Unknown(u8),
+}
+
+impl KissCommand {
+    pub fn from_u8(value: u8) -> Self {
+        match value {
+            0x00 => KissCommand::DataFrame,
+            0x01 => KissCommand::TxDelay,
+            0x02 => KissCommand::Persistence,
+            0x03 => KissCommand::SlotTime,
+            0x04 => KissCommand::TxTail,
+            0x05 => KissCommand::FullDuplex,
+            0x06 => KissCommand::SetHardware,
+            0x0f => KissCommand::Return,
+            c => KissCommand::Unknown(c),
+        }
+    }
+
+    pub fn as_u8(&self) -> u8 {
+        match self {
+            KissCommand::DataFrame => 0x00,
+            KissCommand::TxDelay => 0x01,
+            KissCommand::Persistence => 0x02,
+            KissCommand::SlotTime => 0x03,
+            KissCommand::TxTail => 0x04,
+            KissCommand::FullDuplex => 0x05,
+            KissCommand::SetHardware => 0x06,
+            KissCommand::Return => 0x0f,
+            KissCommand::Unknown(c) => *c,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct KissFrame<'a> {
+    pub port: u8,
+    pub command: KissCommand,
+    pub data: &'a [u8],
+}
+
+/// Encodes a complete frame including both delimiters, returns its length.
+pub fn encode(
+    port: u8,
+    command: KissCommand,
+    data: &[u8],
+    out: &mut [u8],
+) -> Result<usize, KissError> {
+    let mut len = 0;
+    let mut put = |byte: u8| -> Result<(), KissError> {
+        *out.get_mut(len).ok_or(KissError::BufferTooSmall)? = byte;
+        len += 1;
+        Ok(())
+    };
+    put(FEND)?;
+    let kind = match command {
+        KissCommand::Return => 0xff,
+        c => (port << 4) | (c.as_u8() & 0x0f),
+    };
+    for byte in core::iter::once(&kind).chain(data.iter()) {
+        match *byte {
+            FEND => {
+                put(FESC)?;
+                put(TFEND)?;
+            }
+            FESC => {
+                put(FESC)?;
+                put(TFESC)?;
+            }
+            b => put(b)?,
+        }
+    }
+    put(FEND)?;
+    Ok(len)
+}
+
+/// Decodes frames from a byte stream one byte at a time, like `framing::FrameDecoder`.
+pub struct KissDecoder<const N: usize> {
+    buffer: [u8; N],
+    len: usize,
+    escape: bool,
+    error: Option<KissError>,
+}
+
+impl<const N: usize> KissDecoder<N> {
+    pub const fn new() -> Self {
+        KissDecoder {
+            buffer: [0u8; N],
+            len: 0,
+            escape: false,
+            error: None,
+        }
+    }
+
+    /// Drops the partially received frame.
+    pub fn reset(&mut self) {
+        self.len = 0;
+        self.escape = false;
+        self.error = None;
+    }
+
+    /// Feeds one byte, returns the frame once its closing `FEND` arrives.
+    /// Errors only discard the current frame, the next one decodes normally.
+    pub fn push(&mut self, byte: u8) -> Option<Result<KissFrame<'_>, KissError>> {
+        let result = self.push_byte(byte)?;
+        Some(result.map(|len| self.frame(len)))
+    }
+
+    /// Feeds bytes up to the end of the first frame in them, returns how many
+    /// were used along with the frame. The bytes after a `Return` belong to
+    /// whatever the link speaks next.
+    pub fn push_slice(
+        &mut self,
+        bytes: &[u8],
+    ) -> (usize, Option<Result<KissFrame<'_>, KissError>>) {
+        for (i, byte) in bytes.iter().enumerate() {
+            if let Some(result) = self.push_byte(*byte) {
+                return (i + 1, Some(result.map(|len| self.frame(len))));
+            }
+        }
+        (bytes.len(), None)
+    }
+
+    /* the length of the frame in the buffer once it is complete */
+    fn push_byte(&mut self, byte: u8) -> Option<Result<usize, KissError>> {
+        if byte == FEND {
+            let len = self.len;
+            let error = self.error;
+            self.reset();
+            if let Some(e) = error {
+                return Some(Err(e));
+            }
+            if len == 0 {
+                /* back to back delimiters, senders use them to flush the line */
+                return None;
+            }
+            return Some(Ok(len));
+        }
+        if self.error.is_some() {
+            return None;
+        }
+        let byte = match (self.escape, byte) {
+            (false, FESC) => {
+                self.escape = true;
+                return None;
+            }
+            (false, b) => b,
+            (true, TFEND) => FEND,
+            (true, TFESC) => FESC,
+            (true, _) => {
+                self.error = Some(KissError::InvalidEscape);
+                return None;
+            }
+        };
+        self.escape = false;
+        if self.len < N {
+            self.buffer[self.len] = byte;
+            self.len += 1;
+        } else {
+            self.error = Some(KissError::Overflow);
+        }
+        None
+    }
+
+    fn frame(&self, len: usize) -> KissFrame<'_> {
+        KissFrame {
+            port: self.buffer[0] >> 4,
+            command: KissCommand::from_u8(self.buffer[0] & 0x0f),
+            data: &self.buffer[1..len],
+        }
+    }
+}
+
+impl<const N: usize> Default for KissDecoder<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framing::{self, FrameDecoder};
+
+    #[test]
+    fn return_leaves_the_rest_of_the_chunk() {
+        let mut chunk = [0u8; 64];
+        let mut len = encode(0, KissCommand::Return, &[], &mut chunk).unwrap();
+        len += framing::encode(b"next frame", &mut chunk[len..]).unwrap();
+
+        let mut kiss = KissDecoder::<16>::new();
+        let (used, frame) = kiss.push_slice(&chunk[..len]);
+        assert_eq!(frame.unwrap().unwrap().command, KissCommand::Return);
+        assert_eq!(used, 3);
+
+        /* what the host FrameReader gets back when KISS mode ends */
+        let mut frames = FrameDecoder::<64>::new();
+        let mut decoded = None;
+        for byte in &chunk[used..len] {
+            if let Some(frame) = frames.push(*byte) {
+                decoded = Some(frame.unwrap().to_vec());
+            }
+        }
+        assert_eq!(decoded.as_deref(), Some(&b"next frame"[..]));
+    }
+}
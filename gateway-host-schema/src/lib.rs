@@ -1,8 +1,17 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 
-use heapless::Vec;
+pub mod framing;
+pub mod kiss;
+
+use heapless::{String, Vec};
 use serde::{Deserialize, Serialize};
 
+/* a LoRa frame of the modules: header, payload and CRC */
+pub const PACKET_LENGTH: usize = 128;
+pub const HEADER_LENGTH: usize = 5;
+pub const CHECKSUM_LENGTH: usize = 4;
+pub const PAYLOAD_LENGTH: usize = PACKET_LENGTH - HEADER_LENGTH - CHECKSUM_LENGTH;
+
 #[derive(Debug, PartialEq, Deserialize, Serialize)]
 pub struct OtaInitRequest {
     pub destination_address: usize,
@@ -10,6 +19,19 @@ pub struct OtaInitRequest {
     pub binary_sha256: [u8; 32],
     pub block_size: u16,
     pub block_count: u16,
+    /* Ed25519 signature of ota_manifest, required by nodes built with signed_ota */
+    pub signature: Option<Vec<u8, 64>>,
+}
+
+pub const OTA_MANIFEST_LENGTH: usize = 48;
+
+/// What the signature of an OTA image signs, the image itself through its hash.
+pub fn ota_manifest(binary_size: u32, binary_sha256: &[u8; 32]) -> [u8; OTA_MANIFEST_LENGTH] {
+    let mut manifest = [0u8; OTA_MANIFEST_LENGTH];
+    manifest[..12].copy_from_slice(b"lora-ota-v1\0");
+    manifest[12..16].copy_from_slice(&binary_size.to_le_bytes());
+    manifest[16..].copy_from_slice(binary_sha256);
+    manifest
 }
 
 #[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
@@ -18,18 +40,249 @@ pub struct OtaData {
     pub data: Vec<u8, 96>,
 }
 
+/* the OtaData blocks the gateway keeps track of until the node acknowledges them */
+pub const OTA_MAX_NOT_ACKED: usize = 64;
+
 #[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
 pub struct OtaStatus {
     pub in_progress: bool,
-    pub not_acked: Vec<u16, 64>,
+    pub not_acked: Vec<u16, OTA_MAX_NOT_ACKED>,
     pub last_acked: u16,
 }
 
+/* answers OtaInit instead of OtaInitAck when the node already holds part of the image,
+from an earlier attempt or from before it reset, the host skips these blocks */
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize, Serialize)]
+pub struct OtaResume {
+    /* every block below it is on the node */
+    pub first_missing_index: u16,
+    /* blocks after it the node has too, the first 32 of them */
+    pub received_indexes: Vec<u16, 32>,
+}
+
 #[derive(Debug, PartialEq, Deserialize, Serialize)]
 pub struct SoilSensorRequest {
     pub destination_address: usize,
 }
 
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+pub struct LinkStats {
+    pub tx: u32,
+    pub rx: u32,
+    pub crc_failures: u32,
+    pub parse_failures: u32,
+    pub address_mismatches: u32,
+    pub timeouts: u32,
+    pub retries: u32,
+    pub recoveries: u32,
+    pub last_rssi: i16,
+    pub last_snr: i16,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct NodeLinkStatsRequest {
+    pub destination_address: usize,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct NodeInfoRequest {
+    pub destination_address: usize,
+}
+
+/* embedded by the build scripts, the strings are cut short when they do not fit,
+small enough to fit a LoRa packet */
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct FirmwareInfo {
+    pub name: String<16>,           // crate name, e.g. module-node
+    pub version: String<12>,        // crate version
+    pub git_hash: String<10>,       // short hash, + appended when the tree was dirty
+    pub build_time: u64,            // unix seconds
+    pub module_version: String<12>, // the ModuleVersion the firmware was built for
+    pub features: String<40>,       // enabled cargo features, comma separated
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct LinkMetadata {
+    pub rssi: i16,
+    pub snr: i16,
+}
+
+/* sent by a node running new firmware for the first time, when the health checks of the
+application decided about it */
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub enum BootReport {
+    /* every check passed, the node stays on the new firmware */
+    Confirmed,
+    /* the node resets into the previous firmware, check is the one that failed or
+    the first that did not pass in time */
+    RollingBack { check: String<16>, timed_out: bool },
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub enum UplinkPayload {
+    SoilSensorMoisture([u16; 4]),
+    LinkStats(LinkStats),
+    FirmwareInfo(FirmwareInfo),
+    /* the node sent a known packet type, but the payload could not be decoded */
+    Malformed { packet_type: u8, length: usize },
+    BootReport(BootReport),
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct Uplink {
+    pub source_address: usize,
+    pub link: LinkMetadata,
+    pub payload: UplinkPayload,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct FrameHeader {
+    pub destination: usize,
+    pub source: usize,
+    pub packet_type: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct SniffedFrame {
+    pub data: Vec<u8, PACKET_LENGTH>,
+    pub crc_ok: bool,
+    pub header: Option<FrameHeader>,
+    pub rssi: i16,
+    pub snr: i16,
+    pub timestamp_ms: u64, // since the gateway booted
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ModulationOverride {
+    pub spreading_factor: u8, // 5 to 12
+    pub bandwidth_hz: u32,    // one of the LoRa bandwidths, e.g. 125_000
+    pub coding_rate: u8,      // denominator of 4/5 to 4/8
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct RawTransmitRequest {
+    pub destination_address: usize,
+    pub packet_type: u8,
+    pub payload: Vec<u8, PAYLOAD_LENGTH>,
+    /* applies to this transmission only, the gateway keeps listening with its own */
+    pub modulation: Option<ModulationOverride>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum LogLevel {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+    /* nothing is forwarded, RTT still gets everything */
+    Off,
+}
+
+/* forwarding of the gateway's defmt output to the host,
+the level only narrows what the firmware was built with (DEFMT_LOG) */
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct LogConfig {
+    pub level: LogLevel,
+    pub max_per_second: u16, // 0 for no limit
+}
+
+/* the kinds of events the host can subscribe to */
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct EventClasses {
+    pub uplinks: bool,    // Uplink and PeerMessage, except link stats
+    pub ota: bool,        // OTA progress reported by the node
+    pub link_stats: bool, // Uplink with LinkStats of a node
+    pub node_joins: bool, // NodeJoined
+    pub logs: bool,       // log output of the gateway
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct EventFilter {
+    pub classes: EventClasses,
+    /* only events of these nodes, every node when empty */
+    pub sources: Vec<usize, 8>,
+}
+
+impl EventFilter {
+    /* what the gateway starts with, every event */
+    pub fn all() -> Self {
+        EventFilter {
+            classes: EventClasses {
+                uplinks: true,
+                ota: true,
+                link_stats: true,
+                node_joins: true,
+                logs: true,
+            },
+            sources: Vec::new(),
+        }
+    }
+
+    /* errors, sniffed frames and EventsDropped are always delivered,
+    sniffing is enabled by its own command already */
+    pub fn accepts(&self, event: &GatewayPacket) -> bool {
+        let (class, source) = match event {
+            GatewayPacket::Uplink(u) => match u.payload {
+                UplinkPayload::LinkStats(_) => (self.classes.link_stats, Some(u.source_address)),
+                UplinkPayload::BootReport(_) => (self.classes.ota, Some(u.source_address)),
+                _ => (self.classes.uplinks, Some(u.source_address)),
+            },
+            GatewayPacket::PeerMessage { source, .. } => (self.classes.uplinks, Some(*source)),
+            GatewayPacket::OtaInitAck
+            | GatewayPacket::OtaResumed(_)
+            | GatewayPacket::OtaStatus(_)
+            | GatewayPacket::OtaDoneAck
+            | GatewayPacket::OtaAbortAck => (self.classes.ota, None),
+            GatewayPacket::NodeJoined { address, .. } => (self.classes.node_joins, Some(*address)),
+            GatewayPacket::Log { .. } => (self.classes.logs, None),
+            _ => return true,
+        };
+        class
+            && match source {
+                Some(s) if !self.sources.is_empty() => self.sources.contains(&s),
+                _ => true,
+            }
+    }
+}
+
+impl Default for EventFilter {
+    fn default() -> Self {
+        Self::all()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum ErrorCode {
+    /* mirrors OtaError of the gateway's OTA producer */
+    OtaDeserialize,
+    OtaSerialize,
+    OtaTransmit,
+    OtaReceive,
+    OtaInvalidPacketType,
+    OtaAlreadyStarted,
+    OtaNotStarted,
+    OtaMemoryWriteFailed,
+    /* the radio driver failed, the detail names the RadioError */
+    Radio,
+    /* the frame from the host arrived intact, but is not a valid HostMessage */
+    HostDecode,
+    /* a frame from the host was corrupted or lost, it had no request ID to respond to */
+    HostFraming,
+    /* bytes from the host were lost by the UART or SPI */
+    HostLink,
+    /* the command is valid, but not available in this build of the gateway */
+    Unsupported,
+    /* the node refused the image at the end of the OTA, the detail names the OtaFailure */
+    OtaRejected,
+    /* too many requests were waiting for the gateway, this one was not processed and can be sent again */
+    Busy,
+    /* OTA_MAX_NOT_ACKED blocks wait for the node to acknowledge them, this one was not sent,
+    it can be sent again once OtaStatus shows fewer */
+    OtaTooManyNotAcked,
+}
+
 #[derive(Debug, PartialEq, Deserialize, Serialize)]
 pub enum HostPacket {
     PingRequest,
@@ -41,10 +294,36 @@ pub enum HostPacket {
     OtaAbortRequest,
 
     SoilSensor(SoilSensorRequest),
+
+    LinkStatsRequest,
+    LinkStatsReset,
+    NodeLinkStats(NodeLinkStatsRequest),
+
+    GetInfo,
+    /* the node answers with an uplink */
+    NodeInfo(NodeInfoRequest),
+
+    SnifferMode(bool),
+
+    RawTransmit(RawTransmitRequest),
+
+    /* the link switches to KISS after the ack, until a KISS Return command */
+    KissMode,
+
+    /* replaces the previous subscription, the gateway starts with EventFilter::all() */
+    Subscribe(EventFilter),
+
+    /* answered with Ack, or Unsupported when the gateway was built without host_log */
+    LogConfig(LogConfig),
 }
 
 #[derive(Debug, PartialEq, Deserialize, Serialize)]
 pub enum GatewayPacket {
+    /* the command was carried out, it has no data to return */
+    Ack,
+    /* the command failed, or an event describing a failure without a command */
+    Error { code: ErrorCode, detail: String<48> },
+
     PingResponse,
 
     OtaInitAck,
@@ -52,5 +331,54 @@ pub enum GatewayPacket {
     OtaDoneAck,
     OtaAbortAck,
 
-    SoilSensorMoisture([u16; 4]),
+    Uplink(Uplink),
+
+    LinkStats(LinkStats),
+    LinkStatsResetAck,
+
+    Info(FirmwareInfo),
+
+    SnifferModeAck,
+    SniffedFrame(SniffedFrame),
+
+    KissModeAck,
+
+    SubscribeAck,
+    /* events that passed the filter, but were dropped because the host link did not keep up */
+    EventsDropped { count: u32 },
+    /* the first packet heard from a node since the gateway started */
+    NodeJoined { address: usize, rssi: i16, snr: i16 },
+
+    RawTransmitAck,
+    PeerMessage {
+        source: usize,
+        packet_type: u8,
+        payload: Vec<u8, PAYLOAD_LENGTH>,
+        rssi: i16,
+        snr: i16,
+    },
+
+    /* a raw defmt frame, as it is before the rzcobs encoding of RTT, decoded by the host with the ELF */
+    Log {
+        frame: Vec<u8, 128>,
+        /* log messages not forwarded since the previous one, because of the rate limit or a full queue */
+        suppressed: u32,
+    },
+
+    OtaResumed(OtaResume),
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct HostMessage {
+    /* chosen by the host, echoed in the response */
+    pub request_id: u16,
+    pub packet: HostPacket,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub enum GatewayMessage {
+    /* exactly one for every HostMessage */
+    Response { request_id: u16, packet: GatewayPacket },
+    /* sent by the gateway on its own, e.g. uplinks from the nodes */
+    Event(GatewayPacket),
 }
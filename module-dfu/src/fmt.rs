@@ -0,0 +1,26 @@
+/* the log goes to defmt in the firmware, nowhere on the host */
+
+macro_rules! warn {
+    ($s:literal $(, $x:expr)* $(,)?) => {{
+        #[cfg(feature = "defmt")]
+        ::defmt::warn!($s $(, $x)*);
+        #[cfg(not(feature = "defmt"))]
+        let _ = ($(&$x),*);
+    }};
+}
+
+macro_rules! error {
+    ($s:literal $(, $x:expr)* $(,)?) => {{
+        #[cfg(feature = "defmt")]
+        ::defmt::error!($s $(, $x)*);
+        #[cfg(not(feature = "defmt"))]
+        let _ = ($(&$x),*);
+    }};
+}
+
+#[cfg(feature = "defmt")]
+pub(crate) use defmt::Debug2Format;
+
+/* only ever an argument of the macros above, which drop it */
+#[cfg(not(feature = "defmt"))]
+pub(crate) struct Debug2Format<'a, T: ?Sized>(pub &'a T);
@@ -0,0 +1,534 @@
+//! The DFU partition of a module as a NorFlash, the part of `DfuMemory` in
+//! module-runtime that does not need the bootloader, so it is tested on the host.
+
+#![cfg_attr(not(test), no_std)]
+#![allow(async_fn_in_trait)]
+
+#[macro_use]
+mod fmt;
+
+use crate::fmt::Debug2Format;
+use core::fmt::Debug;
+use embedded_storage_async::nor_flash::NorFlash;
+use gateway_core::OtaSessionRecord;
+
+/*
+Blocks are collected in a RAM copy of one flash page, which goes to flash through
+FirmwareWriter::write_firmware (it erases the page first)
+    when a block of another page arrives
+    when the image is read back to verify it, this writes the last partial page
+Blocks arrive out of order and are sent again, so a page is read from flash before
+it is changed. The part of the partition the image needs is erased when the download
+starts, pages never written read as 0xff and not as the previous image.
+The last page of the partition keeps the session, the image has to fit before it.
+It is split into slots of SESSION_SLOT_SIZE, each written once:
+    [length u16][postcard OtaSessionRecord][0xff padding][SESSION_MAGIC u32]
+The last slot with the magic holds the session, the page is erased once every slot was
+used. A record is written only after a page of the image went to flash, the blocks still
+in RAM are left out of it since a reset loses them.
+*/
+
+const SESSION_SLOT_SIZE: usize = 512;
+const SESSION_MAGIC: u32 = 0x4f54_4153;
+
+/// Writes a page of the image, the FirmwareUpdater of embassy-boot on the modules.
+pub trait FirmwareWriter {
+    type Error: Debug;
+
+    /* erases the pages of the DFU partition the data covers, then writes it */
+    async fn write_firmware(&mut self, offset: usize, data: &[u8]) -> Result<(), Self::Error>;
+}
+
+#[repr(C, align(32))]
+struct AlignedBuffer<const N: usize>([u8; N]);
+
+pub struct DfuPages<W: FirmwareWriter, DFU: NorFlash, const PAGE_SIZE: usize> {
+    pub writer: W,
+    /* a second handle of the same partition, the writer cannot read it back */
+    dfu: DFU,
+    page: AlignedBuffer<PAGE_SIZE>,
+    /* where the page in RAM belongs, None when there is none */
+    page_offset: Option<usize>,
+    /* the page in RAM differs from the flash */
+    dirty: bool,
+    /* a page of the image went to flash since the session was saved */
+    flushed_since_save: bool,
+}
+
+impl<W: FirmwareWriter, DFU: NorFlash, const PAGE_SIZE: usize> DfuPages<W, DFU, PAGE_SIZE> {
+    pub fn new(writer: W, dfu: DFU) -> Self {
+        assert_eq!(PAGE_SIZE % DFU::ERASE_SIZE, 0);
+        assert_eq!(PAGE_SIZE % DFU::WRITE_SIZE, 0);
+        DfuPages {
+            writer,
+            dfu,
+            page: AlignedBuffer([0xff; PAGE_SIZE]),
+            page_offset: None,
+            dirty: false,
+            flushed_since_save: false,
+        }
+    }
+
+    fn session_offset(&self) -> usize {
+        self.dfu.capacity() - PAGE_SIZE
+    }
+
+    /* writes the page in RAM if it changed */
+    pub async fn flush(&mut self) -> bool {
+        let offset = match self.page_offset {
+            Some(o) if self.dirty => o,
+            _ => return true,
+        };
+        match self.writer.write_firmware(offset, &self.page.0).await {
+            Ok(()) => {
+                self.dirty = false;
+                self.flushed_since_save = true;
+                true
+            }
+            Err(e) => {
+                error!("dfu write at 0x{:x} failed: {}", offset, Debug2Format(&e));
+                false
+            }
+        }
+    }
+
+    /* brings the page starting at offset to RAM, a page that cannot be written stays */
+    async fn load(&mut self, offset: usize) -> bool {
+        if self.page_offset == Some(offset) {
+            return true;
+        }
+        if !self.flush().await {
+            return false;
+        }
+        self.page_offset = None;
+        if let Err(e) = self.dfu.read(offset as u32, &mut self.page.0).await {
+            error!("dfu read at 0x{:x} failed: {}", offset, Debug2Format(&e));
+            return false;
+        }
+        self.page_offset = Some(offset);
+        true
+    }
+
+    /* a new image of binary_size bytes is coming, what was written before is discarded */
+    pub async fn begin(&mut self, binary_size: usize) -> bool {
+        self.page_offset = None;
+        self.dirty = false;
+        let end = binary_size.div_ceil(PAGE_SIZE) * PAGE_SIZE;
+        if end > self.session_offset() {
+            warn!(
+                "image of {} bytes does not fit the dfu partition of {}",
+                binary_size,
+                self.session_offset()
+            );
+            return false;
+        }
+        self.clear_session().await;
+        match self.dfu.erase(0, end as u32).await {
+            Ok(()) => true,
+            Err(e) => {
+                error!("dfu erase failed: {}", Debug2Format(&e));
+                false
+            }
+        }
+    }
+
+    pub async fn write(&mut self, mut offset: usize, mut data: &[u8]) -> bool {
+        if offset + data.len() > self.session_offset() {
+            return false;
+        }
+        /* a block may span two pages */
+        while !data.is_empty() {
+            let page_offset = offset - offset % PAGE_SIZE;
+            if !self.load(page_offset).await {
+                return false;
+            }
+            let start = offset - page_offset;
+            let len = data.len().min(PAGE_SIZE - start);
+            self.page.0[start..start + len].copy_from_slice(&data[..len]);
+            self.dirty = true;
+            offset += len;
+            data = &data[len..];
+        }
+        true
+    }
+
+    /* writes the page in RAM first, the hash covers what really is in the flash */
+    pub async fn read(&mut self, offset: usize, data: &mut [u8]) -> bool {
+        self.flush().await && self.dfu.read(offset as u32, data).await.is_ok()
+    }
+
+    pub async fn save_session(&mut self, record: &OtaSessionRecord) {
+        if !self.flushed_since_save {
+            return;
+        }
+        let mut record = record.clone();
+        if let Some(page_offset) = self.page_offset.filter(|_| self.dirty) {
+            let block_size = record.params.block_size as usize;
+            for i in page_offset / block_size..(page_offset + PAGE_SIZE).div_ceil(block_size) {
+                record.set_received(i as u16, false);
+            }
+        }
+        let mut slot = AlignedBuffer([0xff; SESSION_SLOT_SIZE]);
+        let length = match postcard::to_slice(&record, &mut slot.0[2..SESSION_SLOT_SIZE - 4]) {
+            Ok(used) => used.len() as u16,
+            Err(e) => {
+                error!("failed to serialize the ota session: {}", Debug2Format(&e));
+                return;
+            }
+        };
+        slot.0[..2].copy_from_slice(&length.to_le_bytes());
+        slot.0[SESSION_SLOT_SIZE - 4..].copy_from_slice(&SESSION_MAGIC.to_le_bytes());
+
+        /* the first slot still erased, or a fresh page */
+        let base = self.session_offset();
+        let mut free = None;
+        let mut read = AlignedBuffer([0; SESSION_SLOT_SIZE]);
+        for offset in (base..base + PAGE_SIZE).step_by(SESSION_SLOT_SIZE) {
+            if self.dfu.read(offset as u32, &mut read.0).await.is_err() {
+                break;
+            }
+            if read.0.iter().all(|b| *b == 0xff) {
+                free = Some(offset);
+                break;
+            }
+        }
+        let offset = match free {
+            Some(o) => o,
+            None => {
+                if let Err(e) = self.dfu.erase(base as u32, (base + PAGE_SIZE) as u32).await {
+                    error!("dfu erase failed: {}", Debug2Format(&e));
+                    return;
+                }
+                base
+            }
+        };
+        match self.dfu.write(offset as u32, &slot.0).await {
+            Ok(()) => self.flushed_since_save = false,
+            Err(e) => error!("saving the ota session failed: {}", Debug2Format(&e)),
+        }
+    }
+
+    pub async fn load_session(&mut self) -> Option<OtaSessionRecord> {
+        let base = self.session_offset();
+        let mut record = None;
+        let mut slot = AlignedBuffer([0; SESSION_SLOT_SIZE]);
+        for offset in (base..base + PAGE_SIZE).step_by(SESSION_SLOT_SIZE) {
+            if self.dfu.read(offset as u32, &mut slot.0).await.is_err() {
+                break;
+            }
+            let magic = &slot.0[SESSION_SLOT_SIZE - 4..];
+            if magic != SESSION_MAGIC.to_le_bytes() {
+                /* the slots are used in order, an interrupted write leaves no magic */
+                continue;
+            }
+            let length = u16::from_le_bytes([slot.0[0], slot.0[1]]) as usize;
+            match slot.0[2..SESSION_SLOT_SIZE - 4]
+                .get(..length)
+                .and_then(|b| postcard::from_bytes::<OtaSessionRecord>(b).ok())
+            {
+                Some(r) => record = Some(r),
+                None => warn!("ota session at 0x{:x} does not decode", offset),
+            }
+        }
+        record
+    }
+
+    pub async fn clear_session(&mut self) {
+        let base = self.session_offset();
+        self.flushed_since_save = false;
+        if let Err(e) = self.dfu.erase(base as u32, (base + PAGE_SIZE) as u32).await {
+            error!("dfu erase failed: {}", Debug2Format(&e));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_storage_async::nor_flash::{ErrorType, NorFlashErrorKind, ReadNorFlash};
+    use gateway_core::OtaInitPacket;
+    use pollster::block_on;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::vec::Vec;
+
+    const PAGE_SIZE: usize = 2048;
+    /* seven pages for the image, the last one for the session */
+    const PAGES: usize = 8;
+    const SESSION_PAGE: usize = PAGES - 1;
+
+    /* the DFU partition, shared by the memory and the bootloader like on the chip */
+    #[derive(Clone)]
+    struct RamFlash {
+        data: Rc<RefCell<Vec<u8>>>,
+        erases: Rc<RefCell<[u32; PAGES]>>,
+    }
+
+    impl RamFlash {
+        fn new() -> Self {
+            RamFlash {
+                data: Rc::new(RefCell::new(std::vec![0xff; PAGE_SIZE * PAGES])),
+                erases: Rc::new(RefCell::new([0; PAGES])),
+            }
+        }
+    }
+
+    impl ErrorType for RamFlash {
+        type Error = NorFlashErrorKind;
+    }
+
+    impl ReadNorFlash for RamFlash {
+        const READ_SIZE: usize = 1;
+
+        async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+            let offset = offset as usize;
+            let data = self.data.borrow();
+            let source = data
+                .get(offset..offset + bytes.len())
+                .ok_or(NorFlashErrorKind::OutOfBounds)?;
+            bytes.copy_from_slice(source);
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            PAGE_SIZE * PAGES
+        }
+    }
+
+    impl NorFlash for RamFlash {
+        const WRITE_SIZE: usize = 8;
+        const ERASE_SIZE: usize = PAGE_SIZE;
+
+        async fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+            let (from, to) = (from as usize, to as usize);
+            if from % PAGE_SIZE != 0 || to % PAGE_SIZE != 0 {
+                return Err(NorFlashErrorKind::NotAligned);
+            }
+            self.data.borrow_mut()[from..to].fill(0xff);
+            for page in from / PAGE_SIZE..to / PAGE_SIZE {
+                self.erases.borrow_mut()[page] += 1;
+            }
+            Ok(())
+        }
+
+        /* like the chip, only what was erased can be written */
+        async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+            let offset = offset as usize;
+            if offset % Self::WRITE_SIZE != 0 || bytes.len() % Self::WRITE_SIZE != 0 {
+                return Err(NorFlashErrorKind::NotAligned);
+            }
+            let mut data = self.data.borrow_mut();
+            let target = &mut data[offset..offset + bytes.len()];
+            assert!(
+                target.iter().all(|b| *b == 0xff),
+                "write over 0x{:x}",
+                offset
+            );
+            target.copy_from_slice(bytes);
+            Ok(())
+        }
+    }
+
+    /* FirmwareUpdater::write_firmware on the same partition */
+    struct RamWriter {
+        dfu: RamFlash,
+    }
+
+    impl FirmwareWriter for RamWriter {
+        type Error = NorFlashErrorKind;
+
+        async fn write_firmware(&mut self, offset: usize, data: &[u8]) -> Result<(), Self::Error> {
+            self.dfu
+                .erase(offset as u32, (offset + data.len()) as u32)
+                .await?;
+            self.dfu.write(offset as u32, data).await
+        }
+    }
+
+    type Memory = DfuPages<RamWriter, RamFlash, PAGE_SIZE>;
+
+    /* what the node has after a reset, the page in RAM is gone */
+    fn memory(flash: &RamFlash) -> Memory {
+        let writer = RamWriter { dfu: flash.clone() };
+        DfuPages::new(writer, flash.clone())
+    }
+
+    fn image(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + i / 251) as u8).collect()
+    }
+
+    fn record(image: &[u8], block_size: usize) -> OtaSessionRecord {
+        OtaSessionRecord::new(OtaInitPacket {
+            binary_size: image.len() as u32,
+            binary_sha256: [0; 32],
+            block_size: block_size as u16,
+            block_count: image.len().div_ceil(block_size) as u16,
+            signature: None,
+        })
+    }
+
+    fn write_block(memory: &mut Memory, image: &[u8], block_size: usize, index: usize) {
+        let start = index * block_size;
+        let block = &image[start..(start + block_size).min(image.len())];
+        assert!(block_on(memory.write(start, block)));
+    }
+
+    fn read_back(memory: &mut Memory, len: usize) -> Vec<u8> {
+        let mut data = std::vec![0; len];
+        for (i, chunk) in data.chunks_mut(256).enumerate() {
+            assert!(block_on(memory.read(i * 256, chunk)));
+        }
+        data
+    }
+
+    #[test]
+    fn blocks_out_of_order() {
+        let flash = RamFlash::new();
+        let mut memory = memory(&flash);
+        /* a previous image, none of it may show through */
+        flash.data.borrow_mut()[..PAGE_SIZE * 3].fill(0x5a);
+        let image = image(5000);
+        let block_size = 96;
+        let block_count = image.len().div_ceil(block_size);
+        assert!(block_on(memory.begin(image.len())));
+        /* every other block, then the rest backwards, some of them twice */
+        let order = (0..block_count)
+            .step_by(2)
+            .chain((0..block_count).rev().filter(|i| i % 2 == 1))
+            .chain([3, 40, 21]);
+        for index in order {
+            write_block(&mut memory, &image, block_size, index);
+        }
+        assert_eq!(read_back(&mut memory, image.len()), image);
+        /* the rest of the last page is erased */
+        let data = flash.data.borrow();
+        assert!(data[image.len()..PAGE_SIZE * 3].iter().all(|b| *b == 0xff));
+    }
+
+    #[test]
+    fn blocks_across_pages() {
+        let flash = RamFlash::new();
+        let mut memory = memory(&flash);
+        let image = image(3 * PAGE_SIZE);
+        let block_size = 96;
+        assert!(block_on(memory.begin(image.len())));
+        /* 2016..2112 and 4032..4128 each span two pages */
+        for index in [21, 42] {
+            write_block(&mut memory, &image, block_size, index);
+        }
+        let data = read_back(&mut memory, image.len());
+        for index in [21, 42] {
+            let range = index * block_size..(index + 1) * block_size;
+            assert_eq!(data[range.clone()], image[range]);
+        }
+        assert!(data[..21 * block_size].iter().all(|b| *b == 0xff));
+    }
+
+    #[test]
+    fn final_partial_page() {
+        let flash = RamFlash::new();
+        let mut memory = memory(&flash);
+        let image = image(2 * PAGE_SIZE + 100);
+        let block_size = 64;
+        let block_count = image.len().div_ceil(block_size);
+        assert!(block_on(memory.begin(image.len())));
+        /* the blocks of the last page, the last of them short */
+        for index in 2 * PAGE_SIZE / block_size..block_count {
+            write_block(&mut memory, &image, block_size, index);
+        }
+        /* no other page was loaded, so it is only in RAM */
+        assert!(flash.data.borrow()[2 * PAGE_SIZE..]
+            .iter()
+            .all(|b| *b == 0xff));
+        let mut tail = [0u8; 100];
+        assert!(block_on(memory.read(2 * PAGE_SIZE, &mut tail)));
+        assert_eq!(tail[..], image[2 * PAGE_SIZE..]);
+        assert_eq!(flash.data.borrow()[2 * PAGE_SIZE..image.len()], tail);
+    }
+
+    #[test]
+    fn session_slots_rotate() {
+        let flash = RamFlash::new();
+        let mut memory = memory(&flash);
+        /* a block is a page, every block written flushes the one before */
+        let image = image(SESSION_PAGE * PAGE_SIZE);
+        let block_size = PAGE_SIZE;
+        let mut record = record(&image, block_size);
+        assert!(block_on(memory.begin(image.len())));
+        let erased = flash.erases.borrow()[SESSION_PAGE];
+        let slots = PAGE_SIZE / SESSION_SLOT_SIZE;
+
+        write_block(&mut memory, &image, block_size, 0);
+        record.set_received(0, true);
+        /* nothing reached the flash yet, there is nothing to resume */
+        block_on(memory.save_session(&record));
+        assert!(block_on(memory.load_session()).is_none());
+
+        for index in 1..=slots + 1 {
+            write_block(&mut memory, &image, block_size, index);
+            record.set_received(index as u16, true);
+            block_on(memory.save_session(&record));
+            /* the block still in RAM is left out */
+            let loaded = block_on(memory.load_session()).unwrap();
+            for i in 0..=index as u16 {
+                assert_eq!(loaded.is_received(i), i < index as u16);
+            }
+            /* erased once every slot was used */
+            let erases = flash.erases.borrow()[SESSION_PAGE] - erased;
+            assert_eq!(erases, (index > slots) as u32);
+        }
+        /* saved again only once another page reached the flash */
+        let used = |flash: &RamFlash| {
+            let data = flash.data.borrow();
+            data[SESSION_PAGE * PAGE_SIZE..]
+                .chunks(SESSION_SLOT_SIZE)
+                .filter(|s| s.iter().any(|b| *b != 0xff))
+                .count()
+        };
+        let before = used(&flash);
+        block_on(memory.save_session(&record));
+        assert_eq!(used(&flash), before);
+    }
+
+    #[test]
+    fn load_session_after_reset() {
+        let flash = RamFlash::new();
+        let mut memory = memory(&flash);
+        let image = image(5000);
+        let block_size = 96;
+        let mut record = record(&image, block_size);
+        assert!(block_on(memory.begin(image.len())));
+        for index in 0..30 {
+            write_block(&mut memory, &image, block_size, index);
+            record.set_received(index as u16, true);
+            block_on(memory.save_session(&record));
+        }
+        drop(memory);
+
+        let mut memory = self::memory(&flash);
+        let loaded = block_on(memory.load_session()).unwrap();
+        assert!(loaded.is_same_image(&record.params));
+        /* the blocks up to the second page made it, 21 spans both and was still in RAM */
+        let received: Vec<u16> = (0..record.params.block_count)
+            .filter(|i| loaded.is_received(*i))
+            .collect();
+        assert_eq!(received, (0..21).collect::<Vec<u16>>());
+        let data = read_back(&mut memory, 21 * block_size);
+        assert_eq!(data, image[..21 * block_size]);
+
+        /* a finished session is not resumed */
+        block_on(memory.clear_session());
+        assert!(block_on(memory.load_session()).is_none());
+    }
+
+    #[test]
+    fn image_must_fit_before_the_session() {
+        let flash = RamFlash::new();
+        let mut memory = memory(&flash);
+        assert!(!block_on(memory.begin(SESSION_PAGE * PAGE_SIZE + 1)));
+        assert!(block_on(memory.begin(SESSION_PAGE * PAGE_SIZE)));
+        assert!(!block_on(
+            memory.write(SESSION_PAGE * PAGE_SIZE - 8, &[0; 16])
+        ));
+    }
+}
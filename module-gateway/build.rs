@@ -7,6 +7,45 @@
 //! Cargo re-run the build script whenever `memory.x` is changed,
 //! updating `memory.x` ensures a rebuild of the application with the
 //! new memory settings.
+//!
+//! It also embeds the build metadata reported by `module_runtime::firmware_info!`.
+
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+fn build_info() {
+    let hash = match git(&["rev-parse", "--short=9", "HEAD"]) {
+        Some(hash) => {
+            let dirty = git(&["status", "--porcelain"]).is_some_and(|s| !s.is_empty());
+            if dirty {
+                hash + "+"
+            } else {
+                hash
+            }
+        }
+        None => "unknown".to_string(),
+    };
+    /* SOURCE_DATE_EPOCH keeps reproducible builds reproducible */
+    let build_time = std::env::var("SOURCE_DATE_EPOCH").unwrap_or_else(|_| {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        now.as_secs().to_string()
+    });
+    let mut features: Vec<String> = std::env::vars()
+        .filter_map(|(k, _)| k.strip_prefix("CARGO_FEATURE_").map(|f| f.to_lowercase()))
+        .collect();
+    features.sort();
+    println!("cargo:rustc-env=FIRMWARE_GIT_HASH={}", hash);
+    println!("cargo:rustc-env=FIRMWARE_BUILD_TIME={}", build_time);
+    println!("cargo:rustc-env=FIRMWARE_FEATURES={}", features.join(","));
+}
 
 fn main() {
     // By default, Cargo will re-run a build script whenever
@@ -18,4 +57,6 @@ fn main() {
     println!("cargo:rustc-link-arg-bins=--nmagic");
     println!("cargo:rustc-link-arg-bins=-Tlink.x");
     println!("cargo:rustc-link-arg-bins=-Tdefmt.x");
-}
\ No newline at end of file
+
+    build_info();
+}
@@ -0,0 +1,44 @@
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicU32, Ordering};
+use module_runtime::embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use module_runtime::embassy_sync::blocking_mutex::Mutex;
+use module_runtime::embassy_sync::channel::Channel;
+use module_runtime::gateway_host_schema::{EventFilter, GatewayPacket};
+
+/*
+Events are queued separately from the responses, so a host that does not keep up
+only loses events and never blocks the tasks producing them. The number of lost
+events is reported with EventsDropped before the next event.
+*/
+
+const EVENT_QUEUE_LENGTH: usize = 8;
+
+static EVENTS: Channel<ThreadModeRawMutex, GatewayPacket, EVENT_QUEUE_LENGTH> = Channel::new();
+static DROPPED: AtomicU32 = AtomicU32::new(0);
+/* None until the host subscribes, which means every event */
+static FILTER: Mutex<ThreadModeRawMutex, RefCell<Option<EventFilter>>> =
+    Mutex::new(RefCell::new(None));
+
+pub fn subscribe(filter: EventFilter) {
+    FILTER.lock(|f| *f.borrow_mut() = Some(filter));
+}
+
+/* never waits, the event is dropped when the queue is full */
+pub fn publish(event: GatewayPacket) {
+    let accepted = FILTER.lock(|f| f.borrow().as_ref().map_or(true, |f| f.accepts(&event)));
+    if !accepted {
+        return;
+    }
+    if EVENTS.try_send(event).is_err() {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/* for the host loop */
+pub async fn next() -> GatewayPacket {
+    let count = DROPPED.swap(0, Ordering::Relaxed);
+    if count > 0 {
+        return GatewayPacket::EventsDropped { count };
+    }
+    EVENTS.receive().await
+}
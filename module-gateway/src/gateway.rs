@@ -1,140 +1,30 @@
-use defmt::*;
-use gateway_host_schema::{self, HostPacket};
-use module_runtime::{gateway_host_schema::GatewayPacket, heapless::Vec, lora_phy::mod_params::RadioError, *};
+#[cfg(feature = "host_log")]
+use module_runtime::configure_host_log;
+use module_runtime::gateway_core::{self, GatewayHost};
+use module_runtime::gateway_host_schema::{EventFilter, LogConfig};
+use module_runtime::lora_phy::mod_params::RadioError;
 
-#[derive(Debug, defmt::Format, PartialEq)]
-pub enum Error {
-    Ota(OtaError),
-    LoRa(RadioError)
-}
+/* the host protocol itself is in gateway-core, shared with the emulator */
+pub use gateway_core::{error_packet, Gateway, KnownNodes};
 
-pub struct Gateway {
-    ota: Option<OtaProducer>,
-}
+pub type Error = gateway_core::Error<RadioError>;
 
-impl Gateway {
-    pub fn new() -> Gateway {
-        Gateway { ota: None }
-    }
+/* the subscription and the log settings of the host, both global to the firmware */
+pub struct Host;
 
-    async fn init_download(
-        &mut self,
-        lora: &mut ModuleLoRa,
-        init: gateway_host_schema::OtaInitRequest,
-    ) -> Result<GatewayPacket, Error> {
-        let mut ota = OtaProducer::new(
-            OtaInitPacket {
-                binary_size: init.binary_size,
-                block_size: init.block_size,
-                block_count: init.block_count,
-                binary_sha256: init.binary_sha256,
-            },
-            init.destination_address,
-        );
-        let ret = ota.init_download(lora).await.map_err(Error::Ota)?;
-        self.ota = Some(ota);
-        Ok(ret)
+impl GatewayHost for Host {
+    fn subscribe(&mut self, filter: EventFilter) {
+        crate::events::subscribe(filter);
     }
 
-    async fn continue_download(
-        &mut self,
-        lora: &mut ModuleLoRa,
-        data: gateway_host_schema::OtaData,
-    ) -> Result<(), Error> {
-        match self.ota.as_mut() {
-            Some(ota) => {
-                ota.continue_download(
-                    lora,
-                    OtaDataPacket {
-                        index: data.index,
-                        data: data.data,
-                    },
-                )
-                .await
-                .map_err(Error::Ota)?;
-            }
-            None => {
-                return Err(Error::Ota(OtaError::NotStarted));
-            }
-        }
-        Ok(())
-    }
-    
-    pub async fn process_host_message(
-        &mut self,
-        lora: &mut ModuleLoRa,
-        packet: HostPacket,
-    ) -> Result<Option<GatewayPacket>, Error> {
-        let ret = match packet {
-            HostPacket::PingRequest => Some(GatewayPacket::PingResponse),
-            HostPacket::OtaInit(init) => {
-                info!("init download");
-                match self.ota.as_mut() {
-                    Some(ota) => {
-                        if ota.is_done() {
-                            Some(self.init_download(lora, init).await?)
-                        } else {
-                            return Err(Error::Ota(OtaError::AlreadyStarted));
-                        }
-                    }
-                    None => Some(self.init_download(lora, init).await?),
-                }
-            }
-            HostPacket::OtaData(data) => {
-                //info!("continue download");
-                self.continue_download(lora, data).await?;
-                None
-            }
-            HostPacket::OtaDoneRequest => {
-                if let Some(ota) = self.ota.as_mut().take() {
-                    Some(ota.done_download(lora).await.map_err(Error::Ota)?)
-                } else {
-                    Some(GatewayPacket::OtaDoneAck) //FIXME this should return invalid command or something
-                }
-            }
-            HostPacket::OtaAbortRequest => {
-                if let Some(ota) = self.ota.as_mut().take() {
-                    Some(ota.abort_download(lora).await.map_err(Error::Ota)?)
-                } else {
-                    Some(GatewayPacket::OtaAbortAck) //FIXME this should return invalid command or something
-                }
-            }
-            HostPacket::OtaGetStatus => {
-                let packet = GatewayPacket::OtaStatus({
-                    if let Some(ota) = self.ota.as_ref() {
-                        ota.get_status()
-                    } else {
-                        gateway_host_schema::OtaStatus {
-                            in_progress: false,
-                            not_acked: Vec::new(),
-                            last_acked: 0,
-                        }
-                    }
-                });
-                Some(packet)
-            },
-            HostPacket::SoilSensor(req) => {
-                let mut p = LoRaPacket::new(req.destination_address, LoRaPacketType::SoilSensor);
-                p.payload.push(0).unwrap();
-                lora.transmit(&mut p).await.map_err(Error::LoRa)?;
-                None
-            }
-        };
-        Ok(ret)
+    #[cfg(feature = "host_log")]
+    fn configure_log(&mut self, config: &LogConfig) -> bool {
+        configure_host_log(config);
+        true
     }
 
-    pub async fn process_peer_message(
-        &mut self,
-        lora: &mut ModuleLoRa,
-        packet: LoRaPacket,
-    ) -> Result<Option<GatewayPacket>, Error> {
-        match self.ota.as_mut() {
-            Some(ota) => Ok(Some(
-                ota.process_response_raw(lora, packet)
-                    .await
-                    .map_err(Error::Ota)?,
-            )),
-            None => Ok(None),
-        }
+    #[cfg(not(feature = "host_log"))]
+    fn configure_log(&mut self, _config: &LogConfig) -> bool {
+        false
     }
 }
@@ -0,0 +1,104 @@
+use crate::events;
+use defmt::*;
+use embassy_futures::select::*;
+use gateway_host_schema::kiss::{self, KissCommand, KissDecoder, KissFrame};
+use gateway_host_schema::{GatewayPacket, ModulationOverride};
+use module_runtime::*;
+
+/*
+KISS mode, the gateway is a transparent LoRa modem for packet-radio software.
+    data frames on port 0 are transmitted as they are (transmit_raw adds only the CRC)
+    frames received with a valid CRC are sent to the host without it, as data frames on port 0
+    SetHardware configures the modulation: spreading factor, bandwidth in Hz (u32 LE), coding rate 5-8
+    Return goes back to the postcard protocol
+TxDelay, Persistence, SlotTime, TxTail and FullDuplex have no meaning for LoRa and are ignored.
+Only available on the UART, the idle 0x00 of the SPI would be taken for data.
+*/
+
+const KISS_PORT: u8 = 0;
+const KISS_FRAME_LENGTH: usize = PACKET_LENGTH;
+
+/* the rest of the gateway keeps running, events other than received frames are dropped */
+pub async fn run(host: &mut ModuleHost, radio: Radio) {
+    info!("entering KISS mode");
+    radio.set_sniffer(true);
+    let mut decoder = KissDecoder::<KISS_FRAME_LENGTH>::new();
+    let mut rx_buffer = [0u8; 64];
+    'kiss: loop {
+        match select(host.read_raw(&mut rx_buffer), events::next()).await {
+            Either::First(Ok(len)) => {
+                let mut pos = 0;
+                while pos < len {
+                    let (used, result) = decoder.push_slice(&rx_buffer[pos..len]);
+                    pos += used;
+                    match result {
+                        Some(Ok(frame)) => {
+                            if matches!(frame.command, KissCommand::Return) {
+                                /* the host may send its next frame right behind the Return */
+                                if let Err(e) = host.unread_raw(&rx_buffer[pos..len]) {
+                                    error!("host link: {}", e);
+                                }
+                                break 'kiss;
+                            }
+                            process_frame(radio, frame).await;
+                        }
+                        Some(Err(e)) => warn!("kiss: dropped frame: {}", e),
+                        None => {}
+                    }
+                }
+            }
+            Either::First(Err(e)) => {
+                error!("host link: {}", e);
+                decoder.reset();
+            }
+            Either::Second(GatewayPacket::SniffedFrame(f)) => {
+                if !f.crc_ok {
+                    continue;
+                }
+                let data = &f.data[..f.data.len() - CHECKSUM_LENGTH];
+                let mut tx_buffer = [0u8; kiss::max_encoded_len(KISS_FRAME_LENGTH)];
+                match kiss::encode(KISS_PORT, KissCommand::DataFrame, data, &mut tx_buffer) {
+                    Ok(len) => {
+                        if let Err(e) = host.write_raw(&tx_buffer[..len]).await {
+                            error!("host link: {}", e);
+                        }
+                    }
+                    Err(e) => error!("kiss: failed to encode frame: {}", e),
+                }
+            }
+            Either::Second(_) => {}
+        }
+    }
+    /* sniffing was most likely not enabled before, the host turns it on again if it was */
+    radio.set_sniffer(false);
+    info!("leaving KISS mode");
+}
+
+async fn process_frame(radio: Radio, frame: KissFrame<'_>) {
+    if frame.port != KISS_PORT {
+        warn!("kiss: port {} does not exist", frame.port);
+        return;
+    }
+    match frame.command {
+        KissCommand::DataFrame => {
+            if let Err(e) = radio.transmit_raw(frame.data).await {
+                error!("kiss: transmit failed: {}", e);
+            }
+            status_led(LedCommand::FlashShort).await;
+        }
+        KissCommand::SetHardware => match frame.data {
+            [spreading_factor, b0, b1, b2, b3, coding_rate] => {
+                let modulation = ModulationOverride {
+                    spreading_factor: *spreading_factor,
+                    bandwidth_hz: u32::from_le_bytes([*b0, *b1, *b2, *b3]),
+                    coding_rate: *coding_rate,
+                };
+                if let Err(e) = radio.set_modulation(modulation).await {
+                    error!("kiss: invalid modulation: {}", e);
+                }
+            }
+            _ => warn!("kiss: SetHardware expects 6 bytes, got {}", frame.data.len()),
+        },
+        c => info!("kiss: ignoring {}", c),
+    }
+}
@@ -4,7 +4,13 @@
 #![feature(type_alias_impl_trait)]
 #![feature(impl_trait_in_assoc_type)]
 
+mod events;
 mod gateway;
+#[cfg(not(feature = "host_spi"))]
+mod kiss;
+
+#[cfg(all(feature = "kiss", feature = "host_spi"))]
+compile_error!("KISS mode needs the UART host link");
 
 use defmt::*;
 use embassy_executor::Spawner;
@@ -12,59 +18,102 @@ use embassy_futures::select::*;
 use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
 use embassy_sync::channel::Channel;
 use gateway::*;
-use gateway_host_schema::{GatewayPacket, HostPacket};
+use gateway_host_schema::{
+    ErrorCode, FirmwareInfo, GatewayMessage, GatewayPacket, HostMessage, HostPacket,
+};
 use module_runtime::*;
 
-static HOST2GATEWAY: Channel<ThreadModeRawMutex, HostPacket, 2> = Channel::new();
-static GATEWAY2HOST: Channel<ThreadModeRawMutex, GatewayPacket, 2> = Channel::new();
+/* the host loop never waits for room in it, a request that does not fit is answered
+with ErrorCode::Busy, so it keeps taking the responses off GATEWAY2HOST however many
+requests a host sends without waiting for their responses */
+static HOST2GATEWAY: Channel<ThreadModeRawMutex, HostMessage, 2> = Channel::new();
+/* responses only, events go through the events module */
+static GATEWAY2HOST: Channel<ThreadModeRawMutex, GatewayMessage, 2> = Channel::new();
+/* nodes reported with NodeJoined, any beyond this many are never reported */
+const MAX_KNOWN_NODES: usize = 32;
 
 #[embassy_executor::task]
-pub async fn gateway_task(mut lora: ModuleLoRa) {
-    let mut gw = Gateway::new();
+pub async fn gateway_task(radio: Radio, info: FirmwareInfo) {
+    let mut gw = Gateway::new(info);
+    let mut lora = radio.client(|p| matches!(p.packet_type, LoRaPacketType::OTA));
     loop {
         match select(HOST2GATEWAY.receive(), lora.receive_continuous()).await {
-            Either::First(p) => match gw.process_host_message(&mut lora, p).await {
-                Ok(resp) => {
-                    if let Some(r) = resp {
-                        GATEWAY2HOST.send(r).await;
+            Either::First(m) => {
+                let result = gw
+                    .process_host_message(&mut lora, &mut Host, m.packet)
+                    .await;
+                let packet = match result {
+                    Ok(resp) => resp,
+                    Err(e) => {
+                        error!("failed to process host message: {}", e);
+                        e.to_packet()
                     }
-                }
-                Err(e) => {
-                    error!("failed to process host message: {}", e);
-                }
-            },
-            Either::Second(lora_result) => match lora_result {
-                Ok(p) => {
-                    match p.packet_type {
-                        LoRaPacketType::OTA => {
-                            match gw.process_peer_message(&mut lora, p).await {
-                                Ok(resp) => {
-                                    if let Some(r) = resp {
-                                        GATEWAY2HOST.send(r).await;
-                                    }
-                                }
-                                Err(e) => {
-                                    error!("failed to process peer message: {}", e);
-                                }
-                            }
-                        },
-                        LoRaPacketType::SoilSensor => {
-                            let mut data = [0u16; 4];
-                            for i in 0..4 {
-                                data[i] = u16::from_le_bytes(p.payload[i*2..i*2+2].try_into().unwrap());
-                            }
-                            GATEWAY2HOST.send(GatewayPacket::SoilSensorMoisture(data)).await;
-                        }
-                        _ => {
-                            error!("unexpected packet type: {:?}", p.packet_type);
+                };
+                GATEWAY2HOST
+                    .send(GatewayMessage::Response {
+                        request_id: m.request_id,
+                        packet,
+                    })
+                    .await;
+            }
+            Either::Second(p) => {
+                match gw.process_peer_message(&mut lora, p).await {
+                    Ok(resp) => {
+                        if let Some(r) = resp {
+                            events::publish(r);
                         }
                     }
-                    status_led(LedCommand::FlashShort).await;
-                }
-                Err(e) => {
-                    error!("failed lora receive: {}", e);
+                    Err(e) => {
+                        error!("failed to process peer message: {}", e);
+                        events::publish(e.to_packet());
+                    }
                 }
-            },
+                status_led(LedCommand::FlashShort).await;
+            }
+        }
+    }
+}
+
+/* forwards application packets from the nodes, runs next to the OTA in gateway_task */
+#[embassy_executor::task]
+pub async fn uplink_task(radio: Radio) {
+    let mut lora = radio.client(|p| !matches!(p.packet_type, LoRaPacketType::OTA));
+    let mut known_nodes = KnownNodes::<MAX_KNOWN_NODES>::new();
+    loop {
+        let p = lora.receive_continuous().await;
+        known_nodes.uplink(p, events::publish);
+        status_led(LedCommand::FlashShort).await;
+    }
+}
+
+/* forwards every frame heard while the sniffer mode is enabled by the host */
+#[embassy_executor::task]
+pub async fn sniffer_task(radio: Radio) {
+    loop {
+        let frame = radio.receive_sniffed().await;
+        events::publish(GatewayPacket::SniffedFrame(frame));
+    }
+}
+
+/* forwards the log output of the gateway, the events filter decides if the host gets it */
+#[cfg(feature = "host_log")]
+#[embassy_executor::task]
+pub async fn log_task() {
+    loop {
+        events::publish(next_host_log().await);
+    }
+}
+
+async fn send_to_host(host: &mut ModuleHost, message: &GatewayMessage) {
+    let mut tx_buffer = [0u8; 256];
+    match postcard::to_slice(message, &mut tx_buffer) {
+        Ok(b) => {
+            if let Err(_e) = host.write(&b).await {
+                error!("failed to transmit packet to host");
+            }
+        }
+        Err(e) => {
+            error!("failed to serialize packet: {}", e);
         }
     }
 }
@@ -73,42 +122,78 @@ pub async fn gateway_task(mut lora: ModuleLoRa) {
 async fn main(spawner: Spawner) {
     let module = init(ModuleConfig::new(ModuleVersion::NucleoWL55JC), &spawner).await;
 
-    info!("hello from gateway {}", module.lora.address);
-    spawner.spawn(gateway_task(module.lora)).unwrap();
+    let firmware = firmware_info!(module.version);
+    info!(
+        "hello from gateway {}, {} {} {}",
+        module.lora.address,
+        firmware.name.as_str(),
+        firmware.version.as_str(),
+        firmware.git_hash.as_str()
+    );
+    let radio = Radio::start(&spawner, module.lora);
+    spawner.spawn(gateway_task(radio, firmware)).unwrap();
+    spawner.spawn(uplink_task(radio)).unwrap();
+    spawner.spawn(sniffer_task(radio)).unwrap();
+    #[cfg(feature = "host_log")]
+    spawner.spawn(log_task()).unwrap();
 
     let mut host = module.host;
-    let mut uart_buffer = [0u8; 128];
+    #[cfg(feature = "kiss")]
+    kiss::run(&mut host, radio).await;
+    let mut uart_buffer = [0u8; 256];
     loop {
-        match select(host.read(&mut uart_buffer), GATEWAY2HOST.receive()).await {
-            Either::First(uart_result) => {
-                match uart_result {
-                    Ok(size) => match postcard::from_bytes::<HostPacket>(&uart_buffer[..size]) {
-                        Ok(p) => {
-                            HOST2GATEWAY.send(p).await;
+        match select3(host.read(&mut uart_buffer), GATEWAY2HOST.receive(), events::next()).await {
+            Either3::First(uart_result) => {
+                /* without a request ID to answer, failures are reported as events */
+                let failure = match uart_result {
+                    Ok(size) => match postcard::from_bytes::<HostMessage>(&uart_buffer[..size]) {
+                        #[cfg(not(feature = "host_spi"))]
+                        Ok(HostMessage {
+                            request_id,
+                            packet: HostPacket::KissMode,
+                        }) => {
+                            /* the ack is the last frame before the link speaks KISS */
+                            let ack = GatewayMessage::Response {
+                                request_id,
+                                packet: GatewayPacket::KissModeAck,
+                            };
+                            send_to_host(&mut host, &ack).await;
+                            kiss::run(&mut host, radio).await;
+                            None
+                        }
+                        Ok(m) => {
+                            let request_id = m.request_id;
+                            if HOST2GATEWAY.try_send(m).is_err() {
+                                warn!("request queue full, request {} refused", request_id);
+                                let busy = GatewayMessage::Response {
+                                    request_id,
+                                    packet: Error::Busy.to_packet(),
+                                };
+                                send_to_host(&mut host, &busy).await;
+                            }
+                            None
                         }
                         Err(e) => {
                             error!("failed to parse packet from host: {}", e);
+                            Some(error_packet(ErrorCode::HostDecode, &e))
                         }
                     },
-                    Err(e) => {
-                        error!("uart: {}", e);
-                    }
-                }
-                status_led(LedCommand::FlashShort).await;
-            }
-            Either::Second(p) => {
-                let mut tx_buffer = [0u8; 256];
-                match postcard::to_slice(&p, &mut tx_buffer) {
-                    Ok(b) => {
-                        if let Err(_e) = host.write(&b).await {
-                            error!("failed to transmit packet to host");
-                        }
+                    Err(e @ (HostError::Uart(_) | HostError::Overrun)) => {
+                        error!("host link: {}", e);
+                        Some(error_packet(ErrorCode::HostLink, &e))
                     }
                     Err(e) => {
-                        error!("failed to serialize packet: {}", e);
+                        error!("host link: {}", e);
+                        Some(error_packet(ErrorCode::HostFraming, &e))
                     }
+                };
+                if let Some(p) = failure {
+                    events::publish(p);
                 }
+                status_led(LedCommand::FlashShort).await;
             }
+            Either3::Second(p) => send_to_host(&mut host, &p).await,
+            Either3::Third(e) => send_to_host(&mut host, &GatewayMessage::Event(e)).await,
         }
     }
 }
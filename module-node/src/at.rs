@@ -0,0 +1,349 @@
+/*
+AT command interface, the node acts as a radio modem for an external MCU on the host link.
+Every command is one line, answered by an optional +NAME:... line and OK or ERROR:<reason>.
+
+    AT                                  OK
+    AT+ADDR? / AT+ADDR=<address>        +ADDR:<address>
+    AT+MOD? / AT+MOD=<sf>,<bw_hz>,<cr>  +MOD:<sf>,<bw_hz>,<cr>, cr is the denominator of 4/x
+    AT+SEND=<dest>,<type>,<hex>         transmits a LoRaPacket with the payload
+    AT+RSSI?                            +RSSI:<rssi>,<snr> of the last received packet
+    AT+STATS?                           +STATS:<tx>,<rx>,<crc>,<parse>,<address>,<timeouts>,<retries>,<recoveries>
+    AT+OTAINIT=<dest>,<size>,<block_size>,<sha256 hex>[,<signature hex>]
+                                        +OTARESUME:<first_missing>,<received indexes separated by :>
+                                        when the node has part of the image, those blocks are skipped
+    AT+OTADATA=<index>,<hex>            OTA of another node, the same steps the gateway takes
+    AT+OTADONE / AT+OTAABORT / AT+OTA?  +OTA:<in_progress>,<last_acked>,<not acked indexes separated by :>
+
+Received packets are reported unsolicited as +RECV:<source>,<type>,<rssi>,<snr>,<hex>,
+OTA packets go to the OTA of this node unless they answer the one started here.
+*/
+
+use core::fmt::Write;
+use defmt::*;
+use embassy_futures::select::*;
+use module_runtime::{
+    embedded_storage_async::nor_flash::NorFlash,
+    gateway_host_schema::{GatewayPacket, ModulationOverride},
+    heapless::{String, Vec},
+    lora_phy::mod_params::RadioError,
+    *,
+};
+
+const AT_LINE_LENGTH: usize = 300;
+type Response = String<512>;
+
+#[derive(Debug)]
+enum AtError {
+    Syntax,
+    UnknownCommand,
+    Radio(RadioError),
+    Ota(OtaError),
+}
+
+struct Modem {
+    radio: Radio,
+    ota: Option<OtaProducer>,
+    ota_destination: usize,
+}
+
+fn parse<T: core::str::FromStr>(value: Option<&str>) -> Result<T, AtError> {
+    value
+        .ok_or(AtError::Syntax)?
+        .trim()
+        .parse()
+        .map_err(|_| AtError::Syntax)
+}
+
+fn parse_hex<const N: usize>(value: Option<&str>) -> Result<Vec<u8, N>, AtError> {
+    let hex = value.ok_or(AtError::Syntax)?.trim().as_bytes();
+    if hex.len() % 2 != 0 {
+        return Err(AtError::Syntax);
+    }
+    let mut data = Vec::new();
+    for pair in hex.chunks(2) {
+        let s = core::str::from_utf8(pair).map_err(|_| AtError::Syntax)?;
+        let byte = u8::from_str_radix(s, 16).map_err(|_| AtError::Syntax)?;
+        data.push(byte).map_err(|_| AtError::Syntax)?;
+    }
+    Ok(data)
+}
+
+fn write_hex(response: &mut Response, data: &[u8]) {
+    for byte in data {
+        let _ = write!(response, "{:02X}", byte);
+    }
+}
+
+fn write_ota_status(response: &mut Response, packet: &GatewayPacket) {
+    match packet {
+        GatewayPacket::OtaStatus(s) => {
+            let _ = write!(response, "+OTA:{},{},", s.in_progress as u8, s.last_acked);
+            for (i, index) in s.not_acked.iter().enumerate() {
+                let _ = write!(response, "{}{}", if i > 0 { ":" } else { "" }, index);
+            }
+            let _ = write!(response, "\r\n");
+        }
+        GatewayPacket::OtaDoneAck => {
+            let _ = write!(response, "+OTADONE\r\n");
+        }
+        GatewayPacket::OtaAbortAck => {
+            let _ = write!(response, "+OTAABORT\r\n");
+        }
+        GatewayPacket::OtaResumed(r) => {
+            let _ = write!(response, "+OTARESUME:{},", r.first_missing_index);
+            for (i, index) in r.received_indexes.iter().enumerate() {
+                let _ = write!(response, "{}{}", if i > 0 { ":" } else { "" }, index);
+            }
+            let _ = write!(response, "\r\n");
+        }
+        _ => {}
+    }
+}
+
+impl Modem {
+    async fn execute(
+        &mut self,
+        ota_lora: &mut RadioClient,
+        line: &str,
+        response: &mut Response,
+    ) -> Result<(), AtError> {
+        let command = line.trim();
+        if command == "AT" {
+            return Ok(());
+        }
+        let command = command.strip_prefix("AT+").ok_or(AtError::UnknownCommand)?;
+        let (name, args) = match command.split_once('=') {
+            Some((n, a)) => (n, Some(a)),
+            None => (command, None),
+        };
+        let mut args = args.unwrap_or("").split(',');
+        match (name, args.clone().next().filter(|a| !a.is_empty())) {
+            ("ADDR?", None) => {
+                let _ = write!(response, "+ADDR:{}\r\n", self.radio.address());
+            }
+            ("ADDR", Some(_)) => {
+                self.radio.set_address(parse(args.next())?);
+            }
+            ("MOD?", None) => {
+                let m = self.radio.modulation();
+                let _ = write!(
+                    response,
+                    "+MOD:{},{},{}\r\n",
+                    m.spreading_factor, m.bandwidth_hz, m.coding_rate
+                );
+            }
+            ("MOD", Some(_)) => {
+                let modulation = ModulationOverride {
+                    spreading_factor: parse(args.next())?,
+                    bandwidth_hz: parse(args.next())?,
+                    coding_rate: parse(args.next())?,
+                };
+                self.radio
+                    .set_modulation(modulation)
+                    .await
+                    .map_err(AtError::Radio)?;
+            }
+            ("SEND", Some(_)) => {
+                let destination = parse(args.next())?;
+                let packet_type: u8 = parse(args.next())?;
+                let mut p = LoRaPacket::new_with_payload(
+                    destination,
+                    LoRaPacketType::from_u8(packet_type),
+                    parse_hex(args.next())?,
+                );
+                self.radio.transmit(&mut p).await.map_err(AtError::Radio)?;
+            }
+            ("RSSI?", None) => {
+                let s = self.radio.stats();
+                let _ = write!(response, "+RSSI:{},{}\r\n", s.last_rssi, s.last_snr);
+            }
+            ("STATS?", None) => {
+                let s = self.radio.stats();
+                let _ = write!(
+                    response,
+                    "+STATS:{},{},{},{},{},{},{},{}\r\n",
+                    s.tx,
+                    s.rx,
+                    s.crc_failures,
+                    s.parse_failures,
+                    s.address_mismatches,
+                    s.timeouts,
+                    s.retries,
+                    s.recoveries
+                );
+            }
+            ("OTAINIT", Some(_)) => {
+                let destination = parse(args.next())?;
+                let binary_size: u32 = parse(args.next())?;
+                let block_size: u16 = parse(args.next())?;
+                let sha256 = parse_hex::<32>(args.next())?;
+                let signature = match args.next() {
+                    Some(hex) => Some(parse_hex::<64>(Some(hex))?),
+                    None => None,
+                };
+                if block_size == 0 || block_size > 96 {
+                    return Err(AtError::Syntax);
+                }
+                let params = OtaInitPacket {
+                    binary_size,
+                    binary_sha256: sha256.as_slice().try_into().map_err(|_| AtError::Syntax)?,
+                    block_size,
+                    block_count: binary_size.div_ceil(block_size as u32) as u16,
+                    signature,
+                };
+                /* the same image again is resumed by the node */
+                if self
+                    .ota
+                    .as_ref()
+                    .is_some_and(|o| !o.is_done() && !o.is_same_download(&params, destination))
+                {
+                    return Err(AtError::Ota(OtaError::AlreadyStarted));
+                }
+                let mut ota = OtaProducer::new(params, destination);
+                let packet = ota.init_download(ota_lora).await.map_err(AtError::Ota)?;
+                write_ota_status(response, &packet);
+                self.ota = Some(ota);
+                self.ota_destination = destination;
+            }
+            ("OTADATA", Some(_)) => {
+                let ota = self.ota.as_mut().ok_or(AtError::Ota(OtaError::NotStarted))?;
+                let index = parse(args.next())?;
+                let data = parse_hex(args.next())?;
+                ota.continue_download(ota_lora, OtaDataPacket { index, data })
+                    .await
+                    .map_err(AtError::Ota)?;
+            }
+            ("OTADONE", None) => {
+                let ota = self.ota.as_mut().ok_or(AtError::Ota(OtaError::NotStarted))?;
+                let packet = ota.done_download(ota_lora).await.map_err(AtError::Ota)?;
+                write_ota_status(response, &packet);
+            }
+            ("OTAABORT", None) => {
+                let ota = self.ota.as_mut().ok_or(AtError::Ota(OtaError::NotStarted))?;
+                let packet = ota.abort_download(ota_lora).await.map_err(AtError::Ota)?;
+                write_ota_status(response, &packet);
+            }
+            ("OTA?", None) => {
+                let status = match self.ota.as_ref() {
+                    Some(ota) => ota.get_status(),
+                    None => gateway_host_schema::OtaStatus {
+                        in_progress: false,
+                        not_acked: Vec::new(),
+                        last_acked: 0,
+                    },
+                };
+                write_ota_status(response, &GatewayPacket::OtaStatus(status));
+            }
+            _ => return Err(AtError::UnknownCommand),
+        }
+        Ok(())
+    }
+
+    /* answers to the OTA started from here, the packet is given back when it is for this node's own OTA */
+    async fn process_ota_response(
+        &mut self,
+        ota_lora: &mut RadioClient,
+        packet: LoRaPacket,
+        response: &mut Response,
+    ) -> Option<LoRaPacket> {
+        let ota = match self.ota.as_mut() {
+            Some(ota) if !ota.is_done() && packet.source == self.ota_destination => ota,
+            _ => return Some(packet),
+        };
+        match ota.process_response_raw(ota_lora, packet).await {
+            Ok(p) => write_ota_status(response, &p),
+            Err(e) => {
+                let _ = write!(response, "+OTAERROR:{:?}\r\n", e);
+            }
+        }
+        None
+    }
+}
+
+async fn send(host: &mut ModuleHost, response: &Response) {
+    if response.is_empty() {
+        return;
+    }
+    if let Err(e) = host.write_raw(response.as_bytes()).await {
+        error!("at: failed to respond: {}", e);
+    }
+}
+
+/// Serves AT commands on the host link, also keeps the OTA of this node running
+/// and resets into its image once it was verified. The OTA of this node is refused
+/// while the firmware is on trial.
+pub async fn modem<DFU: NorFlash, STATE: NorFlash, const PAGE_SIZE: usize>(
+    mut host: ModuleHost,
+    radio: Radio,
+    ota_consumer: &mut OtaConsumer<DfuMemory<'_, DFU, STATE, PAGE_SIZE>>,
+    health: &mut HealthChecks,
+) -> ! {
+    let mut ota_lora = radio.client(|p| matches!(p.packet_type, LoRaPacketType::OTA));
+    let mut lora = radio.client(|p| !matches!(p.packet_type, LoRaPacketType::OTA));
+    let mut modem = Modem {
+        radio,
+        ota: None,
+        ota_destination: 0,
+    };
+    let mut line = [0u8; AT_LINE_LENGTH];
+    send(&mut host, &String::from("\r\nREADY\r\n")).await;
+    loop {
+        let mut response = Response::new();
+        match select3(
+            host.read_line(&mut line),
+            lora.receive_continuous(),
+            ota_lora.receive_continuous(),
+        )
+        .await
+        {
+            Either3::First(Ok(len)) => {
+                line[..len].make_ascii_uppercase();
+                let result = match core::str::from_utf8(&line[..len]) {
+                    Ok(command) => modem.execute(&mut ota_lora, command, &mut response).await,
+                    Err(_) => Err(AtError::Syntax),
+                };
+                let _ = match result {
+                    Ok(()) => write!(response, "OK\r\n"),
+                    Err(e) => write!(response, "ERROR:{:?}\r\n", e),
+                };
+            }
+            Either3::First(Err(e)) => {
+                warn!("at: {}", e);
+                let _ = write!(response, "ERROR:{:?}\r\n", e);
+            }
+            Either3::Second(p) => {
+                let _ = write!(
+                    response,
+                    "+RECV:{},{},{},{},",
+                    p.source,
+                    p.packet_type.as_u8(),
+                    p.rssi,
+                    p.snr
+                );
+                write_hex(&mut response, &p.payload);
+                let _ = write!(response, "\r\n");
+            }
+            Either3::Third(p) => {
+                if let Some(p) = modem
+                    .process_ota_response(&mut ota_lora, p, &mut response)
+                    .await
+                {
+                    /* the DFU partition holds the previous firmware until this one is confirmed */
+                    if health.is_on_trial() {
+                        warn!("ota refused, the firmware is still on trial");
+                    } else if let Err(e) = ota_consumer.process_message(&mut ota_lora, p).await {
+                        error!("ota error: {}", e)
+                    }
+                }
+            }
+        }
+        send(&mut host, &response).await;
+        /* tries mark_booted again when it failed before the modem started */
+        health.conclude(&mut ota_consumer.memory, &mut lora).await;
+
+        /* DoneAck is out, the bootloader swaps the image in after the reset */
+        if ota_consumer.is_verified() {
+            ota_consumer.memory.activate().await;
+        }
+    }
+}
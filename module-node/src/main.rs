@@ -5,19 +5,29 @@
 #![feature(impl_trait_in_assoc_type)]
 
 mod soil_sensor;
-mod ota_memory;
+#[cfg(feature = "at_modem")]
+mod at;
 
 use defmt::*;
 use embassy_executor::Spawner;
-use module_runtime::{embassy_time::Timer, *};
+use embassy_futures::select::*;
+use module_runtime::{embassy_time::{Duration, Timer}, *};
 use embassy_boot_stm32::{AlignedBuffer, FirmwareUpdater, FirmwareUpdaterConfig};
 use embassy_embedded_hal::adapter::BlockingAsync;
 use embassy_stm32::flash::{Flash, WRITE_SIZE};
 use embassy_sync::mutex::Mutex;
 use soil_sensor::{SoilSensor, SoilSensorResult};
-use ota_memory::OtaMemory;
 
-async fn soil_sensor_measure_and_transmit<'a>(soil_sensor: &mut SoilSensor<'a>, lora: &mut ModuleLoRa, destination_address: usize) {
+/* the DFU partition is written a flash page at a time */
+const FLASH_PAGE_SIZE: usize = 2048;
+/* a new firmware is confirmed once these pass within the timeout, see HealthChecks */
+#[cfg(not(feature = "at_modem"))]
+const HEALTH_CHECKS: &[&str] = &["radio", "gateway", "sensor"];
+#[cfg(feature = "at_modem")]
+const HEALTH_CHECKS: &[&str] = &["radio"];
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+async fn soil_sensor_measure_and_transmit<'a>(soil_sensor: &mut SoilSensor<'a>, lora: &mut RadioClient, destination_address: usize) {
     let samples = soil_sensor.sample_all_average().await;
     let mut resp = LoRaPacket::new(destination_address, LoRaPacketType::SoilSensor);
     for sample in samples {
@@ -42,13 +52,23 @@ async fn soil_sensor_measure_and_transmit<'a>(soil_sensor: &mut SoilSensor<'a>,
 async fn main(spawner: Spawner) {
     let mut module = init(ModuleConfig::new(ModuleVersion::Lumia), &spawner).await;
     //module.set_vdd_enable(true);
-    info!("hello from node {}", module.lora.address);
+    let firmware = firmware_info!(module.version);
+    info!(
+        "hello from node {}, {} {} {}",
+        module.lora.address,
+        firmware.name.as_str(),
+        firmware.version.as_str(),
+        firmware.git_hash.as_str()
+    );
 
     let flash = Mutex::new(BlockingAsync::new(Flash::new_blocking(module.flash)));
     let config = FirmwareUpdaterConfig::from_linkerfile(&flash, &flash);
     let mut magic = AlignedBuffer([0; WRITE_SIZE]);
-    let mut _updater = FirmwareUpdater::new(config, &mut magic.0);
+    let updater = FirmwareUpdater::new(config, &mut magic.0);
+    let dfu = FirmwareUpdaterConfig::from_linkerfile(&flash, &flash).dfu;
 
+    /* io4 carries the host UART in the AT modem build */
+    #[cfg(not(feature = "at_modem"))]
     let mut soil_sensor = SoilSensor::new(
             module.io8,
             module.io9,
@@ -66,51 +86,106 @@ async fn main(spawner: Spawner) {
     //info!("res {:?}", memory.read_jedec_id(&mut buff).await);
     //info!("read {=[u8]:x}", buff);
 
-    let mut ota_consumer = OtaConsumer::<OtaMemory>::new(OtaMemory::new());
-    let mut lora = module.lora;
+    let ota_consumer = OtaConsumer::new(DfuMemory::<_, _, FLASH_PAGE_SIZE>::new(updater, dfu));
+    /* images not signed by the key this firmware was built with are refused */
+    #[cfg(feature = "signed_ota")]
+    let ota_consumer = ota_consumer.with_public_key(ota_public_key!());
+    let mut ota_consumer = ota_consumer;
+    let radio = Radio::start(&spawner, module.lora);
+
+    let mut health = HealthChecks::new(&mut ota_consumer.memory, HEALTH_CHECKS, HEALTH_CHECK_TIMEOUT).await;
+    /* init already failed on a radio that does not respond, this proves its task runs */
+    radio.stats();
+    health.pass("radio");
+
+    /* the modem has no gateway to wait for, the host MCU drives it */
+    #[cfg(feature = "at_modem")]
+    {
+        health.conclude(&mut ota_consumer.memory, &mut radio.client(accept_all)).await;
+        at::modem(module.host, radio, &mut ota_consumer, &mut health).await;
+    }
+
+    #[cfg(not(feature = "at_modem"))]
+    if health.is_on_trial() {
+        module.vdd_switch.set_high();
+        Timer::after_millis(10).await;
+        let samples = soil_sensor.sample_all_average().await;
+        module.vdd_switch.set_low();
+        if samples.iter().any(|s| matches!(s, SoilSensorResult::Ok(_))) {
+            health.pass("sensor");
+        } else {
+            health.fail("sensor");
+        }
+    }
+
+    #[cfg(not(feature = "at_modem"))]
+    let mut ota_lora = radio.client(|p| matches!(p.packet_type, LoRaPacketType::OTA));
+    #[cfg(not(feature = "at_modem"))]
+    let mut lora = radio.client(|p| !matches!(p.packet_type, LoRaPacketType::OTA));
+    #[cfg(not(feature = "at_modem"))]
     loop {
-        match lora.receive_continuous().await {
-            Ok(p) => match p.packet_type {
-                LoRaPacketType::OTA => match ota_consumer.process_message(&mut lora, p).await {
-                    Ok(_) => {}
-                    Err(e) => {
-                        error!("ota error: {}", e)
-                    }
-                },
-                LoRaPacketType::SoilSensor => {
-                    module.vdd_switch.set_high();
-                    Timer::after_millis(10).await;
-                    soil_sensor_measure_and_transmit(&mut soil_sensor, &mut lora, p.source).await;
-                    module.vdd_switch.set_low();
-                },
-                _ => {}
+        match select3(ota_lora.receive_continuous(), lora.receive_continuous(), health.timeout()).await {
+            /* only the other packets pass the gateway check, an update proves nothing */
+            Either3::First(p) => {
+                health.set_gateway(p.source);
+                /* until this firmware is confirmed the DFU partition holds the previous one,
+                an update would erase what a rollback swaps back */
+                if health.is_on_trial() {
+                    warn!("ota refused, the firmware is still on trial");
+                } else if let Err(e) = ota_consumer.process_message(&mut ota_lora, p).await {
+                    error!("ota error: {}", e)
+                }
             },
-            Err(e) => {
-                error!("lora rx error: {}", e)
-            }
+            Either3::Second(p) => {
+                health.set_gateway(p.source);
+                health.pass("gateway");
+                match p.packet_type {
+                    LoRaPacketType::SoilSensor => {
+                        module.vdd_switch.set_high();
+                        Timer::after_millis(10).await;
+                        soil_sensor_measure_and_transmit(&mut soil_sensor, &mut lora, p.source).await;
+                        module.vdd_switch.set_low();
+                    },
+                    LoRaPacketType::LinkStats => {
+                        let mut resp = LoRaPacket::new(p.source, LoRaPacketType::LinkStats);
+                        match postcard::to_vec(&radio.stats()) {
+                            Ok(payload) => {
+                                resp.payload = payload;
+                                if let Err(e) = lora.transmit(&mut resp).await {
+                                    error!("lora tx error: {}", e)
+                                }
+                            }
+                            Err(e) => {
+                                error!("failed to serialize link stats: {}", e)
+                            }
+                        }
+                    },
+                    LoRaPacketType::Info => {
+                        let mut resp = LoRaPacket::new(p.source, LoRaPacketType::Info);
+                        match postcard::to_vec(&firmware) {
+                            Ok(payload) => {
+                                resp.payload = payload;
+                                if let Err(e) = lora.transmit(&mut resp).await {
+                                    error!("lora tx error: {}", e)
+                                }
+                            }
+                            Err(e) => {
+                                error!("failed to serialize firmware info: {}", e)
+                            }
+                        }
+                    },
+                    _ => {}
+                }
+            },
+            /* conclude rolls back */
+            Either3::Third(()) => {}
         }
         status_led(LedCommand::FlashShort).await;
+        health.conclude(&mut ota_consumer.memory, &mut lora).await;
 
-        // disabled for testing the range
-        /* if let Some(page) = ota_consumer.memory.get_page() {
-            info!("Writing page at 0x{:x}", page.address);
-            updater
-                .write_firmware(page.address, &page.buffer)
-                .await
-                .unwrap();
+        /* DoneAck is out, the bootloader swaps the image in after the reset */
+        if ota_consumer.is_verified() {
+            ota_consumer.memory.activate().await;
         }
-
-        if ota_consumer.is_done() {
-            if let Some(page) = ota_consumer.memory.get_last_page() {
-                info!("Writing last page at 0x{:x}", page.address);
-                updater
-                    .write_firmware(page.address, &page.buffer)
-                    .await
-                    .unwrap();
-            }
-            updater.mark_updated().await.unwrap();
-            info!("Marked as updated");
-            cortex_m::peripheral::SCB::sys_reset();
-        } */
     }
 }
@@ -0,0 +1,177 @@
+use embassy_stm32::usart;
+use gateway_host_schema::framing::{FrameDecoder, FramingError};
+
+#[cfg(not(feature = "host_spi"))]
+mod uart;
+#[cfg(feature = "host_spi")]
+mod spi;
+
+#[cfg(not(feature = "host_spi"))]
+pub use uart::*;
+#[cfg(feature = "host_spi")]
+pub use spi::*;
+
+/* the transport the gateway talks to the host over, chosen by the host_spi feature */
+#[cfg(not(feature = "host_spi"))]
+pub type ModuleHost = UartHost;
+#[cfg(feature = "host_spi")]
+pub type ModuleHost = SpiHost;
+
+const HOST_FRAME_BUFFER_SIZE: usize = 512;
+const HOST_LINE_LENGTH: usize = 300;
+
+#[derive(Debug, defmt::Format)]
+pub enum HostError {
+    Uart(usart::Error),
+    /* the host clocked bytes faster than they were taken out of the SPI */
+    Overrun,
+    Framing(FramingError),
+    DataTooLong,
+}
+
+/// The link to the host, it carries the same framed packets, or lines of
+/// text, whatever the physical interface is.
+pub trait HostTransport {
+    /// Returns the next complete frame, it is safe to drop the future,
+    /// the bytes received so far stay buffered for the next call.
+    async fn read(&mut self, buffer: &mut [u8]) -> Result<usize, HostError>;
+
+    async fn write(&mut self, buffer: &[u8]) -> Result<(), HostError>;
+
+    /// Returns the next line without its terminator, for text protocols
+    /// instead of the framed one. Empty lines are skipped.
+    async fn read_line(&mut self, buffer: &mut [u8]) -> Result<usize, HostError>;
+
+    /// Writes the bytes as they are, without framing.
+    async fn write_raw(&mut self, buffer: &[u8]) -> Result<(), HostError>;
+
+    /// Returns whatever bytes arrived, starting with those buffered by an
+    /// earlier read, for protocols framed by the application.
+    async fn read_raw(&mut self, buffer: &mut [u8]) -> Result<usize, HostError>;
+
+    /// Puts bytes returned by read_raw back, in front of those not read yet,
+    /// when the application protocol ended before them.
+    fn unread_raw(&mut self, buffer: &[u8]) -> Result<(), HostError>;
+}
+
+/* splits whatever the transport received into frames, no matter how the bytes arrived */
+pub(crate) struct FrameReader {
+    decoder: FrameDecoder<HOST_FRAME_BUFFER_SIZE>,
+    /* bytes taken from the transport that were not fed to the decoder yet */
+    chunk: [u8; HOST_FRAME_BUFFER_SIZE],
+    chunk_len: usize,
+    chunk_pos: usize,
+    /* the line received so far, for read_line */
+    line: [u8; HOST_LINE_LENGTH],
+    line_len: usize,
+    line_overflow: bool,
+}
+
+impl FrameReader {
+    pub(crate) const fn new() -> Self {
+        FrameReader {
+            decoder: FrameDecoder::new(),
+            chunk: [0u8; HOST_FRAME_BUFFER_SIZE],
+            chunk_len: 0,
+            chunk_pos: 0,
+            line: [0u8; HOST_LINE_LENGTH],
+            line_len: 0,
+            line_overflow: false,
+        }
+    }
+
+    /* None once the buffered bytes are used up without completing a frame */
+    pub(crate) fn next_frame(&mut self, buffer: &mut [u8]) -> Option<Result<usize, HostError>> {
+        while self.chunk_pos < self.chunk_len {
+            let byte = self.chunk[self.chunk_pos];
+            self.chunk_pos += 1;
+            match self.decoder.push(byte) {
+                Some(Ok(data)) => {
+                    if data.len() > buffer.len() {
+                        return Some(Err(HostError::DataTooLong));
+                    }
+                    buffer[..data.len()].copy_from_slice(data);
+                    return Some(Ok(data.len()));
+                }
+                /* the decoder is ready for the next frame already */
+                Some(Err(e)) => return Some(Err(HostError::Framing(e))),
+                None => {}
+            }
+        }
+        None
+    }
+
+    /* same as next_frame, but the bytes are split at CR or LF */
+    pub(crate) fn next_line(&mut self, buffer: &mut [u8]) -> Option<Result<usize, HostError>> {
+        while self.chunk_pos < self.chunk_len {
+            let byte = self.chunk[self.chunk_pos];
+            self.chunk_pos += 1;
+            if byte == 0 {
+                /* idle bytes of the SPI, never part of text */
+                continue;
+            }
+            if byte != b'\r' && byte != b'\n' {
+                if self.line_len < HOST_LINE_LENGTH {
+                    self.line[self.line_len] = byte;
+                    self.line_len += 1;
+                } else {
+                    self.line_overflow = true;
+                }
+                continue;
+            }
+            let len = self.line_len;
+            let overflow = self.line_overflow;
+            self.line_len = 0;
+            self.line_overflow = false;
+            if overflow || len > buffer.len() {
+                return Some(Err(HostError::DataTooLong));
+            }
+            if len > 0 {
+                buffer[..len].copy_from_slice(&self.line[..len]);
+                return Some(Ok(len));
+            }
+        }
+        None
+    }
+
+    /* the bytes not consumed by next_frame or next_line yet */
+    pub(crate) fn next_raw(&mut self, buffer: &mut [u8]) -> Option<usize> {
+        let len = (self.chunk_len - self.chunk_pos).min(buffer.len());
+        if len == 0 {
+            return None;
+        }
+        buffer[..len].copy_from_slice(&self.chunk[self.chunk_pos..self.chunk_pos + len]);
+        self.chunk_pos += len;
+        Some(len)
+    }
+
+    /* in front of the bytes not consumed yet, those taken by next_raw always fit back */
+    pub(crate) fn unread(&mut self, data: &[u8]) -> Result<(), HostError> {
+        let rest = self.chunk_len - self.chunk_pos;
+        if data.len() + rest > HOST_FRAME_BUFFER_SIZE {
+            return Err(HostError::DataTooLong);
+        }
+        self.chunk.copy_within(self.chunk_pos..self.chunk_len, data.len());
+        self.chunk[..data.len()].copy_from_slice(data);
+        self.chunk_pos = 0;
+        self.chunk_len = data.len() + rest;
+        Ok(())
+    }
+
+    /* to be filled by the transport, then confirmed with filled() */
+    pub(crate) fn chunk(&mut self) -> &mut [u8] {
+        self.chunk_pos = 0;
+        self.chunk_len = 0;
+        &mut self.chunk
+    }
+
+    pub(crate) fn filled(&mut self, len: usize) {
+        self.chunk_len = len;
+    }
+
+    /* bytes were lost, the frame in progress cannot be complete */
+    pub(crate) fn reset(&mut self) {
+        self.decoder.reset();
+        self.line_len = 0;
+    }
+}
@@ -0,0 +1,200 @@
+use super::{FrameReader, HostError, HostTransport, HOST_FRAME_BUFFER_SIZE};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use embassy_stm32::gpio::{Level, Output, Speed};
+use embassy_stm32::interrupt::{self, InterruptExt};
+use embassy_stm32::pac;
+use embassy_stm32::pac::gpio::vals as gpio_vals;
+use embassy_stm32::pac::spi::vals;
+use embassy_stm32::peripherals;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::pipe::Pipe;
+use gateway_host_schema::framing;
+
+/*
+The gateway is an SPI slave (mode 0, MSB first, 8 bit) of the host:
+    io5 PA1 SCK, io2 PA6 MISO, io1 PA7 MOSI, io3 PA4 NSS
+    io6 PA0 data ready, high while the gateway has bytes for the host
+Both sides send the same framed stream as over the UART, when there is nothing
+to send the bytes are 0x00. Those are frame delimiters that the decoder skips,
+so the host keeps clocking while data ready is high and at any time it wants
+to send something itself.
+The last bytes of a write still sit in the TX FIFO when the pipe runs empty,
+so the FIFO is padded with delimiters and data ready only goes low once more
+of them were queued than the FIFO holds, the data is clocked out by then.
+The host reads a few 0x00 after each write because of that.
+*/
+
+const HOST_SPI_PIPE_SIZE: usize = 1024;
+/* alternate function of SPI1 on the pins above */
+const SPI1_AF: u8 = 5;
+const DATA_READY_PIN: usize = 0;
+/* the 32 bit TX FIFO holds 4 bytes, one more is in the shift register */
+const TX_FIFO_LENGTH: usize = 4;
+
+static RX: Pipe<CriticalSectionRawMutex, HOST_SPI_PIPE_SIZE> = Pipe::new();
+static TX: Pipe<CriticalSectionRawMutex, HOST_SPI_PIPE_SIZE> = Pipe::new();
+static OVERRUN: AtomicBool = AtomicBool::new(false);
+/* delimiters queued since the pipe ran empty, only the interrupt touches it */
+static PADDING: AtomicUsize = AtomicUsize::new(0);
+
+/// Interrupt handler, moves the bytes between the SPI and the pipes one at a time.
+pub struct InterruptHandler {}
+
+impl interrupt::typelevel::Handler<interrupt::typelevel::SPI1> for InterruptHandler {
+    unsafe fn on_interrupt() {
+        let regs = pac::SPI1;
+        let sr = regs.sr().read();
+        if sr.ovr() {
+            /* reading DR and then SR clears the overrun */
+            let _ = core::ptr::read_volatile(regs.dr().as_ptr() as *const u8);
+            let _ = regs.sr().read();
+            OVERRUN.store(true, Ordering::Relaxed);
+        }
+        if sr.rxne() {
+            let byte = core::ptr::read_volatile(regs.dr().as_ptr() as *const u8);
+            if RX.try_write(&[byte]).is_err() {
+                OVERRUN.store(true, Ordering::Relaxed);
+            }
+        }
+        if sr.txe() {
+            let mut byte = [0u8; 1];
+            if TX.try_read(&mut byte).is_ok() {
+                PADDING.store(0, Ordering::Relaxed);
+            } else {
+                let padding = PADDING.load(Ordering::Relaxed) + 1;
+                if padding > TX_FIFO_LENGTH {
+                    /* only delimiters left in the FIFO, the host may stop clocking */
+                    pac::GPIOA.bsrr().write(|w| w.set_br(DATA_READY_PIN, true));
+                } else {
+                    PADDING.store(padding, Ordering::Relaxed);
+                }
+                byte[0] = framing::FRAME_DELIMITER;
+            }
+            core::ptr::write_volatile(regs.dr().as_ptr() as *mut u8, byte[0]);
+        }
+    }
+}
+
+fn set_alternate(pin: usize) {
+    pac::GPIOA
+        .moder()
+        .modify(|w| w.set_moder(pin, gpio_vals::Moder::ALTERNATE));
+    pac::GPIOA
+        .ospeedr()
+        .modify(|w| w.set_ospeedr(pin, gpio_vals::Ospeedr::VERYHIGHSPEED));
+    pac::GPIOA
+        .afr(pin / 8)
+        .modify(|w| w.set_afr(pin % 8, SPI1_AF));
+}
+
+pub struct SpiHost {
+    _spi: peripherals::SPI1,
+    data_ready: Output<'static>,
+    frames: FrameReader,
+}
+
+impl SpiHost {
+    pub fn new(
+        spi: peripherals::SPI1,
+        _irq: impl interrupt::typelevel::Binding<interrupt::typelevel::SPI1, InterruptHandler>
+            + 'static,
+        _sck: peripherals::PA1,
+        _miso: peripherals::PA6,
+        _mosi: peripherals::PA7,
+        _nss: peripherals::PA4,
+        data_ready: peripherals::PA0,
+    ) -> Self {
+        /* embassy has no slave mode, the pins are taken so nobody else uses them */
+        let data_ready = Output::new(data_ready, Level::Low, Speed::Low);
+        for pin in [1, 6, 7, 4] {
+            set_alternate(pin);
+        }
+        pac::RCC.apb2enr().modify(|w| w.set_spi1en(true));
+        let regs = pac::SPI1;
+        regs.cr1().modify(|w| {
+            w.set_spe(false);
+            w.set_mstr(vals::Mstr::SLAVE);
+            w.set_cpol(vals::Cpol::IDLELOW);
+            w.set_cpha(vals::Cpha::FIRSTEDGE);
+            w.set_lsbfirst(vals::Lsbfirst::MSBFIRST);
+            /* NSS from the pin, a transfer only happens while the host selects us */
+            w.set_ssm(false);
+        });
+        regs.cr2().modify(|w| {
+            w.set_ds(vals::Ds::BITS8);
+            /* RXNE after every byte, not every two */
+            w.set_frxth(vals::Frxth::QUARTER);
+            w.set_rxneie(true);
+            w.set_txeie(true);
+            w.set_errie(true);
+        });
+        regs.cr1().modify(|w| w.set_spe(true));
+        interrupt::SPI1.unpend();
+        unsafe { interrupt::SPI1.enable() };
+        SpiHost {
+            _spi: spi,
+            data_ready,
+            frames: FrameReader::new(),
+        }
+    }
+}
+
+impl HostTransport for SpiHost {
+    async fn read(&mut self, buffer: &mut [u8]) -> Result<usize, HostError> {
+        loop {
+            if let Some(result) = self.frames.next_frame(buffer) {
+                return result;
+            }
+            let len = RX.read(self.frames.chunk()).await;
+            self.frames.filled(len);
+            if OVERRUN.swap(false, Ordering::Relaxed) {
+                self.frames.reset();
+                return Err(HostError::Overrun);
+            }
+        }
+    }
+
+    async fn write(&mut self, buffer: &[u8]) -> Result<(), HostError> {
+        let mut buff = [0u8; HOST_FRAME_BUFFER_SIZE];
+        let len = framing::encode(buffer, &mut buff).map_err(HostError::Framing)?;
+        self.write_raw(&buff[..len]).await
+    }
+
+    /* the host reads 0x00 when there is nothing, so it has to ignore those in text */
+    async fn read_line(&mut self, buffer: &mut [u8]) -> Result<usize, HostError> {
+        loop {
+            if let Some(result) = self.frames.next_line(buffer) {
+                return result;
+            }
+            let len = RX.read(self.frames.chunk()).await;
+            self.frames.filled(len);
+            if OVERRUN.swap(false, Ordering::Relaxed) {
+                self.frames.reset();
+                return Err(HostError::Overrun);
+            }
+        }
+    }
+
+    /* includes the 0x00 the host clocks out while it has nothing to send */
+    async fn read_raw(&mut self, buffer: &mut [u8]) -> Result<usize, HostError> {
+        if let Some(len) = self.frames.next_raw(buffer) {
+            return Ok(len);
+        }
+        let len = RX.read(buffer).await;
+        if OVERRUN.swap(false, Ordering::Relaxed) {
+            return Err(HostError::Overrun);
+        }
+        Ok(len)
+    }
+
+    fn unread_raw(&mut self, buffer: &[u8]) -> Result<(), HostError> {
+        self.frames.unread(buffer)
+    }
+
+    async fn write_raw(&mut self, buffer: &[u8]) -> Result<(), HostError> {
+        TX.write_all(buffer).await;
+        /* the interrupt lowers it again once everything was clocked out */
+        self.data_ready.set_high();
+        Ok(())
+    }
+}
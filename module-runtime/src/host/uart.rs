@@ -0,0 +1,83 @@
+use super::{FrameReader, HostError, HostTransport, HOST_FRAME_BUFFER_SIZE};
+use embassy_stm32::peripherals;
+use embassy_stm32::usart::{RingBufferedUartRx, UartTx};
+use gateway_host_schema::framing;
+
+/* filled by the DMA in the background, so nothing gets lost between reads */
+pub(crate) const HOST_UART_RING_BUFFER_SIZE: usize = 1024;
+
+pub struct UartHost {
+    pub tx: UartTx<'static, peripherals::LPUART1, peripherals::DMA1_CH3>,
+    pub rx: RingBufferedUartRx<'static, peripherals::LPUART1, peripherals::DMA1_CH4>,
+    frames: FrameReader,
+}
+
+impl UartHost {
+    pub fn new(
+        tx: UartTx<'static, peripherals::LPUART1, peripherals::DMA1_CH3>,
+        rx: RingBufferedUartRx<'static, peripherals::LPUART1, peripherals::DMA1_CH4>,
+    ) -> Self {
+        UartHost {
+            tx,
+            rx,
+            frames: FrameReader::new(),
+        }
+    }
+}
+
+impl HostTransport for UartHost {
+    async fn read(&mut self, buffer: &mut [u8]) -> Result<usize, HostError> {
+        loop {
+            if let Some(result) = self.frames.next_frame(buffer) {
+                return result;
+            }
+            match self.rx.read(self.frames.chunk()).await {
+                Ok(len) => {
+                    //info!("RX {}", len);
+                    self.frames.filled(len);
+                }
+                Err(e) => {
+                    self.frames.reset();
+                    return Err(HostError::Uart(e));
+                }
+            }
+        }
+    }
+
+    async fn read_line(&mut self, buffer: &mut [u8]) -> Result<usize, HostError> {
+        loop {
+            if let Some(result) = self.frames.next_line(buffer) {
+                return result;
+            }
+            match self.rx.read(self.frames.chunk()).await {
+                Ok(len) => self.frames.filled(len),
+                Err(e) => {
+                    self.frames.reset();
+                    return Err(HostError::Uart(e));
+                }
+            }
+        }
+    }
+
+    async fn read_raw(&mut self, buffer: &mut [u8]) -> Result<usize, HostError> {
+        if let Some(len) = self.frames.next_raw(buffer) {
+            return Ok(len);
+        }
+        self.rx.read(buffer).await.map_err(HostError::Uart)
+    }
+
+    fn unread_raw(&mut self, buffer: &[u8]) -> Result<(), HostError> {
+        self.frames.unread(buffer)
+    }
+
+    async fn write_raw(&mut self, buffer: &[u8]) -> Result<(), HostError> {
+        self.tx.write(buffer).await.map_err(HostError::Uart)
+    }
+
+    async fn write(&mut self, buffer: &[u8]) -> Result<(), HostError> {
+        let mut buff = [0u8; HOST_FRAME_BUFFER_SIZE];
+        let len = framing::encode(buffer, &mut buff).map_err(HostError::Framing)?;
+        //info!("TX {}: {:?}", len, &buff[..len]);
+        self.tx.write(&buff[..len]).await.map_err(HostError::Uart)
+    }
+}
@@ -0,0 +1,57 @@
+use crate::ModuleVersion;
+use gateway_host_schema::FirmwareInfo;
+use heapless::String;
+
+impl ModuleVersion {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ModuleVersion::NucleoWL55JC => "NucleoWL55JC",
+            ModuleVersion::Lumia => "Lumia",
+        }
+    }
+}
+
+/* cut short, a longer value would not fit the packet */
+fn truncated<const N: usize>(value: &str) -> String<N> {
+    let mut s = String::new();
+    for c in value.chars() {
+        if s.push(c).is_err() {
+            break;
+        }
+    }
+    s
+}
+
+/// Use [`firmware_info!`] instead, it fills in the values of the application crate.
+pub fn build_firmware_info(
+    name: &str,
+    version: &str,
+    git_hash: &str,
+    build_time: &str,
+    features: &str,
+    module_version: ModuleVersion,
+) -> FirmwareInfo {
+    FirmwareInfo {
+        name: truncated(name),
+        version: truncated(version),
+        git_hash: truncated(git_hash),
+        build_time: build_time.parse().unwrap_or(0),
+        module_version: truncated(module_version.name()),
+        features: truncated(features),
+    }
+}
+
+/// Describes the firmware being built, from the metadata its build.rs embeds.
+#[macro_export]
+macro_rules! firmware_info {
+    ($module_version:expr) => {
+        $crate::build_firmware_info(
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION"),
+            env!("FIRMWARE_GIT_HASH"),
+            env!("FIRMWARE_BUILD_TIME"),
+            env!("FIRMWARE_FEATURES"),
+            $module_version,
+        )
+    };
+}
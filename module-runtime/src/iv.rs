@@ -1,9 +1,11 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+use embassy_futures::select::*;
 use embassy_stm32::interrupt;
 use embassy_stm32::interrupt::InterruptExt;
 use embassy_stm32::pac;
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::signal::Signal;
-use embassy_time::Timer;
+use embassy_time::{Duration, Instant, Timer};
 use embedded_hal::digital::OutputPin;
 use embedded_hal_async::spi::{ErrorType, Operation, SpiBus, SpiDevice};
 use lora_phy::mod_params::RadioError;
@@ -22,6 +24,45 @@ impl interrupt::typelevel::Handler<interrupt::typelevel::SUBGHZ_RADIO> for Inter
 }
 
 static IRQ_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+/* 0 means wait for the IRQ forever */
+static IRQ_TIMEOUT_MS: AtomicU32 = AtomicU32::new(0);
+/* ends a wait without timeout, kept until the next such wait when none runs */
+static INTERRUPT_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// The radio is considered hung when it stays busy for longer than this.
+const BUSY_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Limit how long the next IRQ waits may take, a radio that does not raise the IRQ in time
+/// is considered hung. `None` is for operations that legitimately wait forever, like
+/// continuous RX.
+pub fn set_irq_timeout(timeout: Option<Duration>) {
+    let ms = match timeout {
+        Some(t) => t.as_millis().clamp(1, u32::MAX as u64) as u32,
+        None => 0,
+    };
+    IRQ_TIMEOUT_MS.store(ms, Ordering::Relaxed);
+}
+
+/// Ends the IRQ wait of a continuous RX with `ReceiveTimeout`, the radio keeps listening
+/// until it is put in standby. Other operations have a timeout and are not interrupted.
+pub fn interrupt_wait() {
+    INTERRUPT_SIGNAL.signal(());
+}
+
+/// Forgets an interrupt that did not end a wait yet.
+pub fn reset_interrupt() {
+    INTERRUPT_SIGNAL.reset();
+}
+
+/// Keeps the interrupt disabled once nobody is waiting for it, including when the
+/// waiting future gets dropped.
+struct IrqGuard;
+
+impl Drop for IrqGuard {
+    fn drop(&mut self) {
+        interrupt::SUBGHZ_RADIO.disable();
+    }
+}
 
 /// Base for the InterfaceVariant implementation for an stm32wl/sx1262 combination
 pub struct Stm32wlInterfaceVariant<CTRL> {
@@ -58,13 +99,31 @@ where
         Ok(())
     }
     async fn wait_on_busy(&mut self) -> Result<(), RadioError> {
-        while pac::PWR.sr2().read().rfbusys() {}
+        let start = Instant::now();
+        while pac::PWR.sr2().read().rfbusys() {
+            if start.elapsed() > BUSY_TIMEOUT {
+                return Err(Busy);
+            }
+        }
         Ok(())
     }
 
     async fn await_irq(&mut self) -> Result<(), RadioError> {
+        /* a signal left behind by a cancelled operation does not belong to this one,
+        the radio keeps the line asserted, so a real event fires again once enabled */
+        IRQ_SIGNAL.reset();
+        let _guard = IrqGuard;
         unsafe { interrupt::SUBGHZ_RADIO.enable() };
-        IRQ_SIGNAL.wait().await;
+        match IRQ_TIMEOUT_MS.load(Ordering::Relaxed) {
+            0 => match select(IRQ_SIGNAL.wait(), INTERRUPT_SIGNAL.wait()).await {
+                Either::First(_) => {}
+                Either::Second(_) => return Err(ReceiveTimeout),
+            },
+            ms => match select(IRQ_SIGNAL.wait(), Timer::after_millis(ms as u64)).await {
+                Either::First(_) => {}
+                Either::Second(_) => return Err(Irq),
+            },
+        }
         Ok(())
     }
 
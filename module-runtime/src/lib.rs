@@ -7,6 +7,10 @@
 pub use cortex_m;
 pub use cortex_m_rt;
 pub use defmt;
+/* with host_log, log.rs is the global logger, there can only be one */
+#[cfg(all(feature = "rtt", feature = "host_log"))]
+compile_error!("host_log replaces the rtt logger, build with --no-default-features");
+#[cfg(feature = "rtt")]
 pub use defmt_rtt;
 pub use embassy_boot;
 pub use embassy_boot_stm32;
@@ -16,15 +20,21 @@ pub use embassy_futures;
 pub use embassy_stm32;
 pub use embassy_sync;
 pub use embassy_time;
+pub use embedded_storage_async;
 pub use futures;
+pub use gateway_core;
 pub use gateway_host_schema;
 pub use heapless;
 pub use host::*;
+pub use info::*;
+#[cfg(feature = "host_log")]
+pub use log::*;
 pub use lora::*;
 pub use lora_phy;
 pub use ota::*;
 pub use panic_probe;
 pub use postcard;
+pub use radio::*;
 pub use serde;
 
 use self::iv::{Stm32wlInterfaceVariant, SubghzSpiDevice};
@@ -47,9 +57,13 @@ use lora_phy::sx126x::{self, Sx126x, Sx126xVariant, TcxoCtrlVoltage};
 use lora_phy::LoRa;
 
 mod host;
+mod info;
 mod iv;
+#[cfg(feature = "host_log")]
+mod log;
 mod lora;
 mod ota;
+mod radio;
 
 const LORA_FREQUENCY_IN_HZ: u32 = 869_525_000; // warning: set this appropriately for the region
 
@@ -58,6 +72,12 @@ bind_interrupts!(struct Irqs{
     SUBGHZ_RADIO => self::iv::InterruptHandler;
 });
 
+#[cfg(feature = "host_spi")]
+bind_interrupts!(struct HostIrqs{
+    SPI1 => host::InterruptHandler;
+});
+
+#[derive(Clone, Copy)]
 pub enum ModuleVersion {
     NucleoWL55JC,
     Lumia,
@@ -160,6 +180,7 @@ impl ModuleMemory {
 }
 
 pub struct ModuleInterface {
+    pub version: ModuleVersion,
     pub lora: ModuleLoRa,
     pub flash: peripherals::FLASH,
     pub memory: ModuleMemory,
@@ -167,12 +188,18 @@ pub struct ModuleInterface {
     #[cfg(feature = "host_interface")]
     pub host: ModuleHost,
 
+    /* io1, io2, io3, io5 and io6 are the SPI to the host with host_spi, io4 is the UART otherwise */
+    #[cfg(not(feature = "host_spi"))]
     pub io1: AnyPin,
+    #[cfg(not(feature = "host_spi"))]
     pub io2: AnyPin,
+    #[cfg(not(feature = "host_spi"))]
     pub io3: AnyPin,
-    #[cfg(not(feature = "host_interface"))]
+    #[cfg(any(not(feature = "host_interface"), feature = "host_spi"))]
     pub io4: AnyPin,
+    #[cfg(not(feature = "host_spi"))]
     pub io5: AnyPin,
+    #[cfg(not(feature = "host_spi"))]
     pub io6: AnyPin,
     pub io7: AnyPin,
     pub io8: AnyPin,
@@ -256,17 +283,22 @@ pub async fn init(
         .await
         .unwrap();
 
+    let spreading_factor = SpreadingFactor::_5;
+    let bandwidth = Bandwidth::_250KHz;
+    let coding_rate = CodingRate::_4_5;
     let lora_modulation = lora
         .create_modulation_params(
-            SpreadingFactor::_5,
-            Bandwidth::_250KHz,
-            CodingRate::_4_5,
+            spreading_factor,
+            bandwidth,
+            coding_rate,
             LORA_FREQUENCY_IN_HZ,
         )
         .unwrap();
 
-    #[cfg(feature = "host_interface")]
-    let mut host_uart = {
+    #[cfg(feature = "host_spi")]
+    let host = ModuleHost::new(p.SPI1, HostIrqs, p.PA1, p.PA6, p.PA7, p.PA4, p.PA0);
+    #[cfg(all(feature = "host_interface", not(feature = "host_spi")))]
+    let host = {
         let mut lpuart1_config = usart::Config::default();
         lpuart1_config.baudrate = 115200;
         let lpuart1 = Uart::new(
@@ -279,7 +311,11 @@ pub async fn init(
             lpuart1_config,
         )
         .unwrap();
-        lpuart1
+        let (tx, rx) = lpuart1.split();
+        let ring_buffer =
+            cortex_m::singleton!(: [u8; host::HOST_UART_RING_BUFFER_SIZE] = [0; host::HOST_UART_RING_BUFFER_SIZE])
+                .unwrap();
+        ModuleHost::new(tx, rx.into_ring_buffered(ring_buffer))
     };
 
     let led = match module_config.version {
@@ -317,25 +353,37 @@ pub async fn init(
     let memory = ModuleMemory { spi, ncs, hold };
 
     ModuleInterface {
+        version: module_config.version,
         lora: ModuleLoRa {
             lora,
             lora_modulation,
+            spreading_factor,
+            bandwidth,
+            coding_rate,
+            frequency_in_hz: LORA_FREQUENCY_IN_HZ,
             crc,
             address: match module_config.version {
                 ModuleVersion::NucleoWL55JC => 1,
                 ModuleVersion::Lumia => 3,
             },
+            stats: Default::default(),
+            operation_pending: false,
         },
         flash: p.FLASH,
         memory,
         vdd_switch,
 
+        #[cfg(not(feature = "host_spi"))]
         io1: p.PA7.degrade(),
+        #[cfg(not(feature = "host_spi"))]
         io2: p.PA6.degrade(),
+        #[cfg(not(feature = "host_spi"))]
         io3: p.PA4.degrade(),
-        #[cfg(not(feature = "host_interface"))]
+        #[cfg(any(not(feature = "host_interface"), feature = "host_spi"))]
         io4: p.PA2.degrade(),
+        #[cfg(not(feature = "host_spi"))]
         io5: p.PA1.degrade(),
+        #[cfg(not(feature = "host_spi"))]
         io6: p.PA0.degrade(),
         io7: p.PB8.degrade(),
         io8: p.PB7.degrade(),
@@ -353,7 +401,7 @@ pub async fn init(
         io10_exti: p.EXTI5.degrade(),
 
         #[cfg(feature = "host_interface")]
-        host: ModuleHost { uart: host_uart },
+        host,
     }
 }
 
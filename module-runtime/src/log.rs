@@ -0,0 +1,239 @@
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicU32, AtomicU8, AtomicUsize, Ordering};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_time::Instant;
+use gateway_host_schema::{GatewayPacket, LogConfig, LogLevel};
+use heapless::Vec;
+
+/*
+defmt global logger used instead of defmt-rtt with the host_log feature.
+Every frame still goes to RTT, so a probe sees the same output as before.
+A copy of the frame before its rzcobs encoding is queued for the host, after
+    the level filter: the frame starts with the index of its format string, the
+        linker sorts them by level between the __DEFMT_MARKER_* symbols
+    the rate limit: at most max_per_second frames within each second
+Frames not queued are counted and reported with the next one.
+*/
+
+pub const LOG_FRAME_LENGTH: usize = 128;
+const LOG_QUEUE_LENGTH: usize = 4;
+const RTT_BUFFER_SIZE: usize = 1024;
+
+const DEFAULT_LEVEL: LogLevel = LogLevel::Info;
+const DEFAULT_MAX_PER_SECOND: u16 = 20;
+
+/* the frame and the number suppressed before it */
+type QueuedFrame = (Vec<u8, LOG_FRAME_LENGTH>, u32);
+
+static LOG_FRAMES: Channel<CriticalSectionRawMutex, QueuedFrame, LOG_QUEUE_LENGTH> = Channel::new();
+static SUPPRESSED: AtomicU32 = AtomicU32::new(0);
+static LEVEL: AtomicU8 = AtomicU8::new(DEFAULT_LEVEL as u8);
+static MAX_PER_SECOND: AtomicU16 = AtomicU16::new(DEFAULT_MAX_PER_SECOND);
+static WINDOW_SECOND: AtomicU32 = AtomicU32::new(0);
+static WINDOW_COUNT: AtomicU16 = AtomicU16::new(0);
+
+pub fn configure_host_log(config: &LogConfig) {
+    LEVEL.store(config.level as u8, Ordering::Relaxed);
+    MAX_PER_SECOND.store(config.max_per_second, Ordering::Relaxed);
+}
+
+/// Waits for the next log frame to forward, as a `GatewayPacket::Log` event.
+pub async fn next_host_log() -> GatewayPacket {
+    let (frame, suppressed) = LOG_FRAMES.receive().await;
+    GatewayPacket::Log { frame, suppressed }
+}
+
+extern "C" {
+    static __DEFMT_MARKER_TRACE_START: u8;
+    static __DEFMT_MARKER_DEBUG_START: u8;
+    static __DEFMT_MARKER_INFO_START: u8;
+    static __DEFMT_MARKER_WARN_START: u8;
+    static __DEFMT_MARKER_ERROR_START: u8;
+    static __DEFMT_MARKER_ERROR_END: u8;
+}
+
+/* the .defmt section is not loaded, the addresses of its symbols are the indexes */
+fn marker(symbol: &'static u8) -> u16 {
+    symbol as *const u8 as usize as u16
+}
+
+fn level() -> LogLevel {
+    match LEVEL.load(Ordering::Relaxed) {
+        0 => LogLevel::Trace,
+        1 => LogLevel::Debug,
+        2 => LogLevel::Info,
+        3 => LogLevel::Warn,
+        4 => LogLevel::Error,
+        _ => LogLevel::Off,
+    }
+}
+
+fn level_passes(index: u16) -> bool {
+    let (first, below) = unsafe {
+        let below = match level() {
+            LogLevel::Trace => return true,
+            LogLevel::Debug => &__DEFMT_MARKER_DEBUG_START,
+            LogLevel::Info => &__DEFMT_MARKER_INFO_START,
+            LogLevel::Warn => &__DEFMT_MARKER_WARN_START,
+            LogLevel::Error => &__DEFMT_MARKER_ERROR_START,
+            LogLevel::Off => &__DEFMT_MARKER_ERROR_END,
+        };
+        (marker(&__DEFMT_MARKER_TRACE_START), marker(below))
+    };
+    /* println! and the like are not sorted by level, they always pass */
+    !(first..below).contains(&index)
+}
+
+fn rate_passes() -> bool {
+    let max = MAX_PER_SECOND.load(Ordering::Relaxed);
+    if max == 0 {
+        return true;
+    }
+    let second = Instant::now().as_secs() as u32;
+    if WINDOW_SECOND.swap(second, Ordering::Relaxed) != second {
+        WINDOW_COUNT.store(0, Ordering::Relaxed);
+    }
+    let count = WINDOW_COUNT.load(Ordering::Relaxed);
+    if count >= max {
+        return false;
+    }
+    WINDOW_COUNT.store(count + 1, Ordering::Relaxed);
+    true
+}
+
+/* runs with interrupts disabled, between acquire and release */
+fn forward(frame: &[u8], complete: bool) {
+    let index = match frame {
+        [b0, b1, ..] => u16::from_le_bytes([*b0, *b1]),
+        _ => return,
+    };
+    if !level_passes(index) {
+        return;
+    }
+    /* a frame cut short cannot be decoded, it is counted like the rate limited ones */
+    if !complete || !rate_passes() {
+        SUPPRESSED.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    let suppressed = SUPPRESSED.swap(0, Ordering::Relaxed);
+    let frame = Vec::from_slice(frame).unwrap();
+    if LOG_FRAMES.try_send((frame, suppressed)).is_err() {
+        SUPPRESSED.fetch_add(suppressed + 1, Ordering::Relaxed);
+    }
+}
+
+static TAKEN: AtomicBool = AtomicBool::new(false);
+static INTERRUPTS_ACTIVE: AtomicBool = AtomicBool::new(false);
+static mut ENCODER: defmt::Encoder = defmt::Encoder::new();
+static mut FRAME: Vec<u8, LOG_FRAME_LENGTH> = Vec::new();
+static mut FRAME_COMPLETE: bool = true;
+
+#[defmt::global_logger]
+struct HostLogger;
+
+unsafe impl defmt::Logger for HostLogger {
+    fn acquire() {
+        let primask = cortex_m::register::primask::read();
+        cortex_m::interrupt::disable();
+        if TAKEN.load(Ordering::Relaxed) {
+            panic!("defmt logger taken reentrantly")
+        }
+        TAKEN.store(true, Ordering::Relaxed);
+        INTERRUPTS_ACTIVE.store(primask.is_active(), Ordering::Relaxed);
+        unsafe {
+            FRAME.clear();
+            FRAME_COMPLETE = true;
+            ENCODER.start_frame(rtt_write);
+        }
+    }
+
+    unsafe fn flush() {}
+
+    unsafe fn release() {
+        ENCODER.end_frame(rtt_write);
+        forward(&FRAME, FRAME_COMPLETE);
+        TAKEN.store(false, Ordering::Relaxed);
+        if INTERRUPTS_ACTIVE.load(Ordering::Relaxed) {
+            cortex_m::interrupt::enable();
+        }
+    }
+
+    unsafe fn write(bytes: &[u8]) {
+        if FRAME.extend_from_slice(bytes).is_err() {
+            FRAME_COMPLETE = false;
+        }
+        ENCODER.write(bytes, rtt_write);
+    }
+}
+
+/* a minimal SEGGER RTT control block with the one up channel probe-rs reads defmt from,
+the one of defmt-rtt is private to it and comes with its global logger, so the rtt
+feature is off with host_log */
+
+const MODE_NON_BLOCKING_TRIM: usize = 1;
+
+#[repr(C)]
+struct RttHeader {
+    id: [u8; 16],
+    max_up_channels: usize,
+    max_down_channels: usize,
+    up_channel: RttChannel,
+}
+
+#[repr(C)]
+struct RttChannel {
+    name: *const u8,
+    buffer: *mut u8,
+    size: usize,
+    write: AtomicUsize,
+    read: AtomicUsize,
+    flags: AtomicUsize,
+}
+
+impl RttChannel {
+    /* never waits for the probe, what does not fit is dropped */
+    fn write_all(&self, mut bytes: &[u8]) {
+        while !bytes.is_empty() {
+            let read = self.read.load(Ordering::Relaxed);
+            let write = self.write.load(Ordering::Acquire);
+            let available = if read > write {
+                read - write - 1
+            } else if read == 0 {
+                self.size - write - 1
+            } else {
+                self.size - write
+            };
+            if available == 0 {
+                return;
+            }
+            let len = bytes.len().min(available);
+            unsafe { ptr::copy_nonoverlapping(bytes.as_ptr(), self.buffer.add(write), len) };
+            self.write
+                .store((write + len) % self.size, Ordering::Release);
+            bytes = &bytes[len..];
+        }
+    }
+}
+
+const RTT_NAME: &[u8] = b"defmt\0";
+static mut RTT_BUFFER: [u8; RTT_BUFFER_SIZE] = [0; RTT_BUFFER_SIZE];
+
+#[no_mangle]
+static mut _SEGGER_RTT: RttHeader = RttHeader {
+    id: *b"SEGGER RTT\0\0\0\0\0\0",
+    max_up_channels: 1,
+    max_down_channels: 0,
+    up_channel: RttChannel {
+        name: RTT_NAME.as_ptr(),
+        buffer: unsafe { &mut RTT_BUFFER as *mut _ as *mut u8 },
+        size: RTT_BUFFER_SIZE,
+        write: AtomicUsize::new(0),
+        read: AtomicUsize::new(0),
+        flags: AtomicUsize::new(MODE_NON_BLOCKING_TRIM),
+    },
+};
+
+fn rtt_write(bytes: &[u8]) {
+    unsafe { _SEGGER_RTT.up_channel.write_all(bytes) }
+}
@@ -1,33 +1,27 @@
-use crate::iv::{Stm32wlInterfaceVariant, SubghzSpiDevice};
-use defmt::info;
-use embassy_futures::select::*;
+use crate::iv::{self, Stm32wlInterfaceVariant, SubghzSpiDevice};
+use defmt::{info, warn};
 use embassy_stm32::crc;
 use embassy_stm32::gpio::Output;
 use embassy_stm32::peripherals;
 use embassy_stm32::spi::Spi;
-use embassy_time::{Delay, Timer};
+use embassy_time::{Delay, Duration, Instant};
+pub use gateway_core::{LoRaPacket, LoRaPacketType};
+use gateway_host_schema::{LinkStats, ModulationOverride};
+pub use gateway_host_schema::{CHECKSUM_LENGTH, HEADER_LENGTH, PACKET_LENGTH, PAYLOAD_LENGTH};
 use heapless::Vec;
 use lora_phy::mod_params::*;
 use lora_phy::sx126x::Sx126x;
 use lora_phy::LoRa;
 
-pub const PACKET_LENGTH: usize = 128;
-pub const HEADER_LENGTH: usize = 5;
-pub const CHECKSUM_LENGTH: usize = 4;
-pub const PAYLOAD_LENGTH: usize = PACKET_LENGTH - HEADER_LENGTH - CHECKSUM_LENGTH;
+const RECEIVE_SINGLE_TIMEOUT: Duration = Duration::from_secs(5);
+/* longest airtime of a full packet with the slowest modulation is well below this */
+const TRANSMIT_IRQ_TIMEOUT: Duration = Duration::from_secs(10);
 
-#[derive(defmt::Format, Debug, Clone, Copy)]
-pub enum LoRaPacketType {
-    Ping,
-    OTA,
-    SoilSensor,
-}
-
-pub struct LoRaPacket {
-    pub source: usize,
-    pub destination: usize,
-    pub packet_type: LoRaPacketType,
-    pub payload: Vec<u8, PAYLOAD_LENGTH>,
+pub struct ReceivedFrame {
+    pub data: Vec<u8, PACKET_LENGTH>,
+    pub rssi: i16,
+    pub snr: i16,
+    pub timestamp: Instant,
 }
 
 pub struct ModuleLoRa {
@@ -41,122 +35,114 @@ pub struct ModuleLoRa {
         Delay,
     >,
     pub lora_modulation: ModulationParams,
+    pub spreading_factor: SpreadingFactor,
+    pub bandwidth: Bandwidth,
+    pub coding_rate: CodingRate,
+    pub frequency_in_hz: u32,
     pub crc: crc::Crc<'static>,
     pub address: usize,
-}
-
-impl LoRaPacket {
-    pub fn new(destination: usize, packet_type: LoRaPacketType) -> Self {
-        LoRaPacket {
-            destination,
-            source: 0,
-            packet_type,
-            payload: Vec::new(),
-        }
-    }
-
-    pub fn new_with_payload(destination: usize, packet_type: LoRaPacketType, payload: Vec<u8, PAYLOAD_LENGTH>) -> Self {
-        let mut ret = Self::new(destination, packet_type);
-        ret.payload = payload;
-        ret
-    }
-
-    pub fn parse(buff: &[u8]) -> Option<Self> {
-        if buff.len() <= HEADER_LENGTH || buff.len() > PACKET_LENGTH {
-            return None;
-        }
-        Some(LoRaPacket {
-            destination: u16::from_le_bytes(buff[0..2].try_into().ok()?) as usize,
-            source: u16::from_le_bytes(buff[2..4].try_into().ok()?) as usize,
-            packet_type: match buff[4] {
-                0 => LoRaPacketType::Ping,
-                1 => LoRaPacketType::OTA,
-                2 => LoRaPacketType::SoilSensor,
-                _ => return None,
-            },
-            payload: Vec::from_slice(&buff[HEADER_LENGTH..]).ok()?,
-        })
-    }
-
-    pub fn serialize(&self, buff: &mut [u8]) -> Option<usize> {
-        let len = HEADER_LENGTH + self.payload.len();
-        if len > buff.len() {
-            return None;
-        }
-        buff[0..2].copy_from_slice(&(self.destination as u16).to_le_bytes());
-        buff[2..4].copy_from_slice(&(self.source as u16).to_le_bytes());
-        buff[4] = match self.packet_type {
-            LoRaPacketType::Ping => 0,
-            LoRaPacketType::OTA => 1,
-            LoRaPacketType::SoilSensor => 2,
-        };
-        buff[HEADER_LENGTH..HEADER_LENGTH + self.payload.len()].copy_from_slice(&self.payload);
-        Some(len)
-    }
+    pub stats: LinkStats,
+    /* set while a radio operation runs, stays set when its future is dropped midway */
+    pub(crate) operation_pending: bool,
 }
 
 impl ModuleLoRa {
     /* sets the source address automatically */
     pub async fn transmit(&mut self, packet: &mut LoRaPacket) -> Result<(), RadioError> {
+        self.transmit_with_modulation(packet, None).await
+    }
+
+    /* the modulation applies to this packet only, reception keeps using lora_modulation */
+    pub async fn transmit_with_modulation(
+        &mut self,
+        packet: &mut LoRaPacket,
+        modulation: Option<&ModulationParams>,
+    ) -> Result<(), RadioError> {
         packet.source = self.address;
         let mut buff = [0u8; PACKET_LENGTH];
         /* serialize the packet */
         let len = packet
             .serialize(buff[..PACKET_LENGTH - CHECKSUM_LENGTH].as_mut())
             .ok_or(RadioError::PayloadSizeUnexpected(packet.payload.len()))?;
+        self.transmit_frame(&mut buff, len, modulation).await
+    }
 
+    /* the data goes on air without the header, only the CRC is added,
+    so nothing but the same transmit_raw on the other side understands it */
+    pub async fn transmit_raw(&mut self, data: &[u8]) -> Result<(), RadioError> {
+        if data.len() > PACKET_LENGTH - CHECKSUM_LENGTH {
+            return Err(RadioError::PayloadSizeUnexpected(data.len()));
+        }
+        let mut buff = [0u8; PACKET_LENGTH];
+        buff[..data.len()].copy_from_slice(data);
+        self.transmit_frame(&mut buff, data.len(), None).await
+    }
+
+    async fn transmit_frame(
+        &mut self,
+        buff: &mut [u8; PACKET_LENGTH],
+        len: usize,
+        modulation: Option<&ModulationParams>,
+    ) -> Result<(), RadioError> {
         /* calculate and add the CRC at the end of the packet */
         self.crc.reset();
         let checksum = self.crc.feed_bytes(&buff[..len]).to_le_bytes();
         buff[len..len + CHECKSUM_LENGTH].copy_from_slice(&checksum);
 
+        self.recover_cancelled().await?;
+        self.operation_pending = true;
+        let modulation = modulation.unwrap_or(&self.lora_modulation);
         /* prepare for transmit */
         let mut lora_tx_params = self
             .lora
-            .create_tx_packet_params(4, false, false, false, &self.lora_modulation)
+            .create_tx_packet_params(4, false, false, false, modulation)
             .unwrap();
-        self.lora
-            .prepare_for_tx(&self.lora_modulation, 15, false)
-            .await?;
+        self.lora.prepare_for_tx(modulation, 15, false).await?;
         info!("TX len {}", len + CHECKSUM_LENGTH);
+        iv::set_irq_timeout(Some(TRANSMIT_IRQ_TIMEOUT));
         /* transmit the packet */
         self.lora
             .tx(
-                &self.lora_modulation,
+                modulation,
                 &mut lora_tx_params,
                 &buff[..len + CHECKSUM_LENGTH],
                 100_000, // is the timeout broken? https://www.thethingsnetwork.org/airtime-calculator
             )
-            .await
+            .await?;
+        self.operation_pending = false;
+        self.stats.tx += 1;
+        Ok(())
     }
 
     pub async fn receive_continuous(&mut self) -> Result<LoRaPacket, RadioError> {
-        self.receive_addressed().await
+        self.receive_addressed(RxMode::Continuous).await
     }
 
+    /* the timeout runs in the radio itself, no future needs to be dropped */
     pub async fn receive_single(&mut self) -> Result<LoRaPacket, RadioError> {
-        match select(self.receive_continuous(), Timer::after_secs(5)).await {
-            Either::First(r) => r,
-            Either::Second(_) => Err(RadioError::ReceiveTimeout),
+        let mode = RxMode::Single(self.timeout_in_symbols(RECEIVE_SINGLE_TIMEOUT));
+        match self.receive_addressed(mode).await {
+            Err(RadioError::ReceiveTimeout) => {
+                self.stats.timeouts += 1;
+                Err(RadioError::ReceiveTimeout)
+            }
+            r => r,
         }
     }
 
-    async fn receive_addressed(&mut self) -> Result<LoRaPacket, RadioError> {
+    async fn receive_addressed(&mut self, mode: RxMode) -> Result<LoRaPacket, RadioError> {
         loop {
-            match self.receive().await {
-                Ok(packet) => {
-                    if packet.destination == self.address {
-                        return Ok(packet);
-                    }
-                }
-                Err(e) => {
-                    return Err(e);
-                }
+            let frame = self.receive_frame(mode).await?;
+            if let Some(packet) = self.accept_frame(&frame)? {
+                return Ok(packet);
             }
         }
     }
 
-    async fn receive(&mut self) -> Result<LoRaPacket, RadioError> {
+    /* receives whatever the radio hears, without any checks,
+    a continuous reception ends with ReceiveTimeout when iv::interrupt_wait is called */
+    pub async fn receive_frame(&mut self, mode: RxMode) -> Result<ReceivedFrame, RadioError> {
+        self.recover_cancelled().await?;
         let lora_rx_params = self
             .lora
             .create_rx_packet_params(
@@ -168,40 +154,205 @@ impl ModuleLoRa {
                 &self.lora_modulation,
             )
             .unwrap();
+        self.operation_pending = true;
+        iv::set_irq_timeout(match mode {
+            RxMode::Single(_) => Some(RECEIVE_SINGLE_TIMEOUT + Duration::from_secs(1)),
+            _ => None,
+        });
         self.lora
-            .prepare_for_rx(
-                RxMode::Continuous,
-                &self.lora_modulation,
-                &lora_rx_params,
-                false,
-            )
+            .prepare_for_rx(mode, &self.lora_modulation, &lora_rx_params, false)
             .await?;
         let mut buff = [0u8; PACKET_LENGTH];
-        match self.lora.rx(&lora_rx_params, &mut buff).await {
-            Ok((received_len, _status)) => {
-                let len = received_len as usize;
-                info!("RX rssi {} len {}", _status.rssi, received_len);
-                if len > CHECKSUM_LENGTH + HEADER_LENGTH {
-                    let payload = &buff[..len - CHECKSUM_LENGTH];
-                    let checksum = &buff[len - CHECKSUM_LENGTH..len];
-
-                    self.crc.reset();
-                    if self.crc.feed_bytes(payload)
-                        == u32::from_le_bytes(checksum.try_into().unwrap())
-                    {
-                        Ok(LoRaPacket::parse(payload).ok_or(RadioError::Busy)?) //FIXME
-                    } else {
-                        Err(RadioError::Busy) //FIXME
-                    }
-                } else {
-                    Err(RadioError::Busy) //FIXME
-                }
+        let result = self.lora.rx(&lora_rx_params, &mut buff).await;
+        if matches!(mode, RxMode::Continuous) && matches!(result, Err(RadioError::ReceiveTimeout)) {
+            /* iv::interrupt_wait ended it, the radio still listens */
+            self.lora.enter_standby().await?;
+        }
+        self.operation_pending = false;
+        let (received_len, status) = result?;
+        info!("RX rssi {} len {}", status.rssi, received_len);
+        self.stats.rx += 1;
+        self.stats.last_rssi = status.rssi;
+        self.stats.last_snr = status.snr;
+        Ok(ReceivedFrame {
+            data: Vec::from_slice(&buff[..(received_len as usize).min(PACKET_LENGTH)]).unwrap(),
+            rssi: status.rssi,
+            snr: status.snr,
+            timestamp: Instant::now(),
+        })
+    }
+
+    /* returns the packet only when it is addressed to this module */
+    pub fn accept_frame(&mut self, frame: &ReceivedFrame) -> Result<Option<LoRaPacket>, RadioError> {
+        let packet = self.decode_frame(frame)?;
+        if packet.destination == self.address {
+            Ok(Some(packet))
+        } else {
+            self.stats.address_mismatches += 1;
+            Ok(None)
+        }
+    }
+
+    pub fn decode_frame(&mut self, frame: &ReceivedFrame) -> Result<LoRaPacket, RadioError> {
+        let len = frame.data.len();
+        if len <= CHECKSUM_LENGTH + HEADER_LENGTH {
+            self.stats.parse_failures += 1;
+            return Err(RadioError::PayloadSizeUnexpected(len));
+        }
+        if !self.check_crc(&frame.data) {
+            self.stats.crc_failures += 1;
+            return Err(RadioError::CRCErrorOnReceive);
+        }
+        match LoRaPacket::parse(&frame.data[..len - CHECKSUM_LENGTH]) {
+            Some(mut packet) => {
+                packet.rssi = frame.rssi;
+                packet.snr = frame.snr;
+                Ok(packet)
+            }
+            None => {
+                self.stats.parse_failures += 1;
+                Err(RadioError::PayloadSizeUnexpected(len))
             }
-            Err(err) => Err(err),
         }
     }
 
+    /* the checksum is stored in the last CHECKSUM_LENGTH bytes of the frame */
+    pub fn check_crc(&mut self, frame: &[u8]) -> bool {
+        if frame.len() <= CHECKSUM_LENGTH {
+            return false;
+        }
+        let (payload, checksum) = frame.split_at(frame.len() - CHECKSUM_LENGTH);
+        self.crc.reset();
+        self.crc.feed_bytes(payload) == u32::from_le_bytes(checksum.try_into().unwrap())
+    }
+
+    /* an operation cancelled midway may have left the radio in RX or TX with the IRQ armed,
+    this can only be fixed asynchronously, so it is done before the next operation */
+    async fn recover_cancelled(&mut self) -> Result<(), RadioError> {
+        if self.operation_pending {
+            warn!("previous radio operation was cancelled, entering standby");
+            self.lora.enter_standby().await?;
+            self.operation_pending = false;
+        }
+        Ok(())
+    }
+
+    pub fn create_modulation(
+        &mut self,
+        modulation: &ModulationOverride,
+    ) -> Result<ModulationParams, RadioError> {
+        let (spreading_factor, bandwidth, coding_rate) = Self::modulation_values(modulation)?;
+        self.lora.create_modulation_params(
+            spreading_factor,
+            bandwidth,
+            coding_rate,
+            self.frequency_in_hz,
+        )
+    }
+
+    /* unlike create_modulation, this one stays for every following transmission and reception */
+    pub fn set_modulation(&mut self, modulation: &ModulationOverride) -> Result<(), RadioError> {
+        let (spreading_factor, bandwidth, coding_rate) = Self::modulation_values(modulation)?;
+        self.lora_modulation = self.lora.create_modulation_params(
+            spreading_factor,
+            bandwidth,
+            coding_rate,
+            self.frequency_in_hz,
+        )?;
+        self.spreading_factor = spreading_factor;
+        self.bandwidth = bandwidth;
+        self.coding_rate = coding_rate;
+        Ok(())
+    }
+
+    pub fn modulation(&self) -> ModulationOverride {
+        ModulationOverride {
+            spreading_factor: self.spreading_factor.factor() as u8,
+            bandwidth_hz: self.bandwidth.value_in_hz(),
+            coding_rate: match self.coding_rate {
+                CodingRate::_4_5 => 5,
+                CodingRate::_4_6 => 6,
+                CodingRate::_4_7 => 7,
+                CodingRate::_4_8 => 8,
+            },
+        }
+    }
+
+    fn modulation_values(
+        modulation: &ModulationOverride,
+    ) -> Result<(SpreadingFactor, Bandwidth, CodingRate), RadioError> {
+        let spreading_factor = match modulation.spreading_factor {
+            5 => SpreadingFactor::_5,
+            6 => SpreadingFactor::_6,
+            7 => SpreadingFactor::_7,
+            8 => SpreadingFactor::_8,
+            9 => SpreadingFactor::_9,
+            10 => SpreadingFactor::_10,
+            11 => SpreadingFactor::_11,
+            12 => SpreadingFactor::_12,
+            _ => return Err(RadioError::UnavailableSpreadingFactor),
+        };
+        let bandwidth = match modulation.bandwidth_hz {
+            7_810 => Bandwidth::_7KHz,
+            10_420 => Bandwidth::_10KHz,
+            15_630 => Bandwidth::_15KHz,
+            20_830 => Bandwidth::_20KHz,
+            31_250 => Bandwidth::_31KHz,
+            41_670 => Bandwidth::_41KHz,
+            62_500 => Bandwidth::_62KHz,
+            125_000 => Bandwidth::_125KHz,
+            250_000 => Bandwidth::_250KHz,
+            500_000 => Bandwidth::_500KHz,
+            _ => return Err(RadioError::UnavailableBandwidth),
+        };
+        let coding_rate = match modulation.coding_rate {
+            5 => CodingRate::_4_5,
+            6 => CodingRate::_4_6,
+            7 => CodingRate::_4_7,
+            8 => CodingRate::_4_8,
+            _ => return Err(RadioError::UnavailableCodingRate),
+        };
+        Ok((spreading_factor, bandwidth, coding_rate))
+    }
+
+    /* the radio stopped responding rather than a single operation failing */
+    pub fn is_hung(error: &RadioError) -> bool {
+        matches!(
+            error,
+            RadioError::Busy | RadioError::Irq | RadioError::SPI | RadioError::TransmitTimeout
+        )
+    }
+
+    /* resets the radio through RCC and configures it again with the current modulation */
+    pub async fn recover(&mut self) -> Result<(), RadioError> {
+        self.stats.recoveries += 1;
+        warn!("recovering radio, recovery {}", self.stats.recoveries);
+        self.operation_pending = false;
+        iv::set_irq_timeout(None);
+        /* init goes through InterfaceVariant::reset, which pulses RCC_CSR.RFRST */
+        self.lora.init().await?;
+        self.lora_modulation = self.lora.create_modulation_params(
+            self.spreading_factor,
+            self.bandwidth,
+            self.coding_rate,
+            self.frequency_in_hz,
+        )?;
+        Ok(())
+    }
+
+    fn timeout_in_symbols(&self, timeout: Duration) -> u16 {
+        let symbol_us = (1u64 << self.spreading_factor.factor()) * 1_000_000
+            / self.bandwidth.value_in_hz() as u64;
+        (timeout.as_micros() / symbol_us).min(u16::MAX as u64) as u16
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.stats = LinkStats::default();
+    }
+
     pub async fn sleep(&mut self) -> Result<(), RadioError> {
-        self.lora.enter_standby().await
+        self.lora.enter_standby().await?;
+        self.operation_pending = false;
+        Ok(())
     }
 }
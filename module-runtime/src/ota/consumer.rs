@@ -1,14 +1,32 @@
 use crate::lora::*;
-use crate::ota::common::*;
+#[cfg(feature = "signed_ota")]
+use crate::ota::signature::*;
+use crate::ota::*;
+use crate::radio::*;
 use defmt::*;
 use heapless::Vec;
+use sha2::{Digest, Sha256};
+
+/* how much of the image is read back at once to hash it */
+const VERIFY_CHUNK_SIZE: usize = 256;
 
 pub trait OtaMemoryDelegate {
+    /* a new image of binary_size bytes is coming, what was written before is discarded */
+    async fn begin(&mut self, binary_size: usize) -> bool;
     async fn write(&mut self, valid_up_to: usize, offset: usize, data: &[u8]) -> bool;
+    /* reads back what was written, the image is hashed with it once every block arrived */
+    async fn read(&mut self, offset: usize, data: &mut [u8]) -> bool;
+    /* called after every block, keeps the session over a reset as far as the blocks are
+    safely stored, the memory decides when it is worth writing */
+    async fn save_session(&mut self, record: &OtaSessionRecord);
+    /* the session saved last, an Init of the same image resumes it */
+    async fn load_session(&mut self) -> Option<OtaSessionRecord>;
+    /* the session ended, it must not be resumed */
+    async fn clear_session(&mut self);
 }
 
 pub struct SessionParams {
-    params: OtaInitPacket,
+    record: OtaSessionRecord,
     source_address: usize,
 }
 
@@ -17,6 +35,11 @@ pub struct OtaConsumer<MemoryDelegate: OtaMemoryDelegate> {
     pub memory: MemoryDelegate,
     recent_indexes: Vec<u16, 32>,
     valid_up_to_index: u16,
+    /* None until the first Done of a complete image, repeated Dones get the same answer */
+    verification: Option<Result<(), OtaFailure>>,
+    /* images not signed by this key are rejected, all of them when it is not set */
+    #[cfg(feature = "signed_ota")]
+    public_key: Option<[u8; 32]>,
 }
 
 impl<MemoryDelegate: OtaMemoryDelegate> OtaConsumer<MemoryDelegate> {
@@ -25,36 +48,105 @@ impl<MemoryDelegate: OtaMemoryDelegate> OtaConsumer<MemoryDelegate> {
             session: None,
             recent_indexes: Vec::new(),
             valid_up_to_index: 0,
+            verification: None,
+            #[cfg(feature = "signed_ota")]
+            public_key: None,
             memory,
         }
     }
 
+    /* usually with ota_public_key!() */
+    #[cfg(feature = "signed_ota")]
+    pub fn with_public_key(mut self, public_key: [u8; 32]) -> Self {
+        self.public_key = Some(public_key);
+        self
+    }
+
     async fn handle_init(
         &mut self,
-        lora: &mut ModuleLoRa,
-        session: SessionParams,
+        lora: &mut RadioClient,
+        params: OtaInitPacket,
+        source_address: usize,
     ) -> Result<(), OtaError> {
         info!("init download");
-        let source = session.source_address;
-        self.session = Some(session);
+        let rejected = matches!(self.verification, Some(Err(_)));
         self.recent_indexes.clear();
-        self.valid_up_to_index = 0;
-        lora_transmit(lora, source, &OtaPacket::InitAck).await
+        self.verification = None;
+        /* no InitAck in the errors, the producer tries again */
+        if params.block_count as usize > OTA_MAX_BLOCKS {
+            warn!(
+                "{} blocks are too many, use larger ones",
+                params.block_count
+            );
+            self.session = None;
+            return Err(OtaError::MemoryWriteFailed);
+        }
+        /* the same image as the session still running, or as the one saved before a reset */
+        let resumed = match self.session.take() {
+            Some(s) if s.record.is_same_image(&params) && !rejected => Some(s.record),
+            _ => match self.memory.load_session().await {
+                Some(r) if r.is_same_image(&params) => Some(r),
+                _ => None,
+            },
+        };
+        let response = match resumed {
+            Some(mut record) => {
+                record.params = params;
+                let first_missing = record.first_missing();
+                info!("resuming download at block {}", first_missing);
+                let response = OtaPacket::InitResumed(OtaResumePacket {
+                    first_missing_index: first_missing,
+                    received_indexes: (first_missing..record.params.block_count)
+                        .filter(|i| record.is_received(*i))
+                        .take(32)
+                        .collect(),
+                });
+                self.session = Some(SessionParams {
+                    record,
+                    source_address,
+                });
+                response
+            }
+            None => {
+                if !self.memory.begin(params.binary_size as usize).await {
+                    warn!("memory not ready for the image");
+                    return Err(OtaError::MemoryWriteFailed);
+                }
+                self.session = Some(SessionParams {
+                    record: OtaSessionRecord::new(params),
+                    source_address,
+                });
+                OtaPacket::InitAck
+            }
+        };
+        self.update_valid_up_to();
+        lora_transmit(lora, source_address, &response).await
+    }
+
+    /* the last of the blocks received without a gap, 0 also before block 0 arrived */
+    fn update_valid_up_to(&mut self) {
+        self.valid_up_to_index = match &self.session {
+            Some(s) => s.record.first_missing().saturating_sub(1),
+            None => 0,
+        };
     }
 
     async fn handle_data(
         &mut self,
-        lora: &mut ModuleLoRa,
+        lora: &mut RadioClient,
         data: OtaDataPacket,
     ) -> Result<(), OtaError> {
         info!("data: index {}", data.index);
-        let session = match &self.session {
+        let session = match &mut self.session {
             Some(p) => p,
             None => {
                 return Err(OtaError::InvalidPacketType);
             }
         };
-        let block_size = session.params.block_size as usize;
+        if data.index >= session.record.params.block_count {
+            return Err(OtaError::InvalidPacketType);
+        }
+        let block_size = session.record.params.block_size as usize;
         let begin = block_size * data.index as usize;
         if self
             .memory
@@ -65,6 +157,10 @@ impl<MemoryDelegate: OtaMemoryDelegate> OtaConsumer<MemoryDelegate> {
             )
             .await
         {
+            // a block written after Done changes what was verified
+            self.verification = None;
+            session.record.set_received(data.index, true);
+            self.memory.save_session(&session.record).await;
             // update recent_indexes with the new index
             if !self.recent_indexes.contains(&data.index) {
                 if self.recent_indexes.is_full() {
@@ -72,26 +168,16 @@ impl<MemoryDelegate: OtaMemoryDelegate> OtaConsumer<MemoryDelegate> {
                 }
                 let _ = self.recent_indexes.push(data.index);
             }
-            // update valid_up_to_index
-            for i in self.valid_up_to_index..u16::MAX {
-                if !self.recent_indexes.contains(&i) {
-                    break;
-                }
-                self.valid_up_to_index = i;
-            }
         } else {
             warn!("write failed");
         }
+        let source = session.source_address;
+        self.update_valid_up_to();
         // send the data status
-        lora_transmit(
-            lora,
-            session.source_address,
-            &OtaPacket::Status(self.get_status()),
-        )
-        .await
+        lora_transmit(lora, source, &OtaPacket::Status(self.get_status())).await
     }
 
-    async fn handle_done(&mut self, lora: &mut ModuleLoRa) -> Result<(), OtaError> {
+    async fn handle_done(&mut self, lora: &mut RadioClient) -> Result<(), OtaError> {
         let session = match &self.session {
             Some(p) => p,
             None => {
@@ -99,44 +185,79 @@ impl<MemoryDelegate: OtaMemoryDelegate> OtaConsumer<MemoryDelegate> {
             }
         };
         info!("done download");
-        if self.is_done() {
-            lora_transmit(lora, session.source_address, &OtaPacket::DoneAck).await
-        } else {
-            lora_transmit(
-                lora,
-                session.source_address,
-                &OtaPacket::Status(self.get_status()),
-            )
-            .await
+        let source = session.source_address;
+        if !self.is_done() {
+            return lora_transmit(lora, source, &OtaPacket::Status(self.get_status())).await;
         }
+        let verification = match self.verification {
+            Some(v) => v,
+            None => {
+                let v = self.verify().await;
+                match v {
+                    Ok(()) => info!("image verified"),
+                    Err(e) => {
+                        warn!("image rejected: {}", e);
+                        /* the next Init starts over */
+                        self.memory.clear_session().await;
+                    }
+                }
+                self.verification = Some(v);
+                v
+            }
+        };
+        let response = match verification {
+            Ok(()) => OtaPacket::DoneAck,
+            Err(e) => OtaPacket::DoneFailed(e),
+        };
+        lora_transmit(lora, source, &response).await
+    }
+
+    /* reading the image back also catches what went wrong on the way to the memory */
+    async fn verify(&mut self) -> Result<(), OtaFailure> {
+        let params = match &self.session {
+            Some(s) => &s.record.params,
+            None => return Err(OtaFailure::ReadFailed),
+        };
+        let size = params.binary_size as usize;
+        let mut hasher = Sha256::new();
+        let mut buffer = [0u8; VERIFY_CHUNK_SIZE];
+        let mut offset = 0;
+        while offset < size {
+            let len = (size - offset).min(buffer.len());
+            if !self.memory.read(offset, &mut buffer[..len]).await {
+                return Err(OtaFailure::ReadFailed);
+            }
+            hasher.update(&buffer[..len]);
+            offset += len;
+        }
+        if hasher.finalize().as_slice() != params.binary_sha256 {
+            return Err(OtaFailure::HashMismatch);
+        }
+        #[cfg(feature = "signed_ota")]
+        verify_signature(self.public_key.as_ref(), params)?;
+        Ok(())
     }
 
     async fn handle_abort(
         &mut self,
-        lora: &mut ModuleLoRa,
+        lora: &mut RadioClient,
         source_address: usize,
     ) -> Result<(), OtaError> {
         info!("abort download");
         self.session = None;
+        self.memory.clear_session().await;
         lora_transmit(lora, source_address, &OtaPacket::AbortAck).await
     }
 
     pub async fn process_message(
         &mut self,
-        lora: &mut ModuleLoRa,
+        lora: &mut RadioClient,
         packet: LoRaPacket,
     ) -> Result<(), OtaError> {
-        match postcard::from_bytes::<OtaPacket>(&packet.payload).map_err(err::deserialize)? {
-            OtaPacket::Init(init) => {
-                self.handle_init(
-                    lora,
-                    SessionParams {
-                        params: init,
-                        source_address: packet.source,
-                    },
-                )
-                .await
-            }
+        match postcard::from_bytes::<OtaPacket>(&packet.payload)
+            .map_err(|_| OtaError::Deserialize)?
+        {
+            OtaPacket::Init(init) => self.handle_init(lora, init, packet.source).await,
             OtaPacket::Data(data) => self.handle_data(lora, data).await,
             OtaPacket::InitAck => return Err(OtaError::InvalidPacketType),
             OtaPacket::Status(_) => return Err(OtaError::InvalidPacketType),
@@ -144,6 +265,8 @@ impl<MemoryDelegate: OtaMemoryDelegate> OtaConsumer<MemoryDelegate> {
             OtaPacket::DoneAck => return Err(OtaError::InvalidPacketType),
             OtaPacket::Abort => self.handle_abort(lora, packet.source).await,
             OtaPacket::AbortAck => return Err(OtaError::InvalidPacketType),
+            OtaPacket::DoneFailed(_) => return Err(OtaError::InvalidPacketType),
+            OtaPacket::InitResumed(_) => return Err(OtaError::InvalidPacketType),
         }
     }
 
@@ -154,13 +277,17 @@ impl<MemoryDelegate: OtaMemoryDelegate> OtaConsumer<MemoryDelegate> {
         }
     }
 
+    /* every block arrived, the image may still be rejected */
     pub fn is_done(&self) -> bool {
-        let block_count = match &self.session {
-            Some(p) => p.params.block_count,
-            None => {
-                return false;
-            }
-        };
-        self.valid_up_to_index + 1 == block_count
+        match &self.session {
+            Some(s) => s.record.first_missing() == s.record.params.block_count,
+            None => false,
+        }
+    }
+
+    /* the image arrived, its SHA-256 matches and with signed_ota its signature too,
+    only then it may be marked for swap */
+    pub fn is_verified(&self) -> bool {
+        matches!(self.verification, Some(Ok(())))
     }
 }
@@ -0,0 +1,114 @@
+use crate::ota::consumer::OtaMemoryDelegate;
+use defmt::*;
+use embassy_boot::{FirmwareUpdater, FirmwareUpdaterError, State};
+use embedded_storage_async::nor_flash::NorFlash;
+use gateway_core::OtaSessionRecord;
+use module_dfu::{DfuPages, FirmwareWriter};
+
+/*
+OtaMemoryDelegate writing the image to the DFU partition, from where embassy-boot
+swaps it in once it is marked updated.
+The pages of the image and the session kept in the last page are handled by DfuPages
+of module-dfu, it only uses the flash traits and is tested there on a RAM flash. A page
+goes to flash through FirmwareUpdater::write_firmware.
+*/
+
+struct Updater<'d, DFU: NorFlash, STATE: NorFlash>(FirmwareUpdater<'d, DFU, STATE>);
+
+impl<'d, DFU: NorFlash, STATE: NorFlash> FirmwareWriter for Updater<'d, DFU, STATE> {
+    type Error = FirmwareUpdaterError;
+
+    async fn write_firmware(&mut self, offset: usize, data: &[u8]) -> Result<(), Self::Error> {
+        self.0.write_firmware(offset, data).await
+    }
+}
+
+pub struct DfuMemory<'d, DFU: NorFlash, STATE: NorFlash, const PAGE_SIZE: usize> {
+    pages: DfuPages<Updater<'d, DFU, STATE>, DFU, PAGE_SIZE>,
+}
+
+impl<'d, DFU: NorFlash, STATE: NorFlash, const PAGE_SIZE: usize>
+    DfuMemory<'d, DFU, STATE, PAGE_SIZE>
+{
+    /* dfu is a second handle of the partition of the updater, which cannot read it back */
+    pub fn new(updater: FirmwareUpdater<'d, DFU, STATE>, dfu: DFU) -> Self {
+        DfuMemory {
+            pages: DfuPages::new(Updater(updater), dfu),
+        }
+    }
+
+    /* writes what is left in RAM and hands the image to the bootloader */
+    pub async fn mark_updated(&mut self) -> bool {
+        if !self.pages.flush().await {
+            return false;
+        }
+        /* the image is complete, a later Init of it must not resume into the swapped partition */
+        self.pages.clear_session().await;
+        match self.pages.writer.0.mark_updated().await {
+            Ok(()) => true,
+            Err(e) => {
+                error!("marking the update failed: {}", Debug2Format(&e));
+                false
+            }
+        }
+    }
+
+    /* the bootloader just swapped this firmware in, it is reverted on the next reset
+    unless mark_booted confirms it */
+    pub async fn is_on_trial(&mut self) -> bool {
+        match self.pages.writer.0.get_state().await {
+            Ok(State::Swap) => true,
+            Ok(_) => false,
+            Err(e) => {
+                error!("reading the update state failed: {}", Debug2Format(&e));
+                false
+            }
+        }
+    }
+
+    pub async fn mark_booted(&mut self) -> bool {
+        match self.pages.writer.0.mark_booted().await {
+            Ok(()) => true,
+            Err(e) => {
+                error!("marking the boot failed: {}", Debug2Format(&e));
+                false
+            }
+        }
+    }
+
+    /* resets into the bootloader, which swaps the image in, returns only when marking failed */
+    pub async fn activate(&mut self) {
+        if self.mark_updated().await {
+            info!("image marked as updated, resetting");
+            cortex_m::peripheral::SCB::sys_reset();
+        }
+    }
+}
+
+impl<'d, DFU: NorFlash, STATE: NorFlash, const PAGE_SIZE: usize> OtaMemoryDelegate
+    for DfuMemory<'d, DFU, STATE, PAGE_SIZE>
+{
+    async fn begin(&mut self, binary_size: usize) -> bool {
+        self.pages.begin(binary_size).await
+    }
+
+    async fn write(&mut self, _valid_up_to: usize, offset: usize, data: &[u8]) -> bool {
+        self.pages.write(offset, data).await
+    }
+
+    async fn read(&mut self, offset: usize, data: &mut [u8]) -> bool {
+        self.pages.read(offset, data).await
+    }
+
+    async fn save_session(&mut self, record: &OtaSessionRecord) {
+        self.pages.save_session(record).await
+    }
+
+    async fn load_session(&mut self) -> Option<OtaSessionRecord> {
+        self.pages.load_session().await
+    }
+
+    async fn clear_session(&mut self) {
+        self.pages.clear_session().await
+    }
+}
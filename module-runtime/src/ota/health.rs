@@ -0,0 +1,154 @@
+use crate::lora::*;
+use crate::ota::dfu::DfuMemory;
+use crate::radio::*;
+use defmt::*;
+use embassy_time::{Duration, Instant, Timer};
+use embedded_storage_async::nor_flash::NorFlash;
+use gateway_host_schema::BootReport;
+use heapless::{String, Vec};
+
+/*
+After an update embassy-boot runs the new firmware on trial: unless it calls mark_booted
+before the next reset, the bootloader swaps the previous firmware back.
+HealthChecks makes that decision for the application, which names its checks, passes
+or fails them as it finds out and calls conclude after each step:
+    every check passed: the firmware is confirmed and the gateway gets BootReport::Confirmed
+    a check failed or the timeout passed: the gateway gets BootReport::RollingBack,
+        then the node resets into the previous firmware
+A panic or a hang resets before mark_booted too, that rolls back without a report.
+The report goes to the gateway address the application learned, usually the source of
+the first packet from the gateway, a node that never heard it rolls back silently.
+Outside of a trial there is nothing to check and every call returns immediately.
+*/
+
+pub const MAX_HEALTH_CHECKS: usize = 8;
+
+pub struct HealthChecks {
+    trial: bool,
+    pending: Vec<&'static str, MAX_HEALTH_CHECKS>,
+    failed: Option<&'static str>,
+    deadline: Instant,
+    gateway: Option<usize>,
+}
+
+impl HealthChecks {
+    pub async fn new<DFU: NorFlash, STATE: NorFlash, const PAGE_SIZE: usize>(
+        memory: &mut DfuMemory<'_, DFU, STATE, PAGE_SIZE>,
+        checks: &[&'static str],
+        timeout: Duration,
+    ) -> Self {
+        let trial = memory.is_on_trial().await;
+        if trial {
+            info!(
+                "new firmware on trial, {} checks within {} s",
+                checks.len(),
+                timeout.as_secs()
+            );
+        }
+        HealthChecks {
+            trial,
+            pending: match trial {
+                true => Vec::from_slice(checks).unwrap(),
+                false => Vec::new(),
+            },
+            failed: None,
+            deadline: Instant::now() + timeout,
+            gateway: None,
+        }
+    }
+
+    pub fn is_on_trial(&self) -> bool {
+        self.trial
+    }
+
+    pub fn pass(&mut self, check: &'static str) {
+        if let Some(i) = self.pending.iter().position(|c| *c == check) {
+            info!("health check {} passed", check);
+            self.pending.swap_remove(i);
+        }
+    }
+
+    /* the first failure decides, the firmware rolls back at the next conclude */
+    pub fn fail(&mut self, check: &'static str) {
+        if self.trial && self.failed.is_none() {
+            warn!("health check {} failed", check);
+            self.failed = Some(check);
+        }
+    }
+
+    /* where the outcome is reported */
+    pub fn set_gateway(&mut self, address: usize) {
+        self.gateway = Some(address);
+    }
+
+    /* completes once the timeout of a trial passed, never otherwise, for a select next
+    to the work of the application */
+    pub async fn timeout(&self) {
+        match self.trial {
+            true => Timer::at(self.deadline).await,
+            false => core::future::pending().await,
+        }
+    }
+
+    pub async fn conclude<DFU: NorFlash, STATE: NorFlash, const PAGE_SIZE: usize>(
+        &mut self,
+        memory: &mut DfuMemory<'_, DFU, STATE, PAGE_SIZE>,
+        lora: &mut RadioClient,
+    ) {
+        if !self.trial {
+            return;
+        }
+        if self.failed.is_none() && self.pending.is_empty() {
+            /* a failed mark is tried again on the next call */
+            if memory.mark_booted().await {
+                info!("new firmware confirmed");
+                self.trial = false;
+                self.report(lora, BootReport::Confirmed).await;
+            }
+            return;
+        }
+        let (check, timed_out) = match self.failed {
+            Some(c) => (c, false),
+            None if Instant::now() >= self.deadline => (self.pending[0], true),
+            None => return,
+        };
+        warn!("rolling back, health check {} did not pass", check);
+        let mut name = String::new();
+        for c in check.chars() {
+            if name.push(c).is_err() {
+                break;
+            }
+        }
+        self.report(
+            lora,
+            BootReport::RollingBack {
+                check: name,
+                timed_out,
+            },
+        )
+        .await;
+        cortex_m::peripheral::SCB::sys_reset();
+    }
+
+    async fn report(&self, lora: &mut RadioClient, report: BootReport) {
+        let gateway = match self.gateway {
+            Some(g) => g,
+            None => {
+                warn!("the gateway was not heard, the boot is not reported");
+                return;
+            }
+        };
+        let mut packet = LoRaPacket::new(gateway, LoRaPacketType::Boot);
+        match postcard::to_vec(&report) {
+            Ok(payload) => {
+                packet.payload = payload;
+                if let Err(e) = lora.transmit(&mut packet).await {
+                    error!("lora tx error: {}", e)
+                }
+            }
+            Err(e) => {
+                error!("failed to serialize boot report: {}", e)
+            }
+        }
+    }
+}
@@ -1,7 +1,17 @@
-mod common;
 mod consumer;
-mod producer;
+mod dfu;
+mod health;
+#[cfg(feature = "signed_ota")]
+mod signature;
 
-pub use common::*;
+/* the OTA protocol is shared with the gateway emulator */
+pub use gateway_core::{
+    lora_transmit, lora_transmit_until_response, OtaDataPacket, OtaError, OtaFailure,
+    OtaInitPacket, OtaPacket, OtaProducer, OtaProducerState, OtaResumePacket, OtaSessionRecord,
+    OtaStatusPacket, OTA_MAX_BLOCKS,
+};
 pub use consumer::*;
-pub use producer::*;
+pub use dfu::*;
+pub use health::*;
+#[cfg(feature = "signed_ota")]
+pub use signature::*;
@@ -0,0 +1,62 @@
+use ed25519_dalek::{Signature, VerifyingKey};
+use gateway_core::{OtaFailure, OtaInitPacket};
+use gateway_host_schema::ota_manifest;
+
+/*
+With signed_ota the consumer activates only images signed by the key the
+application was built with. The host signs ota_manifest, which holds the size
+and SHA-256 of the image, so a valid signature over it covers the image too,
+once its hash was checked.
+*/
+
+/// Builds the key at compile time from the `OTA_PUBLIC_KEY` environment
+/// variable, 64 hex digits as printed by `gateway-cli ota keygen`.
+#[macro_export]
+macro_rules! ota_public_key {
+    () => {{
+        const KEY: [u8; 32] = $crate::parse_public_key(env!("OTA_PUBLIC_KEY"));
+        KEY
+    }};
+}
+
+const fn hex_digit(c: u8) -> u8 {
+    match c {
+        b'0'..=b'9' => c - b'0',
+        b'a'..=b'f' => c - b'a' + 10,
+        b'A'..=b'F' => c - b'A' + 10,
+        _ => panic!("OTA_PUBLIC_KEY must be 64 hex digits"),
+    }
+}
+
+/// Use [`ota_public_key!`] instead, it fails the build on a malformed key.
+pub const fn parse_public_key(hex: &str) -> [u8; 32] {
+    let hex = hex.as_bytes();
+    if hex.len() != 64 {
+        panic!("OTA_PUBLIC_KEY must be 64 hex digits");
+    }
+    let mut key = [0u8; 32];
+    let mut i = 0;
+    while i < key.len() {
+        key[i] = (hex_digit(hex[2 * i]) << 4) | hex_digit(hex[2 * i + 1]);
+        i += 1;
+    }
+    key
+}
+
+pub(super) fn verify_signature(
+    public_key: Option<&[u8; 32]>,
+    params: &OtaInitPacket,
+) -> Result<(), OtaFailure> {
+    let (public_key, signature) = match (public_key, params.signature.as_ref()) {
+        (Some(k), Some(s)) => (k, s),
+        _ => return Err(OtaFailure::Unsigned),
+    };
+    let signature: &[u8; 64] = signature
+        .as_slice()
+        .try_into()
+        .map_err(|_| OtaFailure::SignatureInvalid)?;
+    let key = VerifyingKey::from_bytes(public_key).map_err(|_| OtaFailure::SignatureInvalid)?;
+    let manifest = ota_manifest(params.binary_size, &params.binary_sha256);
+    key.verify_strict(&manifest, &Signature::from_bytes(signature))
+        .map_err(|_| OtaFailure::SignatureInvalid)
+}
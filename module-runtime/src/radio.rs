@@ -0,0 +1,447 @@
+use crate::iv;
+use crate::lora::*;
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use defmt::*;
+use embassy_executor::Spawner;
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::blocking_mutex::Mutex as BlockingMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::mutex::Mutex;
+use embassy_sync::pubsub::{ImmediatePublisher, PubSubChannel, Subscriber, WaitResult};
+use embassy_sync::signal::Signal;
+use embassy_time::Timer;
+use gateway_core::RadioLink;
+use gateway_host_schema::{LinkStats, ModulationOverride, SniffedFrame};
+use heapless::Vec;
+use lora_phy::mod_params::{RadioError, RxMode};
+
+pub const RADIO_MAX_CLIENTS: usize = 4;
+const RADIO_RX_QUEUE_LENGTH: usize = 4;
+const RADIO_SNIFFER_QUEUE_LENGTH: usize = 4;
+/* what transmit_raw fits into a frame next to the CRC */
+pub const RAW_DATA_LENGTH: usize = PACKET_LENGTH - CHECKSUM_LENGTH;
+
+/* decides which of the received packets are delivered to a client */
+pub type RxFilter = fn(&LoRaPacket) -> bool;
+
+pub fn accept_all(_: &LoRaPacket) -> bool {
+    true
+}
+
+/* only what needs the radio itself, each of them interrupts the reception,
+the rest is shared state the handles read and change directly */
+enum RadioRequest {
+    Transmit(LoRaPacket, Option<ModulationOverride>),
+    TransmitRaw(Vec<u8, RAW_DATA_LENGTH>),
+    /* reception restarts with it */
+    SetModulation(ModulationOverride),
+    /* ModuleLoRa::receive_single, the packet is published like the others */
+    ReceiveSingle,
+}
+
+/* requests and responses carry a sequence number, a response to a request whose client
+was dropped while it waited is recognised and ignored by the next client */
+static REQUESTS: Channel<ThreadModeRawMutex, (u32, RadioRequest), 1> = Channel::new();
+static RESPONSE: Signal<ThreadModeRawMutex, (u32, Result<(), RadioError>)> = Signal::new();
+static SEQUENCE: AtomicU32 = AtomicU32::new(0);
+/* held by a client from sending the request until the response arrives */
+static REQUEST_LOCK: Mutex<ThreadModeRawMutex, ()> = Mutex::new(());
+static RECEIVED: PubSubChannel<
+    ThreadModeRawMutex,
+    LoRaPacket,
+    RADIO_RX_QUEUE_LENGTH,
+    RADIO_MAX_CLIENTS,
+    1,
+> = PubSubChannel::new();
+type ReceivedPublisher = ImmediatePublisher<
+    'static,
+    ThreadModeRawMutex,
+    LoRaPacket,
+    RADIO_RX_QUEUE_LENGTH,
+    RADIO_MAX_CLIENTS,
+    1,
+>;
+/* every frame the radio hears while the sniffer is enabled, dropped when nobody keeps up */
+static SNIFFED: Channel<ThreadModeRawMutex, SniffedFrame, RADIO_SNIFFER_QUEUE_LENGTH> =
+    Channel::new();
+/* ModuleLoRa::address follows it, it is taken before every transmission and received frame */
+static ADDRESS: AtomicUsize = AtomicUsize::new(0);
+static SNIFFER: AtomicBool = AtomicBool::new(false);
+/* the counters of ModuleLoRa are added here after every radio operation */
+static STATS: BlockingMutex<ThreadModeRawMutex, RefCell<LinkStats>> =
+    BlockingMutex::new(RefCell::new(LinkStats {
+        tx: 0,
+        rx: 0,
+        crc_failures: 0,
+        parse_failures: 0,
+        address_mismatches: 0,
+        timeouts: 0,
+        retries: 0,
+        recoveries: 0,
+        last_rssi: 0,
+        last_snr: 0,
+    }));
+/* mirrors ModuleLoRa::modulation, set before the radio task starts */
+static MODULATION: BlockingMutex<ThreadModeRawMutex, RefCell<ModulationOverride>> =
+    BlockingMutex::new(RefCell::new(ModulationOverride {
+        spreading_factor: 0,
+        bandwidth_hz: 0,
+        coding_rate: 0,
+    }));
+
+/* adds what ModuleLoRa counted since the previous call to STATS */
+fn publish_stats(lora: &ModuleLoRa, published: &mut LinkStats) {
+    let new = &lora.stats;
+    STATS.lock(|stats| {
+        let mut stats = stats.borrow_mut();
+        stats.tx += new.tx.wrapping_sub(published.tx);
+        stats.rx += new.rx.wrapping_sub(published.rx);
+        stats.crc_failures += new.crc_failures.wrapping_sub(published.crc_failures);
+        stats.parse_failures += new.parse_failures.wrapping_sub(published.parse_failures);
+        stats.address_mismatches += new
+            .address_mismatches
+            .wrapping_sub(published.address_mismatches);
+        stats.timeouts += new.timeouts.wrapping_sub(published.timeouts);
+        stats.retries += new.retries.wrapping_sub(published.retries);
+        stats.recoveries += new.recoveries.wrapping_sub(published.recoveries);
+        if new.rx != published.rx {
+            stats.last_rssi = new.last_rssi;
+            stats.last_snr = new.last_snr;
+        }
+    });
+    *published = new.clone();
+}
+
+fn sniff(lora: &mut ModuleLoRa, frame: &ReceivedFrame) -> SniffedFrame {
+    SniffedFrame {
+        data: frame.data.clone(),
+        crc_ok: lora.check_crc(&frame.data),
+        header: LoRaPacket::parse_header(&frame.data),
+        rssi: frame.rssi,
+        snr: frame.snr,
+        timestamp_ms: frame.timestamp.as_millis(),
+    }
+}
+
+/* re-initialises a radio that stopped responding, keeps trying until it comes back */
+async fn supervise(lora: &mut ModuleLoRa, error: &RadioError) {
+    if !ModuleLoRa::is_hung(error) {
+        return;
+    }
+    while let Err(e) = lora.recover().await {
+        error!("radio recovery failed: {}", e);
+        Timer::after_secs(1).await;
+    }
+    info!("radio recovered");
+}
+
+async fn handle_request(
+    lora: &mut ModuleLoRa,
+    received: &ReceivedPublisher,
+    request: RadioRequest,
+) -> Result<(), RadioError> {
+    lora.address = ADDRESS.load(Ordering::Relaxed);
+    match request {
+        RadioRequest::Transmit(mut packet, modulation) => {
+            let result = match modulation {
+                Some(m) => match lora.create_modulation(&m) {
+                    Ok(params) => {
+                        lora.transmit_with_modulation(&mut packet, Some(&params))
+                            .await
+                    }
+                    Err(e) => Err(e),
+                },
+                None => lora.transmit(&mut packet).await,
+            };
+            if let Err(e) = &result {
+                supervise(lora, e).await;
+            }
+            result
+        }
+        RadioRequest::TransmitRaw(data) => {
+            let result = lora.transmit_raw(&data).await;
+            if let Err(e) = &result {
+                supervise(lora, e).await;
+            }
+            result
+        }
+        RadioRequest::SetModulation(m) => {
+            let result = lora.set_modulation(&m);
+            let modulation = lora.modulation();
+            MODULATION.lock(|m| *m.borrow_mut() = modulation);
+            result
+        }
+        RadioRequest::ReceiveSingle => match lora.receive_single().await {
+            Ok(packet) => {
+                received.publish_immediate(packet);
+                Ok(())
+            }
+            Err(e) => {
+                supervise(lora, &e).await;
+                Err(e)
+            }
+        },
+    }
+}
+
+/* the reception is never dropped midway, a request ends it through iv::interrupt_wait
+and receive_frame puts the radio in standby before the request gets it */
+#[embassy_executor::task]
+async fn radio_task(mut lora: ModuleLoRa) {
+    let received = RECEIVED.immediate_publisher();
+    let mut published = lora.stats.clone();
+    loop {
+        match lora.receive_frame(RxMode::Continuous).await {
+            Ok(frame) => {
+                if SNIFFER.load(Ordering::Relaxed)
+                    && SNIFFED.try_send(sniff(&mut lora, &frame)).is_err()
+                {
+                    warn!("sniffer queue full, frame dropped");
+                }
+                lora.address = ADDRESS.load(Ordering::Relaxed);
+                match lora.accept_frame(&frame) {
+                    /* never block the radio on a slow client, it will see a lag instead */
+                    Ok(Some(packet)) => received.publish_immediate(packet),
+                    Ok(None) => {}
+                    Err(e) => warn!("dropped frame: {}", e),
+                }
+            }
+            /* interrupted by a request */
+            Err(RadioError::ReceiveTimeout) => {}
+            Err(e) => {
+                error!("failed lora receive: {}", e);
+                supervise(&mut lora, &e).await;
+            }
+        }
+        /* a request sent from now on interrupts the next reception */
+        iv::reset_interrupt();
+        while let Ok((sequence, request)) = REQUESTS.try_receive() {
+            let response = handle_request(&mut lora, &received, request).await;
+            RESPONSE.signal((sequence, response));
+        }
+        publish_stats(&lora, &mut published);
+    }
+}
+
+/// Handle to the radio task, which owns the [`ModuleLoRa`] and keeps it
+/// listening whenever no transmission is requested.
+#[derive(Clone, Copy)]
+pub struct Radio {
+    _private: (),
+}
+
+impl Radio {
+    pub fn start(spawner: &Spawner, lora: ModuleLoRa) -> Radio {
+        ADDRESS.store(lora.address, Ordering::Relaxed);
+        MODULATION.lock(|m| *m.borrow_mut() = lora.modulation());
+        spawner.spawn(radio_task(lora)).unwrap();
+        Radio { _private: () }
+    }
+
+    pub fn address(&self) -> usize {
+        ADDRESS.load(Ordering::Relaxed)
+    }
+
+    /* panics when more than RADIO_MAX_CLIENTS clients are created */
+    pub fn client(&self, filter: RxFilter) -> RadioClient {
+        RadioClient {
+            radio: *self,
+            filter,
+            received: RECEIVED.subscriber().unwrap(),
+        }
+    }
+
+    async fn request(&self, request: RadioRequest) -> Result<(), RadioError> {
+        let _lock = REQUEST_LOCK.lock().await;
+        let sequence = SEQUENCE.fetch_add(1, Ordering::Relaxed);
+        REQUESTS.send((sequence, request)).await;
+        iv::interrupt_wait();
+        loop {
+            let (s, response) = RESPONSE.wait().await;
+            /* otherwise left behind by a cancelled request */
+            if s == sequence {
+                return response;
+            }
+        }
+    }
+
+    /* sets the source address automatically */
+    pub async fn transmit(&self, packet: &mut LoRaPacket) -> Result<(), RadioError> {
+        self.transmit_with_modulation(packet, None).await
+    }
+
+    /* the modulation applies to this packet only */
+    pub async fn transmit_with_modulation(
+        &self,
+        packet: &mut LoRaPacket,
+        modulation: Option<ModulationOverride>,
+    ) -> Result<(), RadioError> {
+        packet.source = self.address();
+        self.request(RadioRequest::Transmit(packet.clone(), modulation))
+            .await
+    }
+
+    /* see ModuleLoRa::transmit_raw, the other side gets it from the sniffer */
+    pub async fn transmit_raw(&self, data: &[u8]) -> Result<(), RadioError> {
+        let data = Vec::from_slice(data).map_err(|_| RadioError::PayloadSizeUnexpected(data.len()))?;
+        self.request(RadioRequest::TransmitRaw(data)).await
+    }
+
+    pub fn stats(&self) -> LinkStats {
+        STATS.lock(|s| s.borrow().clone())
+    }
+
+    pub fn reset_stats(&self) {
+        STATS.lock(|s| *s.borrow_mut() = LinkStats::default());
+    }
+
+    pub fn record_retry(&self) {
+        STATS.lock(|s| s.borrow_mut().retries += 1);
+    }
+
+    /* when enabled, every received frame is also queued for receive_sniffed */
+    pub fn set_sniffer(&self, enabled: bool) {
+        info!("sniffer enabled: {}", enabled);
+        SNIFFER.store(enabled, Ordering::Relaxed);
+    }
+
+    pub async fn receive_sniffed(&self) -> SniffedFrame {
+        SNIFFED.receive().await
+    }
+
+    /* packets for the old address are not accepted anymore */
+    pub fn set_address(&self, address: usize) {
+        info!("address changed to {}", address);
+        ADDRESS.store(address, Ordering::Relaxed);
+    }
+
+    /* for both transmission and reception, until changed again */
+    pub async fn set_modulation(&self, modulation: ModulationOverride) -> Result<(), RadioError> {
+        self.request(RadioRequest::SetModulation(modulation)).await
+    }
+
+    pub fn modulation(&self) -> ModulationOverride {
+        MODULATION.lock(|m| m.borrow().clone())
+    }
+}
+
+/// A single user of the radio, it receives every packet accepted by its
+/// filter independently of the other clients.
+pub struct RadioClient {
+    radio: Radio,
+    filter: RxFilter,
+    received: Subscriber<
+        'static,
+        ThreadModeRawMutex,
+        LoRaPacket,
+        RADIO_RX_QUEUE_LENGTH,
+        RADIO_MAX_CLIENTS,
+        1,
+    >,
+}
+
+impl RadioClient {
+    pub fn radio(&self) -> Radio {
+        self.radio
+    }
+
+    pub fn address(&self) -> usize {
+        self.radio.address()
+    }
+
+    pub async fn transmit(&mut self, packet: &mut LoRaPacket) -> Result<(), RadioError> {
+        self.radio.transmit(packet).await
+    }
+
+    pub async fn receive_continuous(&mut self) -> LoRaPacket {
+        loop {
+            match self.received.next_message().await {
+                WaitResult::Message(packet) => {
+                    if (self.filter)(&packet) {
+                        return packet;
+                    }
+                }
+                WaitResult::Lagged(n) => {
+                    warn!("radio client lagged by {} packets", n);
+                }
+            }
+        }
+    }
+
+    /* the next packet accepted by the filter, the radio task receives in RxMode::Single
+    until it comes, each time with the timeout of ModuleLoRa::receive_single, the other
+    clients get what it receives too but cannot transmit meanwhile */
+    pub async fn receive_single(&mut self) -> Result<LoRaPacket, RadioError> {
+        loop {
+            /* heard before, during the continuous reception */
+            if let Some(packet) = self.try_receive() {
+                return Ok(packet);
+            }
+            self.radio.request(RadioRequest::ReceiveSingle).await?;
+        }
+    }
+
+    fn try_receive(&mut self) -> Option<LoRaPacket> {
+        loop {
+            match self.received.try_next_message()? {
+                WaitResult::Message(packet) => {
+                    if (self.filter)(&packet) {
+                        return Some(packet);
+                    }
+                }
+                WaitResult::Lagged(n) => {
+                    warn!("radio client lagged by {} packets", n);
+                }
+            }
+        }
+    }
+
+    /* drop packets that were received but not yet consumed */
+    pub fn clear(&mut self) {
+        while self.received.try_next_message().is_some() {}
+    }
+}
+
+/* what the OTA and the gateway logic of gateway-core transmit and receive with */
+impl RadioLink for RadioClient {
+    type Error = RadioError;
+
+    async fn transmit(
+        &mut self,
+        packet: &mut LoRaPacket,
+        modulation: Option<ModulationOverride>,
+    ) -> Result<(), RadioError> {
+        self.radio
+            .transmit_with_modulation(packet, modulation)
+            .await
+    }
+
+    async fn receive_single(&mut self) -> Result<LoRaPacket, RadioError> {
+        RadioClient::receive_single(self).await
+    }
+
+    fn clear(&mut self) {
+        RadioClient::clear(self)
+    }
+
+    async fn hold_off(&mut self) {
+        Timer::after_millis(100).await
+    }
+
+    fn stats(&self) -> LinkStats {
+        self.radio.stats()
+    }
+
+    fn reset_stats(&mut self) {
+        self.radio.reset_stats()
+    }
+
+    fn record_retry(&mut self) {
+        self.radio.record_retry()
+    }
+
+    fn set_sniffer(&mut self, enabled: bool) {
+        self.radio.set_sniffer(enabled)
+    }
+}